@@ -0,0 +1,35 @@
+//! Embeds what the version endpoint reports about the build.
+
+use std::process::Command;
+
+fn output(command: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(command).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+fn main() {
+    let sha = output("git", &["rev-parse", "HEAD"]).unwrap_or_default();
+    println!("cargo:rustc-env=DEPLOY_SERVER_GIT_SHA={sha}");
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_default();
+    println!("cargo:rustc-env=DEPLOY_SERVER_RUSTC_VERSION={rustc_version}");
+
+    let mut features: Vec<_> = std::env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!(
+        "cargo:rustc-env=DEPLOY_SERVER_FEATURES={}",
+        features.join(",")
+    );
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,82 @@
+/// The commands a script can print to annotate its job, in the style of GitHub Actions
+/// workflow commands: `::error file=app.js,line=10,title=Lint::Missing semicolon`.
+const LEVELS: &[&str] = &["notice", "warning", "error"];
+
+/// A message a deploy script has asked to be surfaced on its job.
+#[derive(Clone)]
+pub struct Annotation {
+    pub stream: &'static str,
+    pub level: &'static str,
+    pub message: String,
+    pub title: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Reverses the percent-encoding GitHub Actions uses to keep commands on one line.
+fn unescape(value: &str, property: bool) -> String {
+    let value = value.replace("%0D", "\r").replace("%0A", "\n");
+    let value = if property {
+        value.replace("%3A", ":").replace("%2C", ",")
+    } else {
+        value
+    };
+    value.replace("%25", "%")
+}
+
+/// Parses a `::label name=tier::web` command, with which a script labels its job.
+pub fn parse_label(line: &str) -> Option<(String, String)> {
+    let command = line.trim_end().strip_prefix("::label ")?;
+    let (properties, value) = command.split_once("::")?;
+    let name = properties.trim().strip_prefix("name=")?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((unescape(name, true), unescape(value, false)))
+}
+
+impl Annotation {
+    /// Parses a line as an annotation command. Lines that are not one of the recognized
+    /// commands are left as plain output.
+    pub fn parse(line: &str, stream: &'static str) -> Option<Self> {
+        let command = line.trim_end().strip_prefix("::")?;
+        let (command, message) = command.split_once("::")?;
+        let (name, properties) = match command.split_once(' ') {
+            Some((name, properties)) => (name, properties),
+            None => (command, ""),
+        };
+        let level = LEVELS.iter().copied().find(|level| *level == name)?;
+
+        let mut annotation = Self {
+            stream,
+            level,
+            message: unescape(message, false),
+            title: None,
+            file: None,
+            line: None,
+        };
+        for property in properties
+            .split(',')
+            .filter(|property| !property.is_empty())
+        {
+            let (key, value) = property.split_once('=')?;
+            let value = unescape(value.trim(), true);
+            match key.trim() {
+                "title" => annotation.title = Some(value),
+                "file" => annotation.file = Some(value),
+                "line" => annotation.line = value.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(annotation)
+    }
+
+    /// Where the annotation points, like `src/app.rs:10`, if it points anywhere.
+    pub fn location(&self) -> Option<String> {
+        let file = self.file.as_ref()?;
+        Some(match self.line {
+            Some(line) => format!("{file}:{line}"),
+            None => file.clone(),
+        })
+    }
+}
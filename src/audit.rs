@@ -0,0 +1,154 @@
+//! Signed records of finished jobs, so that audit consumers who keep them can later prove
+//! they have not been edited.
+//!
+//! Records are signed with Ed25519, so that anyone with the public key, which is served
+//! at /api/records/key, can check them without asking the server, and nobody without the
+//! private key can sign an edited one.
+
+use crate::{unix_millis, Job, JobResult};
+use deploy_server_types::JobRecord;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+/// Signs job records with the Ed25519 private key in the `record_signing_key` environment
+/// variable, given as its 32 byte seed, hex encoded.
+pub struct RecordSigner {
+    key: SigningKey,
+}
+
+impl RecordSigner {
+    /// Records are only signed when a key is configured.
+    pub fn from_env() -> Option<Self> {
+        let key = std::env::var("record_signing_key")
+            .ok()
+            .filter(|key| !key.is_empty())?;
+        Some(Self::new(&key).unwrap_or_else(|| {
+            panic!("`record_signing_key` environment variable must be a hex encoded Ed25519 seed")
+        }))
+    }
+
+    fn new(seed: &str) -> Option<Self> {
+        let seed = <[u8; 32]>::try_from(hex::decode(seed.trim()).ok()?).ok()?;
+        Some(Self {
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// The public key that signatures are checked against, hex encoded.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+
+    pub fn sign(&self, record: &JobRecord) -> String {
+        hex::encode(
+            self.key
+                .sign(&serde_json::to_vec(record).unwrap())
+                .to_bytes(),
+        )
+    }
+
+    pub fn verify(&self, record: &JobRecord, signature: &str) -> bool {
+        let signature = match hex::decode(signature) {
+            Ok(signature) => signature,
+            Err(..) => return false,
+        };
+        match Signature::from_slice(&signature) {
+            Ok(signature) => self
+                .key
+                .verifying_key()
+                .verify(&serde_json::to_vec(record).unwrap(), &signature)
+                .is_ok(),
+            Err(..) => false,
+        }
+    }
+}
+
+/// The record of a job, or `None` if it is still running.
+pub fn record(job: &Job) -> Option<JobRecord> {
+    let result = job.result.borrow();
+    Some(JobRecord {
+        id: job.id,
+        app: job.app.clone(),
+        environment: job.environment.clone(),
+        sha: job.sha.clone(),
+        sender: job.sender.clone(),
+        request_id: job.request_id.clone(),
+        flags: job.flags.clone(),
+        owner: job.owner.clone(),
+        contact: job.contact.clone(),
+        received_at: unix_millis(job.received_at),
+        status: result.status?,
+        output_sha256: hash_output(&result),
+    })
+}
+
+/// Hashes each line of output with the stream it was written to, so that moving a line
+/// between streams changes the hash too.
+fn hash_output(result: &JobResult) -> String {
+    let mut hasher = Sha256::new();
+    for line in &result.output {
+        hasher.update(line.stream());
+        hasher.update(b"\t");
+        hasher.update(line.text());
+        hasher.update(b"\n");
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::VerifyingKey;
+    use uuid::Uuid;
+
+    fn record() -> JobRecord {
+        JobRecord {
+            id: Uuid::new_v4(),
+            app: "web".to_owned(),
+            environment: None,
+            sha: Some("abc123".to_owned()),
+            sender: None,
+            request_id: "test".to_owned(),
+            flags: vec![],
+            owner: None,
+            contact: None,
+            received_at: 0,
+            status: 0,
+            output_sha256: hex::encode(Sha256::digest(b"")),
+        }
+    }
+
+    #[test]
+    fn records_check_against_the_public_key_alone() {
+        let signer = RecordSigner::new(&"01".repeat(32)).unwrap();
+        let record = record();
+        let signature = signer.sign(&record);
+        assert!(signer.verify(&record, &signature));
+
+        let public_key = <[u8; 32]>::try_from(hex::decode(signer.public_key()).unwrap()).unwrap();
+        let public_key = VerifyingKey::from_bytes(&public_key).unwrap();
+        let signature = Signature::from_slice(&hex::decode(&signature).unwrap()).unwrap();
+        let message = serde_json::to_vec(&record).unwrap();
+        assert!(public_key.verify(&message, &signature).is_ok());
+    }
+
+    #[test]
+    fn edited_records_do_not_check() {
+        let signer = RecordSigner::new(&"01".repeat(32)).unwrap();
+        let mut record = record();
+        let signature = signer.sign(&record);
+        record.status = 1;
+        assert!(!signer.verify(&record, &signature));
+        assert!(!signer.verify(&record, "not hex"));
+        assert!(!RecordSigner::new(&"02".repeat(32))
+            .unwrap()
+            .verify(&record, &signature));
+    }
+
+    #[test]
+    fn keys_are_32_byte_seeds() {
+        assert!(RecordSigner::new("secret").is_none());
+        assert!(RecordSigner::new(&"01".repeat(31)).is_none());
+    }
+}
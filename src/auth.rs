@@ -0,0 +1,697 @@
+//! Filters that authenticate trigger requests, shared by every trigger route.
+
+use crate::payload::Payload;
+use bytes::Bytes;
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha256;
+use std::str::FromStr;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use warp::http::HeaderMap;
+use warp::{reject, Filter, Rejection};
+
+pub mod basic;
+pub mod console;
+mod github_login;
+mod oidc;
+pub mod signing;
+
+/// Webhook payloads larger than this are refused before being read.
+const MAX_PAYLOAD: u64 = 25 * 1024 * 1024;
+
+/// Every authentication failure, whichever provider or scheme it came from, is reported
+/// as this rejection.
+#[derive(Debug)]
+pub struct InvalidSignature;
+impl reject::Reject for InvalidSignature {}
+
+/// The sender is authenticated, but the app does not allow it to deploy.
+#[derive(Debug)]
+pub struct UnauthorizedSender;
+impl reject::Reject for UnauthorizedSender {}
+
+/// The sender asked to deploy a ref other than the app's default branch, which its token
+/// may not.
+#[derive(Debug)]
+pub struct UnexpectedRef;
+impl reject::Reject for UnexpectedRef {}
+
+/// Compares a secret a request carried with an expected one in constant time, so that how
+/// long the comparison takes says nothing about how much of it was right. Empty secrets
+/// never match.
+pub fn secret_matches(expected: &str, secret: &str) -> bool {
+    !expected.is_empty() && bool::from(expected.as_bytes().ct_eq(secret.as_bytes()))
+}
+
+/// The secrets in a comma separated list, so that a new secret can be accepted alongside
+/// the old one while senders move over to it.
+pub fn secrets(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|secret| !secret.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// API tokens accepted in the `X-Deploy-Secret` header, each with a name that apps can
+/// use to restrict who may deploy them.
+pub struct Tokens {
+    tokens: Vec<(String, String)>,
+    /// Names of the tokens that may deploy refs other than an app's default branch.
+    any_ref: Vec<String>,
+}
+
+impl Tokens {
+    /// Each of the `github_actions_secrets` is always accepted as the `default` token. More
+    /// tokens are read from `deploy_tokens`, as comma separated `name=secret` pairs, and
+    /// a name may be given more than once to accept several secrets for it. The names in
+    /// `any_ref_tokens` may deploy any ref, not just an app's default branch.
+    pub fn from_env(actions_secrets: &[String]) -> Self {
+        let mut tokens = Self::parse(
+            actions_secrets,
+            &std::env::var("deploy_tokens").unwrap_or_default(),
+        )
+        .expect("`deploy_tokens` environment variable must be a list of `name=secret` pairs");
+        tokens.any_ref = std::env::var("any_ref_tokens")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+        tokens
+    }
+
+    fn parse(actions_secrets: &[String], tokens: &str) -> Result<Self, String> {
+        let mut parsed: Vec<_> = actions_secrets
+            .iter()
+            .map(|secret| ("default".to_owned(), secret.clone()))
+            .collect();
+        for token in tokens
+            .split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+        {
+            match token.split_once('=') {
+                Some((name, secret)) if !name.is_empty() && !secret.is_empty() => {
+                    parsed.push((name.to_owned(), secret.to_owned()))
+                }
+                _ => return Err(format!("invalid token `{token}`")),
+            }
+        }
+        Ok(Self {
+            tokens: parsed,
+            any_ref: vec![],
+        })
+    }
+
+    /// The name of the token with this secret.
+    pub fn find(&self, secret: &str) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|(_, token)| secret_matches(token, secret))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Whether the sender may deploy refs other than an app's default branch.
+    pub fn may_deploy_any_ref(&self, sender: &str) -> bool {
+        self.any_ref.iter().any(|name| name == sender)
+    }
+}
+
+/// Checks that an app's list of allowed senders, if it has one, includes `sender`.
+pub fn authorize_sender(allowed: &[String], sender: &str) -> Result<(), Rejection> {
+    if allowed.is_empty() || allowed.iter().any(|allowed| allowed == sender) {
+        Ok(())
+    } else {
+        Err(reject::custom(UnauthorizedSender))
+    }
+}
+
+/// Whether `reference`, given either as a branch name or as its full ref, is an app's
+/// default branch. Every ref is when the app has none.
+pub fn is_default_branch(default_branch: Option<&str>, reference: &str) -> bool {
+    let branch = reference.strip_prefix("refs/heads/").unwrap_or(reference);
+    default_branch.is_none_or(|default_branch| branch == default_branch)
+}
+
+/// A kind of webhook sender: how it signs its deliveries, and how it says what they are
+/// about. Adding a provider is a matter of implementing this and naming it in
+/// [`provider`].
+pub trait WebhookProvider: Send + Sync {
+    /// The name the provider is configured by.
+    fn name(&self) -> &'static str;
+
+    /// Whether the delivery was signed with the secret.
+    fn verify(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> bool;
+
+    /// The kind of event the delivery is about, if the provider says.
+    fn event<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str>;
+
+    /// Whether deliveries about the event are pushes, which are the only ones deployed.
+    fn is_push(&self, event: &str) -> bool;
+
+    /// Whether the event only checks that deliveries arrive, as when a webhook is added.
+    /// Pings are always answered with 200, whatever the app's `ignored_status`, so that
+    /// the sender shows the webhook as working.
+    fn is_ping(&self, event: &str) -> bool {
+        event == "ping"
+    }
+
+    /// The parts of the delivery's payload that the server cares about. Every provider's
+    /// payload is read by the same parser unless one needs otherwise.
+    fn payload(&self, body: &[u8]) -> Payload {
+        Payload::parse(body)
+    }
+}
+
+/// The provider with the given name:
+///
+/// - `github` accepts either of GitHub's signatures, preferring SHA-256, while
+///   `github:sha256` or `github:sha256,sha1` name the algorithms to accept in order of
+///   preference.
+/// - `gitlab`, `gitea` (or `forgejo`) and `bitbucket` verify those services' deliveries.
+/// - `hmac` or `hmac:{header}` accepts a hex HMAC-SHA256 of the body from any sender, in
+///   the `X-Signature` or named header.
+pub fn provider(name: &str) -> Result<Arc<dyn WebhookProvider>, String> {
+    if let Some(algorithms) = name.strip_prefix("github:") {
+        let algorithms = algorithms
+            .split(',')
+            .map(str::trim)
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Arc::new(GitHub { algorithms }));
+    }
+    match name {
+        "github" => Ok(Arc::new(GitHub::default())),
+        "gitlab" => Ok(Arc::new(GitLab)),
+        "gitea" | "forgejo" => Ok(Arc::new(Gitea)),
+        "bitbucket" => Ok(Arc::new(Bitbucket)),
+        "hmac" => Ok(Arc::new(Generic {
+            header: "X-Signature".to_owned(),
+        })),
+        _ => match name.strip_prefix("hmac:") {
+            Some(header) if !header.is_empty() => Ok(Arc::new(Generic {
+                header: header.to_owned(),
+            })),
+            _ => Err(format!("unknown webhook provider `{name}`")),
+        },
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn verify_hmac<M: Mac + KeyInit>(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(..) => return false,
+    };
+    let mut mac = match <M as Mac>::new_from_slice(secret) {
+        Ok(mac) => mac,
+        Err(..) => return false,
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// A hash algorithm that GitHub signs deliveries with, each in its own header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Sha1,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(algorithm: &str) -> Result<Self, Self::Err> {
+        match algorithm {
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha1" => Ok(Algorithm::Sha1),
+            _ => Err(format!("unknown signature algorithm `{algorithm}`")),
+        }
+    }
+}
+
+impl Algorithm {
+    fn header(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "X-Hub-Signature-256",
+            Algorithm::Sha1 => "X-Hub-Signature",
+        }
+    }
+
+    fn verify(self, secret: &[u8], body: &[u8], signature: &str) -> bool {
+        match self {
+            Algorithm::Sha256 => signature
+                .strip_prefix("sha256=")
+                .is_some_and(|signature| verify_hmac::<Hmac<Sha256>>(secret, body, signature)),
+            Algorithm::Sha1 => signature
+                .strip_prefix("sha1=")
+                .is_some_and(|signature| verify_hmac::<Hmac<Sha1>>(secret, body, signature)),
+        }
+    }
+}
+
+/// `X-Hub-Signature-256: sha256=<hex HMAC-SHA256>` or `X-Hub-Signature: sha1=<hex
+/// HMAC-SHA1>`, checking the header of the first of the accepted algorithms that the
+/// delivery has.
+pub struct GitHub {
+    algorithms: Vec<Algorithm>,
+}
+
+impl Default for GitHub {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![Algorithm::Sha256, Algorithm::Sha1],
+        }
+    }
+}
+
+impl WebhookProvider for GitHub {
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    fn verify(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+        // A delivery signed with an algorithm that is preferred is never accepted on the
+        // strength of a weaker signature alongside it.
+        !secret.is_empty()
+            && self
+                .algorithms
+                .iter()
+                .find_map(|algorithm| Some((algorithm, header(headers, algorithm.header())?)))
+                .is_some_and(|(algorithm, signature)| {
+                    algorithm.verify(secret.as_bytes(), body, signature)
+                })
+    }
+
+    fn event<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        header(headers, "X-GitHub-Event")
+    }
+
+    fn is_push(&self, event: &str) -> bool {
+        event == "push"
+    }
+}
+
+/// `X-Gitlab-Token: <secret>`.
+pub struct GitLab;
+
+impl WebhookProvider for GitLab {
+    fn name(&self) -> &'static str {
+        "gitlab"
+    }
+
+    fn verify(&self, headers: &HeaderMap, _: &[u8], secret: &str) -> bool {
+        header(headers, "X-Gitlab-Token").is_some_and(|token| secret_matches(secret, token))
+    }
+
+    fn event<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        header(headers, "X-Gitlab-Event")
+    }
+
+    fn is_push(&self, event: &str) -> bool {
+        event == "Push Hook" || event == "Tag Push Hook"
+    }
+}
+
+/// `X-Gitea-Signature: <hex HMAC-SHA256>`, or Forgejo's `X-Forgejo-Signature`.
+pub struct Gitea;
+
+impl WebhookProvider for Gitea {
+    fn name(&self) -> &'static str {
+        "gitea"
+    }
+
+    fn verify(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+        !secret.is_empty()
+            && header(headers, "X-Forgejo-Signature")
+                .or_else(|| header(headers, "X-Gitea-Signature"))
+                .is_some_and(|signature| {
+                    verify_hmac::<Hmac<Sha256>>(secret.as_bytes(), body, signature)
+                })
+    }
+
+    fn event<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        header(headers, "X-Forgejo-Event").or_else(|| header(headers, "X-Gitea-Event"))
+    }
+
+    fn is_push(&self, event: &str) -> bool {
+        event == "push"
+    }
+}
+
+/// `X-Hub-Signature: sha256=<hex HMAC-SHA256>`, as Bitbucket Cloud signs deliveries.
+pub struct Bitbucket;
+
+impl WebhookProvider for Bitbucket {
+    fn name(&self) -> &'static str {
+        "bitbucket"
+    }
+
+    fn verify(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+        !secret.is_empty()
+            && header(headers, "X-Hub-Signature").is_some_and(|signature| {
+                Algorithm::Sha256.verify(secret.as_bytes(), body, signature)
+            })
+    }
+
+    fn event<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        header(headers, "X-Event-Key")
+    }
+
+    fn is_push(&self, event: &str) -> bool {
+        event == "repo:push"
+    }
+}
+
+/// A hex HMAC-SHA256 of the body in the named header, optionally prefixed `sha256=`, from
+/// a sender that does not say what its deliveries are about.
+pub struct Generic {
+    header: String,
+}
+
+impl WebhookProvider for Generic {
+    fn name(&self) -> &'static str {
+        "hmac"
+    }
+
+    fn verify(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+        !secret.is_empty()
+            && header(headers, &self.header)
+                .map(|signature| signature.strip_prefix("sha256=").unwrap_or(signature))
+                .is_some_and(|signature| {
+                    verify_hmac::<Hmac<Sha256>>(secret.as_bytes(), body, signature)
+                })
+    }
+
+    fn event<'a>(&self, _: &'a HeaderMap) -> Option<&'a str> {
+        None
+    }
+
+    fn is_push(&self, _: &str) -> bool {
+        true
+    }
+}
+
+/// Extracts the headers and raw body of a webhook delivery, for its signature to be
+/// verified by a [`WebhookProvider`].
+pub fn webhook_delivery() -> impl Filter<Extract = (HeaderMap, Bytes), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::body::content_length_limit(MAX_PAYLOAD))
+        .and(warp::body::bytes())
+}
+
+/// Accepts requests with any of the actions secrets in the `X-Deploy-Secret` header.
+pub fn verify_actions_secret(
+    actions_secrets: Arc<Vec<String>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::header("X-Deploy-Secret")
+        .and_then(move |secret: String| {
+            let is_valid = actions_secrets
+                .iter()
+                .any(|actions_secret| secret_matches(actions_secret, &secret));
+            async move {
+                if is_valid {
+                    Ok(())
+                } else {
+                    Err(reject::custom(InvalidSignature))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    const BODY: &[u8] = br#"{"ref":"refs/heads/main"}"#;
+    const SHA1: &str = "b4a16e3f7e972ac84f7ec491f5bd8e412e95cd69";
+    const SHA256: &str = "d8f89f0618acd61fe621aa4e64078c0e2bca15d0b578b7f3eb734f55883c5320";
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn github_accepts_valid_signature() {
+        let headers = headers("X-Hub-Signature", &format!("sha1={SHA1}"));
+        assert!(GitHub::default().verify(&headers, BODY, "secret"));
+    }
+
+    #[test]
+    fn github_rejects_wrong_secret_or_body() {
+        let headers = headers("X-Hub-Signature", &format!("sha1={SHA1}"));
+        assert!(!GitHub::default().verify(&headers, BODY, "not the secret"));
+        assert!(!GitHub::default().verify(&headers, b"{}", "secret"));
+    }
+
+    #[test]
+    fn github_requires_algorithm_prefix() {
+        let headers = headers("X-Hub-Signature", SHA1);
+        assert!(!GitHub::default().verify(&headers, BODY, "secret"));
+    }
+
+    #[test]
+    fn github_prefers_sha256_signature() {
+        let mut headers = headers("X-Hub-Signature-256", &format!("sha256={SHA256}"));
+        assert!(GitHub::default().verify(&headers, BODY, "secret"));
+        headers.insert("X-Hub-Signature", HeaderValue::from_static("sha1=00"));
+        assert!(GitHub::default().verify(&headers, BODY, "secret"));
+    }
+
+    #[test]
+    fn github_does_not_fall_back_from_invalid_sha256_signature() {
+        let mut headers = headers("X-Hub-Signature-256", "sha256=00");
+        headers.insert(
+            "X-Hub-Signature",
+            HeaderValue::from_str(&format!("sha1={SHA1}")).unwrap(),
+        );
+        assert!(!GitHub::default().verify(&headers, BODY, "secret"));
+    }
+
+    #[test]
+    fn github_accepts_only_configured_algorithms() {
+        let github = provider("github:sha256").unwrap();
+        let headers = headers("X-Hub-Signature", &format!("sha1={SHA1}"));
+        assert!(!github.verify(&headers, BODY, "secret"));
+        assert!(provider("github:sha256,md5").is_err());
+    }
+
+    #[test]
+    fn only_push_events_are_deployed() {
+        let github = GitHub::default();
+        let headers = headers("X-GitHub-Event", "ping");
+        assert_eq!(github.event(&headers), Some("ping"));
+        assert!(!github.is_push("ping"));
+        assert!(github.is_ping("ping"));
+        assert!(github.is_push("push"));
+        assert!(!github.is_ping("push"));
+        assert!(GitLab.is_push("Tag Push Hook"));
+        assert!(provider("hmac").unwrap().event(&headers).is_none());
+    }
+
+    #[test]
+    fn gitlab_compares_token() {
+        let headers = headers("X-Gitlab-Token", "secret");
+        assert!(GitLab.verify(&headers, BODY, "secret"));
+        assert!(!GitLab.verify(&headers, BODY, "other"));
+    }
+
+    #[test]
+    fn gitea_accepts_valid_signature() {
+        let headers = headers("X-Gitea-Signature", SHA256);
+        assert!(Gitea.verify(&headers, BODY, "secret"));
+        assert!(!Gitea.verify(&headers, b"{}", "secret"));
+    }
+
+    #[test]
+    fn forgejo_signature_is_accepted_as_gitea() {
+        let provider = provider("forgejo").unwrap();
+        assert_eq!(provider.name(), "gitea");
+        let headers = headers("X-Forgejo-Signature", SHA256);
+        assert!(provider.verify(&headers, BODY, "secret"));
+    }
+
+    #[test]
+    fn bitbucket_accepts_sha256_signature() {
+        let provider = provider("bitbucket").unwrap();
+        let headers = headers("X-Hub-Signature", &format!("sha256={SHA256}"));
+        assert!(provider.verify(&headers, BODY, "secret"));
+        assert!(!provider.verify(&headers, b"{}", "secret"));
+        let sha1 = self::headers("X-Hub-Signature", &format!("sha1={SHA1}"));
+        assert!(!provider.verify(&sha1, BODY, "secret"));
+    }
+
+    #[test]
+    fn hmac_accepts_signature_with_or_without_prefix() {
+        let provider = provider("hmac:X-Signature-256").unwrap();
+        let bare = headers("X-Signature-256", SHA256);
+        let prefixed = headers("X-Signature-256", &format!("sha256={SHA256}"));
+        assert!(provider.verify(&bare, BODY, "secret"));
+        assert!(provider.verify(&prefixed, BODY, "secret"));
+    }
+
+    #[test]
+    fn missing_header_is_rejected() {
+        assert!(!GitHub::default().verify(&HeaderMap::new(), BODY, "secret"));
+        assert!(!Gitea.verify(&HeaderMap::new(), BODY, "secret"));
+    }
+
+    #[test]
+    fn empty_secret_is_rejected() {
+        let headers = headers("X-Gitlab-Token", "");
+        assert!(!GitLab.verify(&headers, BODY, ""));
+    }
+
+    #[test]
+    fn unknown_provider_fails_to_parse() {
+        assert!(provider("sourcehut").is_err());
+        assert!(provider("hmac:").is_err());
+    }
+
+    #[test]
+    fn tokens_are_found_by_secret() {
+        let actions = ["actions".to_owned()];
+        let tokens = Tokens::parse(&actions, "staging=abc, production=def").unwrap();
+        assert_eq!(tokens.find("actions"), Some("default"));
+        assert_eq!(tokens.find("abc"), Some("staging"));
+        assert_eq!(tokens.find("def"), Some("production"));
+        assert_eq!(tokens.find("ghi"), None);
+    }
+
+    #[test]
+    fn malformed_tokens_fail_to_parse() {
+        let actions = ["actions".to_owned()];
+        assert!(Tokens::parse(&actions, "staging").is_err());
+        assert!(Tokens::parse(&actions, "=abc").is_err());
+        assert!(Tokens::parse(&actions, "staging=").is_err());
+    }
+
+    #[test]
+    fn default_branch_is_matched_by_name_or_ref() {
+        assert!(is_default_branch(Some("main"), "main"));
+        assert!(is_default_branch(Some("main"), "refs/heads/main"));
+        assert!(!is_default_branch(Some("main"), "feature"));
+        assert!(!is_default_branch(Some("main"), "refs/heads/feature"));
+        assert!(!is_default_branch(Some("main"), "refs/tags/main"));
+        assert!(is_default_branch(None, "feature"));
+    }
+
+    #[test]
+    fn senders_are_checked_against_allowlist() {
+        let allowed = vec!["production".to_owned()];
+        assert!(authorize_sender(&allowed, "production").is_ok());
+        assert!(authorize_sender(&allowed, "staging").is_err());
+        assert!(authorize_sender(&[], "staging").is_ok());
+    }
+
+    #[tokio::test]
+    async fn actions_secret_filter() {
+        let filter = verify_actions_secret(Arc::new(vec!["secret".to_owned()]));
+        assert!(
+            warp::test::request()
+                .header("X-Deploy-Secret", "secret")
+                .matches(&filter)
+                .await
+        );
+        assert!(
+            !warp::test::request()
+                .header("X-Deploy-Secret", "secrets")
+                .matches(&filter)
+                .await
+        );
+        assert!(!warp::test::request().matches(&filter).await);
+    }
+
+    #[tokio::test]
+    async fn near_miss_actions_secrets_are_refused() {
+        let filter = verify_actions_secret(Arc::new(vec!["secret".to_owned()]));
+        for secret in ["secreT", "secre", "secret ", "", "Secret"] {
+            assert!(
+                !warp::test::request()
+                    .header("X-Deploy-Secret", secret)
+                    .matches(&filter)
+                    .await,
+                "{:?} was accepted",
+                secret
+            );
+        }
+    }
+
+    #[test]
+    fn empty_secrets_never_match() {
+        assert!(secret_matches("secret", "secret"));
+        assert!(!secret_matches("", ""));
+        assert!(!secret_matches("secret", "secreu"));
+    }
+
+    fn basic_auth(credentials: &str) -> String {
+        use base64::Engine;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        format!("Basic {encoded}")
+    }
+
+    #[tokio::test]
+    async fn console_credentials_are_checked() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+        let salt = SaltString::new("c2FsdHNhbHRzYWx0").unwrap();
+        let argon2 = argon2::Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        for hash in [argon2, bcrypt] {
+            let credentials = basic::ConsoleCredentials::new("admin".to_owned(), &hash).unwrap();
+            let filter = console::authenticate(Some(Arc::new(credentials)));
+            for _ in 0..2 {
+                assert!(
+                    warp::test::request()
+                        .header("authorization", basic_auth("admin:hunter2"))
+                        .matches(&filter)
+                        .await
+                );
+            }
+            for wrong in ["admin:hunter3", "root:hunter2", "admin", ""] {
+                assert!(
+                    !warp::test::request()
+                        .header("authorization", basic_auth(wrong))
+                        .matches(&filter)
+                        .await,
+                    "{:?} was accepted",
+                    wrong
+                );
+            }
+            assert!(!warp::test::request().matches(&filter).await);
+        }
+        let unsalted = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+        assert!(basic::ConsoleCredentials::new("admin".to_owned(), unsalted).is_none());
+        assert!(
+            warp::test::request()
+                .matches(&console::authenticate(None))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn old_and_new_actions_secrets_are_accepted() {
+        let filter = verify_actions_secret(Arc::new(secrets("old, new")));
+        for secret in ["old", "new"] {
+            assert!(
+                warp::test::request()
+                    .header("X-Deploy-Secret", secret)
+                    .matches(&filter)
+                    .await
+            );
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! HTTP basic authentication for the console and the APIs that read jobs, whose output
+//! often holds things that should not be shown to whoever can reach the port.
+
+use super::console::ConsoleAuth;
+use super::secret_matches;
+use argon2::{Argon2, PasswordVerifier};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use warp::{reject, Rejection};
+
+/// The realm browsers are asked to log in to.
+pub const REALM: &str = "deploy-server";
+
+/// The request needs credentials that it did not have.
+#[derive(Debug)]
+pub struct Unauthenticated;
+impl reject::Reject for Unauthenticated {}
+
+/// The one user that may read the console, with a slow, salted hash of their password,
+/// so the password itself is never configured in plain text, and the hash is not worth
+/// trying to guess from.
+pub struct ConsoleCredentials {
+    username: String,
+    password_hash: PasswordHash,
+    /// The digest of the last `Authorization` header that was accepted, so the slow hash
+    /// is not checked again on every request from the same browser.
+    accepted: Mutex<Option<Vec<u8>>>,
+}
+
+/// A password hash in a format that can be checked without the password.
+enum PasswordHash {
+    /// A PHC string, as the `argon2` command line tool prints, such as `$argon2id$...`.
+    Argon2(String),
+    /// A bcrypt hash, as `htpasswd -B` writes after the username, such as `$2y$...`.
+    Bcrypt(String),
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2") {
+            argon2::PasswordHash::new(hash).ok()?;
+            Some(Self::Argon2(hash.to_owned()))
+        } else {
+            hash.parse::<bcrypt::HashParts>().ok()?;
+            Some(Self::Bcrypt(hash.to_owned()))
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Argon2(hash) => argon2::PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+            Self::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+        }
+    }
+}
+
+impl ConsoleCredentials {
+    /// Reads `console_username` and `console_password_hash`, if `console_username` is set.
+    pub fn from_env() -> Option<Self> {
+        let username = std::env::var("console_username").ok()?;
+        if username.is_empty() {
+            panic!("`console_username` environment variable must not be empty");
+        }
+        let password_hash = std::env::var("console_password_hash")
+            .expect("`console_password_hash` environment variable must be set");
+        Some(Self::new(username, &password_hash).unwrap_or_else(|| {
+            panic!("`console_password_hash` environment variable must be an argon2 PHC string or a bcrypt hash")
+        }))
+    }
+
+    /// The credentials of `username`, if `password_hash` is an argon2 PHC string or a
+    /// bcrypt hash.
+    pub fn new(username: String, password_hash: &str) -> Option<Self> {
+        Some(Self {
+            username,
+            password_hash: PasswordHash::parse(password_hash)?,
+            accepted: Mutex::new(None),
+        })
+    }
+
+    /// Whether an `Authorization` header holds these credentials.
+    fn accepts(&self, authorization: &str) -> bool {
+        let digest = Sha256::digest(authorization.as_bytes()).to_vec();
+        if self.accepted.lock().unwrap().as_ref() == Some(&digest) {
+            return true;
+        }
+        let decoded = match authorization
+            .strip_prefix("Basic ")
+            .and_then(|encoded| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(encoded)
+                    .ok()
+            })
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+        {
+            Some(decoded) => decoded,
+            None => return false,
+        };
+        let (username, password) = match decoded.split_once(':') {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        // Both are checked, whether or not the first matches, so that timing says
+        // nothing about which was wrong.
+        let username_matches = secret_matches(&self.username, username);
+        let password_matches = self.password_hash.verify(password);
+        let accepted = username_matches && password_matches;
+        if accepted {
+            *self.accepted.lock().unwrap() = Some(digest);
+        }
+        accepted
+    }
+}
+
+impl ConsoleAuth for ConsoleCredentials {
+    fn user(&self, authorization: Option<&str>, _: Option<&str>) -> Option<String> {
+        authorization
+            .filter(|authorization| self.accepts(authorization))
+            .map(|_| self.username.clone())
+    }
+
+    fn challenge(&self) -> Rejection {
+        reject::custom(Unauthenticated)
+    }
+}
@@ -0,0 +1,498 @@
+//! Who may read the console and the APIs that read jobs. A provider decides, chosen by the
+//! `console_auth` environment variable:
+//!
+//! - `basic` asks for the `console_username` and its password on every request.
+//! - `oidc` sends browsers to log in with an OpenID Connect provider.
+//! - `github` sends browsers to log in with GitHub.
+//!
+//! Providers that send browsers elsewhere remember who logged in with a signed cookie.
+//! Adding one is a matter of implementing [`IdentityProvider`] and naming it in
+//! [`from_env`].
+
+use super::basic::ConsoleCredentials;
+use super::github_login::GitHubLogin;
+use super::oidc::Oidc;
+use super::secret_matches;
+use base64::Engine;
+use futures::future::BoxFuture;
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::future::ready;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+use warp::http::header::{LOCATION, SET_COOKIE};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{reject, Filter, Rejection, Reply};
+
+/// Where browsers are sent to log in.
+pub const LOGIN_PATH: &str = "/auth/login";
+
+/// Where identity providers send browsers back to once they have logged in.
+const CALLBACK_PATH: &str = "/auth/callback";
+
+/// Holds who logged in.
+const SESSION_COOKIE: &str = "deploy_session";
+
+/// Ties a login to the browser that started it, so that nobody can finish logging in
+/// someone else's browser as themselves.
+const LOGIN_COOKIE: &str = "deploy_login";
+
+const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long a browser has to log in with the identity provider.
+const LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// The request is from nobody logged in, so the browser is sent to log in.
+#[derive(Debug)]
+pub struct LoginRequired;
+impl reject::Reject for LoginRequired {}
+
+/// Logging in with the identity provider failed, or was for someone not allowed in.
+#[derive(Debug)]
+pub struct LoginFailed;
+impl reject::Reject for LoginFailed {}
+
+/// A way of deciding who may read the console.
+pub trait ConsoleAuth: Send + Sync {
+    /// Who the request is from, going by its `Authorization` header and session cookie,
+    /// if they let anyone in.
+    fn user(&self, authorization: Option<&str>, session: Option<&str>) -> Option<String>;
+
+    /// Why a request from nobody who may read the console is refused.
+    fn challenge(&self) -> Rejection;
+
+    /// How browsers log in, for providers that send them to log in elsewhere.
+    fn login(&self) -> Option<&Login> {
+        None
+    }
+}
+
+/// A site that browsers log in with by OAuth.
+pub trait IdentityProvider: Send + Sync {
+    /// The name the provider is configured by.
+    fn name(&self) -> &'static str;
+
+    /// Where to send the browser to log in, to come back to `redirect_uri` with `state`.
+    fn authorize_url<'a>(
+        &'a self,
+        redirect_uri: &'a str,
+        state: &'a str,
+    ) -> BoxFuture<'a, Result<String, String>>;
+
+    /// Who logged in, given the code the browser came back with.
+    fn user<'a>(
+        &'a self,
+        redirect_uri: &'a str,
+        code: &'a str,
+    ) -> BoxFuture<'a, Result<String, String>>;
+}
+
+/// Logging in with an identity provider, and remembering who did.
+pub struct Login {
+    provider: Box<dyn IdentityProvider>,
+    sessions: Sessions,
+    /// Who may log in, when not everyone the provider knows may.
+    allowed_users: Vec<String>,
+    redirect_uri: String,
+    /// Whether the console is served over HTTPS, so cookies should only be sent over it.
+    secure: bool,
+}
+
+impl Login {
+    /// Logs in with `provider`. Reads `public_url`, which the provider sends browsers back
+    /// to, `console_allowed_users`, a comma separated list of who may log in, and
+    /// `console_session_secret`, which signs session cookies. Without it, a random key
+    /// is used, and everyone must log in again when the server restarts.
+    fn from_env(provider: Box<dyn IdentityProvider>) -> Self {
+        let public_url = std::env::var("public_url").unwrap_or_else(|_| {
+            panic!(
+                "`public_url` environment variable must be set to log in with {}",
+                provider.name()
+            )
+        });
+        let public_url = public_url.trim_end_matches('/');
+        let key = match std::env::var("console_session_secret") {
+            Ok(secret) if secret.is_empty() => {
+                panic!("`console_session_secret` environment variable must not be empty")
+            }
+            Ok(secret) => secret.into_bytes(),
+            Err(..) => Uuid::new_v4().as_bytes().to_vec(),
+        };
+        Self {
+            provider,
+            sessions: Sessions { key },
+            allowed_users: super::secrets(
+                &std::env::var("console_allowed_users").unwrap_or_default(),
+            ),
+            redirect_uri: format!("{public_url}{CALLBACK_PATH}"),
+            secure: public_url.starts_with("https://"),
+        }
+    }
+
+    fn cookie(&self, name: &str, value: &str, path: &str, lifetime: Duration) -> String {
+        let secure = if self.secure { "; Secure" } else { "" };
+        format!(
+            "{name}={value}; Path={path}; Max-Age={}; HttpOnly; SameSite=Lax{secure}",
+            lifetime.as_secs()
+        )
+    }
+
+    /// Sends the browser to log in with the provider.
+    async fn start(&self) -> Result<Response, Rejection> {
+        let nonce = Uuid::new_v4().to_string();
+        let state = self.sessions.issue(Purpose::Login, &nonce, LOGIN_LIFETIME);
+        let url = self
+            .provider
+            .authorize_url(&self.redirect_uri, &state)
+            .await
+            .map_err(|error| {
+                eprintln!(
+                    "Failed to start logging in with {}: {error}",
+                    self.provider.name()
+                );
+                reject::custom(LoginFailed)
+            })?;
+        Ok(redirect(
+            &url,
+            Some(self.cookie(LOGIN_COOKIE, &nonce, "/auth", LOGIN_LIFETIME)),
+        ))
+    }
+
+    /// Finishes logging in once the provider sends the browser back, starting a session
+    /// for whoever logged in if they are allowed in.
+    async fn finish(
+        &self,
+        callback: Callback,
+        nonce: Option<String>,
+    ) -> Result<Response, Rejection> {
+        let started = self.sessions.open(Purpose::Login, &callback.state);
+        let from_this_browser = matches!(
+            (&started, &nonce),
+            (Some(started), Some(nonce)) if secret_matches(started, nonce)
+        );
+        if !from_this_browser {
+            eprintln!("Refused a login that this browser did not start, or that expired");
+            return Err(reject::custom(LoginFailed));
+        }
+        let user = self
+            .provider
+            .user(&self.redirect_uri, &callback.code)
+            .await
+            .map_err(|error| {
+                eprintln!("Failed to log in with {}: {error}", self.provider.name());
+                reject::custom(LoginFailed)
+            })?;
+        if !self.allowed_users.is_empty() && !self.allowed_users.contains(&user) {
+            eprintln!("Refused to let {user} into the console");
+            return Err(reject::custom(LoginFailed));
+        }
+        eprintln!("{user} logged in to the console");
+        let session = self
+            .sessions
+            .issue(Purpose::Session, &user, SESSION_LIFETIME);
+        Ok(redirect(
+            "/",
+            Some(self.cookie(SESSION_COOKIE, &session, "/", SESSION_LIFETIME)),
+        ))
+    }
+}
+
+impl ConsoleAuth for Login {
+    fn user(&self, _: Option<&str>, session: Option<&str>) -> Option<String> {
+        self.sessions.open(Purpose::Session, session?)
+    }
+
+    fn challenge(&self) -> Rejection {
+        reject::custom(LoginRequired)
+    }
+
+    fn login(&self) -> Option<&Login> {
+        Some(self)
+    }
+}
+
+fn redirect(location: &str, cookie: Option<String>) -> Response {
+    let mut response = warp::reply::with_status("", StatusCode::SEE_OTHER).into_response();
+    response
+        .headers_mut()
+        .insert(LOCATION, location.parse().unwrap());
+    if let Some(cookie) = cookie {
+        response
+            .headers_mut()
+            .insert(SET_COOKIE, cookie.parse().unwrap());
+    }
+    response
+}
+
+/// What a token was issued for. Tokens are signed with the same key, so each names its
+/// purpose, and one issued for a login cannot be passed off as a session.
+#[derive(Clone, Copy)]
+enum Purpose {
+    /// The `state` of a login, holding the nonce of the browser that started it.
+    Login,
+    /// The session cookie, holding who logged in.
+    Session,
+}
+
+impl Purpose {
+    fn tag(self) -> &'static str {
+        match self {
+            Purpose::Login => "login",
+            Purpose::Session => "session",
+        }
+    }
+}
+
+/// Values signed with a key only the server knows, which expire, so that sessions can be
+/// kept in cookies rather than on the server.
+struct Sessions {
+    key: Vec<u8>,
+}
+
+impl Sessions {
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as KeyInit>::new_from_slice(&self.key).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// A token for `value` that expires after `lifetime`.
+    fn issue(&self, purpose: Purpose, value: &str, lifetime: Duration) -> String {
+        let expires = (SystemTime::now() + lifetime)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("{}:{expires}:{value}", purpose.tag()));
+        let signature = hex::encode(self.mac(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// The value of a token this issued for `purpose`, if it has not expired.
+    fn open(&self, purpose: Purpose, token: &str) -> Option<String> {
+        let (payload, signature) = token.split_once('.')?;
+        self.mac(payload)
+            .verify_slice(&hex::decode(signature).ok()?)
+            .ok()?;
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()?;
+        let (tag, rest) = std::str::from_utf8(&payload).ok()?.split_once(':')?;
+        if tag != purpose.tag() {
+            return None;
+        }
+        let (expires, value) = rest.split_once(':')?;
+        let expires = UNIX_EPOCH + Duration::from_secs(expires.parse().ok()?);
+        (expires > SystemTime::now()).then(|| value.to_owned())
+    }
+}
+
+/// The provider named by `console_auth`, or basic authentication if only
+/// `console_username` is set. The console is open to everyone when neither is.
+pub fn from_env() -> Option<Arc<dyn ConsoleAuth>> {
+    let name = match std::env::var("console_auth") {
+        Ok(name) => name,
+        Err(..) => {
+            return ConsoleCredentials::from_env()
+                .map(|credentials| Arc::new(credentials) as Arc<dyn ConsoleAuth>)
+        }
+    };
+    let provider: Box<dyn IdentityProvider> = match name.as_str() {
+        "basic" => {
+            let credentials = ConsoleCredentials::from_env().expect(
+                "`console_username` environment variable must be set for `basic` console auth",
+            );
+            return Some(Arc::new(credentials));
+        }
+        "oidc" => Box::new(Oidc::from_env()),
+        "github" => Box::new(GitHubLogin::from_env()),
+        _ => panic!("`console_auth` environment variable must be `basic`, `oidc` or `github`"),
+    };
+    Some(Arc::new(Login::from_env(provider)))
+}
+
+/// Passes requests from someone the provider lets in, with who they are, or every request,
+/// from nobody in particular, if there is no provider.
+pub fn authenticate(
+    auth: Option<Arc<dyn ConsoleAuth>>,
+) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and_then(
+            move |authorization: Option<String>, session: Option<String>| {
+                let user = auth
+                    .as_ref()
+                    .map(|auth| {
+                        auth.user(authorization.as_deref(), session.as_deref())
+                            .ok_or_else(|| auth.challenge())
+                    })
+                    .transpose();
+                ready(user)
+            },
+        )
+}
+
+#[derive(serde::Deserialize)]
+struct Callback {
+    code: String,
+    state: String,
+}
+
+/// Where browsers log in, for providers that send them to log in elsewhere.
+pub fn routes(
+    auth: Option<Arc<dyn ConsoleAuth>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let login = warp::any().and_then(move || {
+        let auth = auth.clone();
+        async move {
+            match auth {
+                Some(auth) if auth.login().is_some() => Ok(auth),
+                _ => Err(reject::not_found()),
+            }
+        }
+    });
+    let start = warp::get()
+        .and(warp::path!("auth" / "login"))
+        .and(login.clone())
+        .and_then(|auth: Arc<dyn ConsoleAuth>| async move { auth.login().unwrap().start().await });
+    let finish = warp::get()
+        .and(warp::path!("auth" / "callback"))
+        .and(login)
+        .and(warp::query::<Callback>())
+        .and(warp::cookie::optional::<String>(LOGIN_COOKIE))
+        .and_then(
+            |auth: Arc<dyn ConsoleAuth>, callback: Callback, nonce: Option<String>| async move {
+                auth.login().unwrap().finish(callback, nonce).await
+            },
+        );
+    start.or(finish).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    /// Logs in whoever the code names.
+    struct Fake;
+
+    impl IdentityProvider for Fake {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn authorize_url<'a>(
+            &'a self,
+            _: &'a str,
+            state: &'a str,
+        ) -> BoxFuture<'a, Result<String, String>> {
+            futures::future::ready(Ok(format!("https://login.example/?state={state}"))).boxed()
+        }
+
+        fn user<'a>(&'a self, _: &'a str, code: &'a str) -> BoxFuture<'a, Result<String, String>> {
+            futures::future::ready(Ok(code.to_owned())).boxed()
+        }
+    }
+
+    fn sessions() -> Sessions {
+        Sessions {
+            key: b"key".to_vec(),
+        }
+    }
+
+    fn login() -> Arc<dyn ConsoleAuth> {
+        Arc::new(Login {
+            provider: Box::new(Fake),
+            sessions: sessions(),
+            allowed_users: vec!["alice".to_owned()],
+            redirect_uri: "https://deploy.example/auth/callback".to_owned(),
+            secure: true,
+        })
+    }
+
+    #[test]
+    fn sessions_open_what_they_issued_until_it_expires() {
+        let sessions = sessions();
+        let token = sessions.issue(Purpose::Session, "alice", SESSION_LIFETIME);
+        assert_eq!(
+            sessions.open(Purpose::Session, &token).as_deref(),
+            Some("alice")
+        );
+        let other = Sessions {
+            key: b"other".to_vec(),
+        };
+        assert_eq!(other.open(Purpose::Session, &token), None);
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged =
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode("session:99999999999:mallory");
+        assert_eq!(
+            sessions.open(Purpose::Session, &format!("{forged}.{signature}")),
+            None
+        );
+        let expired = sessions.issue(Purpose::Session, "alice", Duration::ZERO);
+        assert_eq!(sessions.open(Purpose::Session, &expired), None);
+    }
+
+    #[test]
+    fn login_states_are_not_sessions() {
+        let login = login();
+        let sessions = &login.login().unwrap().sessions;
+        let state = sessions.issue(Purpose::Login, "nonce", LOGIN_LIFETIME);
+        assert_eq!(login.user(None, Some(&state)), None);
+        let session = sessions.issue(Purpose::Session, "alice", SESSION_LIFETIME);
+        assert_eq!(sessions.open(Purpose::Login, &session), None);
+        assert_eq!(login.user(None, Some(&session)).as_deref(), Some("alice"));
+    }
+
+    async fn callback(
+        login: &Arc<dyn ConsoleAuth>,
+        code: &str,
+        state: &str,
+        nonce: Option<&str>,
+    ) -> Result<Response, Rejection> {
+        let mut request =
+            warp::test::request().path(&format!("/auth/callback?code={code}&state={state}"));
+        if let Some(nonce) = nonce {
+            request = request.header("cookie", format!("{LOGIN_COOKIE}={nonce}"));
+        }
+        request.filter(&routes(Some(login.clone()))).await
+    }
+
+    #[tokio::test]
+    async fn callbacks_start_sessions_for_allowed_users_only() {
+        let login = login();
+        let sessions = &login.login().unwrap().sessions;
+        let state = sessions.issue(Purpose::Login, "nonce", LOGIN_LIFETIME);
+
+        let response = callback(&login, "alice", &state, Some("nonce"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        let session = cookie
+            .strip_prefix("deploy_session=")
+            .and_then(|cookie| cookie.split(';').next())
+            .unwrap();
+        assert_eq!(login.user(None, Some(session)).as_deref(), Some("alice"));
+
+        let session = sessions.issue(Purpose::Session, "nonce", SESSION_LIFETIME);
+        let expired = sessions.issue(Purpose::Login, "nonce", Duration::ZERO);
+        for (code, state, nonce) in [
+            ("alice", state.as_str(), None),
+            ("alice", state.as_str(), Some("another browser")),
+            ("alice", session.as_str(), Some("nonce")),
+            ("alice", expired.as_str(), Some("nonce")),
+            ("mallory", state.as_str(), Some("nonce")),
+        ] {
+            let rejection = callback(&login, code, state, nonce).await.unwrap_err();
+            assert!(
+                rejection.find::<LoginFailed>().is_some(),
+                "{:?} was accepted",
+                (code, state, nonce)
+            );
+        }
+    }
+}
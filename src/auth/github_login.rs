@@ -0,0 +1,159 @@
+//! Logging in to the console with GitHub.
+
+use super::console::IdentityProvider;
+use crate::outbound;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+
+const AUTHORIZE: &str = "https://github.com/login/oauth/authorize";
+const ACCESS_TOKEN: &str = "https://github.com/login/oauth/access_token";
+const API: &str = "https://api.github.com";
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct User {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct Membership {
+    state: String,
+}
+
+/// A GitHub OAuth app, configured by `github_oauth_client_id` and
+/// `github_oauth_client_secret`. Users are known by their GitHub login. As anyone can log
+/// in with GitHub, either `console_allowed_users` must list who may, or
+/// `console_github_org` must name the organization they must be members of.
+pub struct GitHubLogin {
+    client: reqwest::Client,
+    client_id: String,
+    client_secret: String,
+    org: Option<String>,
+}
+
+impl GitHubLogin {
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name).unwrap_or_else(|_| {
+                panic!(
+                    "`{}` environment variable must be set for `github` console auth",
+                    name
+                )
+            })
+        };
+        let org = std::env::var("console_github_org")
+            .ok()
+            .filter(|org| !org.is_empty());
+        let allowed_users =
+            super::secrets(&std::env::var("console_allowed_users").unwrap_or_default());
+        if org.is_none() && allowed_users.is_empty() {
+            panic!("`console_allowed_users` or `console_github_org` environment variable must be set, and not empty, for `github` console auth");
+        }
+        Self {
+            client: outbound::client("github"),
+            client_id: var("github_oauth_client_id"),
+            client_secret: var("github_oauth_client_secret"),
+            org,
+        }
+    }
+
+    fn api(&self, path: &str, token: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(format!("{API}{path}"))
+            .bearer_auth(token)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+    }
+
+    async fn exchange(&self, redirect_uri: &str, code: &str) -> Result<String, String> {
+        let token: Token = self
+            .client
+            .post(ACCESS_TOKEN)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+            ])
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| error.to_string())?
+            .json()
+            .await
+            .map_err(|error| error.to_string())?;
+        // GitHub refuses codes with a 200 that has an error in place of the token.
+        let Token {
+            access_token,
+            error_description,
+        } = token;
+        let token = access_token.ok_or_else(|| {
+            error_description.unwrap_or_else(|| "No access token was given".to_owned())
+        })?;
+        let user: User = self
+            .api("/user", &token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| error.to_string())?
+            .json()
+            .await
+            .map_err(|error| error.to_string())?;
+        if let Some(org) = &self.org {
+            let membership: Membership = self
+                .api(&format!("/user/memberships/orgs/{org}"), &token)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|error| format!("{} is not a member of {org}: {error}", user.login))?
+                .json()
+                .await
+                .map_err(|error| error.to_string())?;
+            if membership.state != "active" {
+                return Err(format!("{} is not yet a member of {org}", user.login));
+            }
+        }
+        Ok(user.login)
+    }
+}
+
+impl IdentityProvider for GitHubLogin {
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    fn authorize_url<'a>(
+        &'a self,
+        redirect_uri: &'a str,
+        state: &'a str,
+    ) -> BoxFuture<'a, Result<String, String>> {
+        // Membership of an organization can only be read with the `read:org` scope.
+        let scope = if self.org.is_some() { "read:org" } else { "" };
+        let url = reqwest::Url::parse_with_params(
+            AUTHORIZE,
+            &[
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", redirect_uri),
+                ("scope", scope),
+                ("state", state),
+            ],
+        )
+        .map(String::from)
+        .map_err(|error| error.to_string());
+        futures::future::ready(url).boxed()
+    }
+
+    fn user<'a>(
+        &'a self,
+        redirect_uri: &'a str,
+        code: &'a str,
+    ) -> BoxFuture<'a, Result<String, String>> {
+        self.exchange(redirect_uri, code).boxed()
+    }
+}
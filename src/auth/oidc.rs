@@ -0,0 +1,174 @@
+//! Logging in to the console with any OpenID Connect provider.
+
+use super::console::IdentityProvider;
+use crate::outbound;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+
+/// The endpoints an issuer publishes at `/.well-known/openid-configuration`.
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+/// An OpenID Connect client, configured by `oidc_issuer`, `oidc_client_id` and
+/// `oidc_client_secret`. Users are known by their `email`, if the issuer has verified
+/// it, or else their `sub`, as `console_allowed_users` must list them. Names users
+/// can pick for themselves, such as `preferred_username`, are not trusted.
+pub struct Oidc {
+    client: reqwest::Client,
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+}
+
+impl Oidc {
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name).unwrap_or_else(|_| {
+                panic!(
+                    "`{}` environment variable must be set for `oidc` console auth",
+                    name
+                )
+            })
+        };
+        if super::secrets(&std::env::var("console_allowed_users").unwrap_or_default()).is_empty() {
+            panic!("`console_allowed_users` environment variable must be set, and not empty, for `oidc` console auth");
+        }
+        Self {
+            client: outbound::client("oidc"),
+            issuer: var("oidc_issuer").trim_end_matches('/').to_owned(),
+            client_id: var("oidc_client_id"),
+            client_secret: var("oidc_client_secret"),
+        }
+    }
+
+    /// Looks the endpoints up every time, as logging in is rare enough that they are not
+    /// worth caching, and so the issuer is free to move them.
+    async fn discover(&self) -> Result<Discovery, String> {
+        self.client
+            .get(format!("{}/.well-known/openid-configuration", self.issuer))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| error.to_string())?
+            .json()
+            .await
+            .map_err(|error| error.to_string())
+    }
+
+    async fn exchange(&self, redirect_uri: &str, code: &str) -> Result<String, String> {
+        let discovery = self.discover().await?;
+        let token: Token = self
+            .client
+            .post(&discovery.token_endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+            ])
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| error.to_string())?
+            .json()
+            .await
+            .map_err(|error| error.to_string())?;
+        let user: UserInfo = self
+            .client
+            .get(&discovery.userinfo_endpoint)
+            .bearer_auth(token.access_token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| error.to_string())?
+            .json()
+            .await
+            .map_err(|error| error.to_string())?;
+        Ok(identity(user))
+    }
+}
+
+/// Who logged in: their email only once the issuer vouches for it, as otherwise anyone
+/// could claim an allowed user's address.
+fn identity(user: UserInfo) -> String {
+    match user.email {
+        Some(email) if user.email_verified => email,
+        _ => user.sub,
+    }
+}
+
+impl IdentityProvider for Oidc {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn authorize_url<'a>(
+        &'a self,
+        redirect_uri: &'a str,
+        state: &'a str,
+    ) -> BoxFuture<'a, Result<String, String>> {
+        async move {
+            let discovery = self.discover().await?;
+            reqwest::Url::parse_with_params(
+                &discovery.authorization_endpoint,
+                &[
+                    ("response_type", "code"),
+                    ("client_id", self.client_id.as_str()),
+                    ("redirect_uri", redirect_uri),
+                    ("scope", "openid profile email"),
+                    ("state", state),
+                ],
+            )
+            .map(String::from)
+            .map_err(|error| error.to_string())
+        }
+        .boxed()
+    }
+
+    fn user<'a>(
+        &'a self,
+        redirect_uri: &'a str,
+        code: &'a str,
+    ) -> BoxFuture<'a, Result<String, String>> {
+        self.exchange(redirect_uri, code).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(email_verified: bool) -> UserInfo {
+        serde_json::from_value(serde_json::json!({
+            "sub": "248289761001",
+            "preferred_username": "alice",
+            "email": "alice@example.com",
+            "email_verified": email_verified,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_verified_emails_identify_users() {
+        assert_eq!(identity(user(true)), "alice@example.com");
+        assert_eq!(identity(user(false)), "248289761001");
+    }
+}
@@ -0,0 +1,217 @@
+use super::{InvalidSignature, Tokens};
+use crate::auth;
+use crate::payload;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::filters::path::FullPath;
+use warp::{reject, Filter, Rejection};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Requests signed longer ago than this (in seconds) are rejected as replays.
+const DEFAULT_MAX_SKEW: u64 = 300;
+
+/// The longest body a signed request may have. The bodies of these endpoints are a single
+/// ref update at most.
+const MAX_BODY: u64 = 16 * 1024;
+
+/// Signed-request mode for the token-based trigger endpoints.
+///
+/// A signed request carries an `X-Deploy-Signature: t=<unix time>,n=<nonce>,s=<signature>`
+/// header, where the signature is the hex HMAC-SHA256 of `{t}.{n}.{path}.{query}.{body}`
+/// keyed by the signing secret. The query is as it appears in the URL, without the `?`,
+/// and empty if there is none, and the body is the hex SHA-256 of the body as sent, so
+/// that neither can be changed in transit. Each nonce is accepted only once while its
+/// timestamp is within the allowed skew, so a captured request cannot be replayed.
+pub struct RequestSigning {
+    /// Requests signed with any of these are accepted.
+    secrets: Vec<String>,
+    max_skew: u64,
+    required: bool,
+    nonces: Mutex<HashMap<String, u64>>,
+}
+
+impl RequestSigning {
+    /// Requests are signed with the `signing_secret`, or else the actions secret, either
+    /// of which may be a comma separated list while it is rotated.
+    pub fn from_env(actions_secrets: &[String]) -> Self {
+        Self {
+            secrets: std::env::var("signing_secret")
+                .map(|secrets| auth::secrets(&secrets))
+                .unwrap_or_else(|_| actions_secrets.to_vec()),
+            max_skew: std::env::var("signed_request_max_skew")
+                .map(|skew| {
+                    skew.parse().expect(
+                        "`signed_request_max_skew` environment variable must be a number of seconds",
+                    )
+                })
+                .unwrap_or(DEFAULT_MAX_SKEW),
+            required: std::env::var("require_signed_requests")
+                .map(|required| required == "true")
+                .unwrap_or(false),
+            nonces: Mutex::default(),
+        }
+    }
+
+    fn verify(&self, header: &str, path: &str, query: &str, body: &[u8]) -> bool {
+        let mut timestamp = None;
+        let mut nonce = None;
+        let mut signature = None;
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+                Some(("n", value)) if !value.is_empty() => nonce = Some(value),
+                Some(("s", value)) => signature = hex::decode(value).ok(),
+                _ => {}
+            }
+        }
+        let (timestamp, nonce, signature) = match (timestamp, nonce, signature) {
+            (Some(timestamp), Some(nonce), Some(signature)) => (timestamp, nonce, signature),
+            _ => return false,
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if now.abs_diff(timestamp) > self.max_skew {
+            return false;
+        }
+
+        let message = signed_message(timestamp, nonce, path, query, body);
+        let signed = self.secrets.iter().any(|secret| {
+            let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(message.as_bytes());
+            mac.verify_slice(&signature).is_ok()
+        });
+        if !signed {
+            return false;
+        }
+
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, seen| *seen + self.max_skew >= now);
+        nonces.insert(nonce.to_owned(), timestamp).is_none()
+    }
+}
+
+/// What the signature of a request is the HMAC of.
+fn signed_message(timestamp: u64, nonce: &str, path: &str, query: &str, body: &[u8]) -> String {
+    let body = hex::encode(Sha256::digest(body));
+    format!("{timestamp}.{nonce}.{path}.{query}.{body}")
+}
+
+/// The sender recorded for requests authenticated by signature rather than by token.
+pub const SIGNED_SENDER: &str = "signed";
+
+/// Accepts either a signed request or, unless signatures are required, one of the API
+/// tokens in the `X-Deploy-Secret` header, extracting the name of the sender and the
+/// body, which has to be read to check the signature.
+pub fn verify_deploy_request(
+    tokens: Arc<Tokens>,
+    signing: Arc<RequestSigning>,
+) -> impl Filter<Extract = (String, Bytes), Error = Rejection> + Clone {
+    warp::header::optional::<String>("X-Deploy-Signature")
+        .and(warp::header::optional::<String>("X-Deploy-Secret"))
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(payload::bytes(MAX_BODY))
+        .and_then(
+            move |signature: Option<String>,
+                  secret: Option<String>,
+                  path: FullPath,
+                  query: String,
+                  body: Bytes| {
+                let sender = match (signature, secret) {
+                    (Some(signature), _) => signing
+                        .verify(&signature, path.as_str(), &query, &body)
+                        .then(|| SIGNED_SENDER.to_owned()),
+                    (None, Some(secret)) if !signing.required => {
+                        tokens.find(&secret).map(str::to_owned)
+                    }
+                    _ => None,
+                };
+                async move {
+                    match sender {
+                        Some(sender) => Ok((sender, body)),
+                        None => Err(reject::custom(InvalidSignature)),
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signing() -> Arc<RequestSigning> {
+        Arc::new(RequestSigning {
+            secrets: vec!["secret".to_owned()],
+            max_skew: DEFAULT_MAX_SKEW,
+            required: true,
+            nonces: Mutex::default(),
+        })
+    }
+
+    fn sign(nonce: &str, path: &str, query: &str, body: &[u8]) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut mac = HmacSha256::new_from_slice(b"secret").unwrap();
+        mac.update(signed_message(timestamp, nonce, path, query, body).as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        format!("t={timestamp},n={nonce},s={signature}")
+    }
+
+    async fn accepts(
+        signing: &Arc<RequestSigning>,
+        signature: &str,
+        uri: &str,
+        body: &[u8],
+    ) -> bool {
+        let tokens = Arc::new(Tokens::parse(&[], "").unwrap());
+        warp::test::request()
+            .method("POST")
+            .path(uri)
+            .header("X-Deploy-Signature", signature)
+            .body(body)
+            .filter(&verify_deploy_request(tokens, signing.clone()))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn signatures_cover_the_query_and_body() {
+        let body = br#"{"ref":"refs/heads/main"}"#;
+        let signature = sign("a", "/post-receive/app", "sha=abc", body);
+        assert!(accepts(&signing(), &signature, "/post-receive/app?sha=abc", body).await);
+        for (uri, body) in [
+            ("/post-receive/app?sha=def", &body[..]),
+            ("/post-receive/app?sha=abc&ref=main", &body[..]),
+            ("/post-receive/app", &body[..]),
+            ("/post-receive/other?sha=abc", &body[..]),
+            ("/post-receive/app?sha=abc", br#"{"ref":"refs/heads/evil"}"#),
+            ("/post-receive/app?sha=abc", b""),
+        ] {
+            assert!(
+                !accepts(&signing(), &signature, uri, body).await,
+                "{} was accepted",
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_requests_cannot_be_replayed() {
+        let signing = signing();
+        let signature = sign("a", "/deploy2/app", "", b"");
+        assert!(accepts(&signing, &signature, "/deploy2/app", b"").await);
+        assert!(!accepts(&signing, &signature, "/deploy2/app", b"").await);
+    }
+}
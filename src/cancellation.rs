@@ -0,0 +1,365 @@
+//! Cancelling a job from outside the task that runs it, by killing whatever process it is
+//! running, along with everything that process started.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::process::Child;
+use uuid::Uuid;
+
+/// How long a stopped process has to exit after `SIGTERM` before it is sent `SIGKILL`.
+pub const GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct Cancelled {
+    /// The name of the token the job was cancelled with.
+    pub by: String,
+    pub at: SystemTime,
+}
+
+/// Why a job was stopped before it finished.
+#[derive(Clone)]
+pub enum Stop {
+    Cancelled(Cancelled),
+    /// The job ran for longer than its timeout.
+    TimedOut(Duration),
+    /// The job store lost the lock the job deployed under, so another instance sharing
+    /// the store could start deploying the same app.
+    LockLost,
+    /// The job store could not lock the job's app for it, so it was never started, in
+    /// case another instance sharing the store was deploying the app.
+    LockFailed(String),
+    /// The job waited for its turn for longer than its app allows, so it never started.
+    Expired(Duration),
+}
+
+impl Stop {
+    /// Why the job failed, as its output says.
+    pub fn reason(&self) -> String {
+        match self {
+            Stop::Cancelled(cancelled) => format!("The job was cancelled by {}", cancelled.by),
+            Stop::TimedOut(timeout) => {
+                format!("The job timed out after {} seconds", timeout.as_secs())
+            }
+            Stop::LockLost => {
+                "The job lost its lock in the job store, so it was stopped before another \
+                 instance could deploy at the same time"
+                    .to_owned()
+            }
+            Stop::LockFailed(error) => format!(
+                "The job could not take its lock in the job store ({error}), so it was not \
+                 started in case another instance was deploying at the same time"
+            ),
+            Stop::Expired(wait) => format!(
+                "The job expired after waiting more than {} seconds for its turn to deploy",
+                wait.as_secs()
+            ),
+        }
+    }
+}
+
+/// Whether a job has had its turn to deploy.
+#[derive(Default)]
+enum Turn {
+    #[default]
+    Waiting,
+    Taken,
+    /// A later job of the same app was triggered while this one waited, and deploys in
+    /// its place.
+    Superseded(Uuid),
+}
+
+/// A process a job is running, and whatever it starts.
+pub struct Process {
+    id: u32,
+    /// The job object the process was assigned to, which the processes it starts join
+    /// too. On Unix, the process leads its own process group instead.
+    #[cfg(windows)]
+    job: job_object::JobObject,
+}
+
+impl Process {
+    /// Tracks a process that was just spawned, or returns `None` if it has already exited.
+    /// On Windows, the process must have been spawned suspended, with `SUSPENDED`, so that
+    /// it is in the job object before it can start anything, and it is resumed here.
+    pub fn of(child: &Child) -> Option<Self> {
+        let id = child.id()?;
+        #[cfg(windows)]
+        let job = {
+            let job = job_object::JobObject::assign(child.raw_handle()?);
+            if !job_object::resume(id) {
+                eprintln!("Failed to resume process {id}");
+            }
+            job?
+        };
+        Some(Self {
+            id,
+            #[cfg(windows)]
+            job,
+        })
+    }
+}
+
+/// The creation flag that spawns a process suspended, for `Process::of` to resume once it
+/// is in a job object.
+#[cfg(windows)]
+pub const SUSPENDED: u32 = windows_sys::Win32::System::Threading::CREATE_SUSPENDED;
+
+/// Shared between a job and the task running it.
+#[derive(Default)]
+pub struct Cancellation {
+    process: Mutex<Option<Process>>,
+    stopped: Mutex<Option<Stop>>,
+    turn: Mutex<Turn>,
+}
+
+impl Cancellation {
+    /// The cancellation of a job that has already finished.
+    pub fn finished(stopped: Option<Stop>, superseded_by: Option<Uuid>) -> Self {
+        Self {
+            process: Mutex::default(),
+            stopped: Mutex::new(stopped),
+            turn: Mutex::new(superseded_by.map_or(Turn::Taken, Turn::Superseded)),
+        }
+    }
+
+    /// Skips the job in favour of a later one, unless it has already started. Returns
+    /// whether it will be skipped.
+    pub fn supersede(&self, by: Uuid) -> bool {
+        let mut turn = self.turn.lock().unwrap();
+        match *turn {
+            Turn::Waiting => {
+                *turn = Turn::Superseded(by);
+                true
+            }
+            Turn::Taken | Turn::Superseded(..) => false,
+        }
+    }
+
+    /// Starts the job's turn to deploy, unless it was superseded, in which case this
+    /// returns the job that superseded it.
+    pub fn take_turn(&self) -> Option<Uuid> {
+        let mut turn = self.turn.lock().unwrap();
+        match *turn {
+            Turn::Superseded(by) => Some(by),
+            Turn::Waiting | Turn::Taken => {
+                *turn = Turn::Taken;
+                None
+            }
+        }
+    }
+
+    pub fn superseded_by(&self) -> Option<Uuid> {
+        match *self.turn.lock().unwrap() {
+            Turn::Superseded(by) => Some(by),
+            Turn::Waiting | Turn::Taken => None,
+        }
+    }
+
+    pub fn stopped(&self) -> Option<Stop> {
+        self.stopped.lock().unwrap().clone()
+    }
+
+    pub fn cancelled(&self) -> Option<Cancelled> {
+        match self.stopped()? {
+            Stop::Cancelled(cancelled) => Some(cancelled),
+            Stop::TimedOut(..) | Stop::LockLost | Stop::LockFailed(..) | Stop::Expired(..) => None,
+        }
+    }
+
+    pub fn timed_out(&self) -> Option<Duration> {
+        match self.stopped()? {
+            Stop::TimedOut(timeout) => Some(timeout),
+            Stop::Cancelled(..) | Stop::LockLost | Stop::LockFailed(..) | Stop::Expired(..) => None,
+        }
+    }
+
+    pub fn expired(&self) -> Option<Duration> {
+        match self.stopped()? {
+            Stop::Expired(wait) => Some(wait),
+            Stop::Cancelled(..) | Stop::TimedOut(..) | Stop::LockLost | Stop::LockFailed(..) => {
+                None
+            }
+        }
+    }
+
+    /// Records the process the job is running, or that it is running none. A process that
+    /// starts as the job is stopped is terminated straight away.
+    pub fn running(self: &Arc<Self>, process: Option<Process>) {
+        let mut running = self.process.lock().unwrap();
+        *running = process;
+        if let (Some(process), Some(_)) = (&*running, self.stopped()) {
+            self.terminate(process);
+        }
+    }
+
+    /// Marks the job cancelled, so it starts nothing else, and terminates the process
+    /// tree it is running, if any. Returns false if the job was already stopped.
+    pub fn cancel(self: &Arc<Self>, by: String) -> bool {
+        self.stop(Stop::Cancelled(Cancelled {
+            by,
+            at: SystemTime::now(),
+        }))
+    }
+
+    /// Stops the job for running for longer than `timeout`, as cancelling it would.
+    pub fn time_out(self: &Arc<Self>, timeout: Duration) -> bool {
+        self.stop(Stop::TimedOut(timeout))
+    }
+
+    /// Stops the job for losing its lock in the job store, as cancelling it would.
+    pub fn lose_lock(self: &Arc<Self>) -> bool {
+        self.stop(Stop::LockLost)
+    }
+
+    /// Stops the job before it starts, for the job store failing to lock its app.
+    pub fn fail_to_lock(self: &Arc<Self>, error: String) -> bool {
+        self.stop(Stop::LockFailed(error))
+    }
+
+    /// Whether the job was stopped before its turn to deploy, so it is never started.
+    pub fn stopped_before_turn(&self) -> bool {
+        matches!(
+            self.stopped(),
+            Some(Stop::Expired(..) | Stop::LockFailed(..))
+        )
+    }
+
+    /// Stops the job for waiting longer than `wait` for its turn, unless it was superseded
+    /// while it waited. Its turn is taken, so that it cannot be superseded after expiring.
+    pub fn expire(self: &Arc<Self>, wait: Duration) -> bool {
+        let mut turn = self.turn.lock().unwrap();
+        if let Turn::Superseded(..) = *turn {
+            return false;
+        }
+        *turn = Turn::Taken;
+        self.stop(Stop::Expired(wait))
+    }
+
+    fn stop(self: &Arc<Self>, stop: Stop) -> bool {
+        {
+            let mut stopped = self.stopped.lock().unwrap();
+            if stopped.is_some() {
+                return false;
+            }
+            *stopped = Some(stop);
+        }
+        if let Some(process) = &*self.process.lock().unwrap() {
+            self.terminate(process);
+        }
+        true
+    }
+
+    /// Sends the process group `SIGTERM`, then `SIGKILL` if the job is still running it
+    /// after a grace period. The job stops running it once all of its output is closed,
+    /// so anything left in the group holding on to it is killed too.
+    #[cfg(unix)]
+    fn terminate(self: &Arc<Self>, process: &Process) {
+        let id = process.id;
+        let group = id as libc::pid_t;
+        // SAFETY: `killpg` only sends a signal, and has no memory safety requirements.
+        unsafe { libc::killpg(group, libc::SIGTERM) };
+        let this = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(GRACE).await;
+            let running = this.process.lock().unwrap();
+            if matches!(&*running, Some(process) if process.id == id) {
+                // SAFETY: as above, `killpg` only sends a signal.
+                unsafe { libc::killpg(group, libc::SIGKILL) };
+            }
+        });
+    }
+
+    /// Terminates every process in the job object. Windows has no equivalent of `SIGTERM`
+    /// for processes without a window, so there is no grace period.
+    #[cfg(windows)]
+    fn terminate(self: &Arc<Self>, process: &Process) {
+        if !process.job.terminate() {
+            eprintln!("Could not terminate process {} or its children", process.id);
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn terminate(self: &Arc<Self>, process: &Process) {
+        eprintln!("Cannot terminate process {} on this platform", process.id);
+    }
+}
+
+#[cfg(windows)]
+mod job_object {
+    use std::os::windows::io::RawHandle;
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+    };
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, TerminateJobObject,
+    };
+    use windows_sys::Win32::System::Threading::{OpenThread, ResumeThread, THREAD_SUSPEND_RESUME};
+
+    /// An anonymous job object, closed when dropped. Closing it leaves its processes
+    /// running.
+    pub struct JobObject(HANDLE);
+
+    // SAFETY: job object handles can be used from any thread.
+    unsafe impl Send for JobObject {}
+    unsafe impl Sync for JobObject {}
+
+    impl JobObject {
+        /// Creates a job object containing the process, or returns `None` if it could
+        /// not. Anything the process starts after this joins the job object too.
+        pub fn assign(process: RawHandle) -> Option<Self> {
+            // SAFETY: the handle belongs to a child that has not been waited on, and the
+            // job object is closed if it cannot be assigned to.
+            unsafe {
+                let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+                if job == 0 {
+                    return None;
+                }
+                let job = Self(job);
+                (AssignProcessToJobObject(job.0, process as HANDLE) != 0).then_some(job)
+            }
+        }
+
+        /// Terminates every process in the job object. Returns whether it could.
+        pub fn terminate(&self) -> bool {
+            // SAFETY: the handle is open until the job object is dropped.
+            unsafe { TerminateJobObject(self.0, 1) != 0 }
+        }
+    }
+
+    /// Resumes the threads of a process that was spawned suspended. Returns whether any
+    /// were resumed.
+    pub fn resume(process: u32) -> bool {
+        // SAFETY: the entry is plain data, sized as the snapshot functions expect, and
+        // every handle opened here is closed before returning.
+        unsafe {
+            let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+            if snapshot == INVALID_HANDLE_VALUE {
+                return false;
+            }
+            let mut entry: THREADENTRY32 = std::mem::zeroed();
+            entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+            let mut resumed = false;
+            let mut found = Thread32First(snapshot, &mut entry) != 0;
+            while found {
+                if entry.th32OwnerProcessID == process {
+                    let thread = OpenThread(THREAD_SUSPEND_RESUME, 0, entry.th32ThreadID);
+                    if thread != 0 {
+                        resumed |= ResumeThread(thread) != u32::MAX;
+                        CloseHandle(thread);
+                    }
+                }
+                found = Thread32Next(snapshot, &mut entry) != 0;
+            }
+            CloseHandle(snapshot);
+            resumed
+        }
+    }
+
+    impl Drop for JobObject {
+        fn drop(&mut self) {
+            // SAFETY: the handle is open, and is not used again.
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Reads a script's output into lines, without letting very long lines, binary output or
+//! sheer volume fill the job with garbage.
+
+use futures::stream::{self, Stream};
+use std::collections::VecDeque;
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader};
+
+/// Lines longer than this many bytes are truncated, unless `max_line_length` says
+/// otherwise.
+const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Lines that are mostly not valid UTF-8 are taken to be binary.
+const BINARY_RATIO: f64 = 0.25;
+
+#[derive(Clone, Copy)]
+pub struct Limits {
+    max_line_length: usize,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        Self {
+            max_line_length: std::env::var("max_line_length")
+                .map(|length| {
+                    length
+                        .parse()
+                        .expect("`max_line_length` environment variable must be a number")
+                })
+                .unwrap_or(DEFAULT_MAX_LINE_LENGTH),
+        }
+    }
+}
+
+pub enum Captured {
+    /// A line of text, truncated if it was too long.
+    Line(String),
+    /// A note about output that was left out.
+    Note(String),
+}
+
+/// Reads one line, keeping at most `max` bytes of it and counting the rest. Returns
+/// `None` once there is nothing left to read.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max: usize,
+) -> io::Result<Option<(Vec<u8>, usize)>> {
+    let mut line = vec![];
+    let mut dropped = 0;
+    let mut read_any = false;
+    loop {
+        let buffer = reader.fill_buf().await?;
+        if buffer.is_empty() {
+            return Ok(if read_any {
+                Some((line, dropped))
+            } else {
+                None
+            });
+        }
+        read_any = true;
+        let newline = buffer.iter().position(|byte| *byte == b'\n');
+        let chunk = &buffer[..newline.unwrap_or(buffer.len())];
+        let keep = chunk.len().min(max - line.len());
+        line.extend_from_slice(&chunk[..keep]);
+        dropped += chunk.len() - keep;
+        let used = newline.map_or(buffer.len(), |newline| newline + 1);
+        reader.consume(used);
+        if newline.is_some() {
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return Ok(Some((line, dropped)));
+        }
+    }
+}
+
+fn is_binary(line: &[u8], text: &str) -> bool {
+    let replaced = text
+        .chars()
+        .filter(|c| *c == char::REPLACEMENT_CHARACTER)
+        .count();
+    line.contains(&0) || replaced as f64 > line.len() as f64 * BINARY_RATIO
+}
+
+fn skipped_note(lines: usize) -> Captured {
+    Captured::Note(format!(
+        "[deploy-server] Skipped {lines} line{} of binary output",
+        if lines == 1 { "" } else { "s" }
+    ))
+}
+
+/// Splits output into lines, replacing invalid UTF-8 and truncating long lines. Runs of
+/// binary lines are left out, and replaced by a note saying how many there were.
+pub fn lines<R: AsyncRead + Unpin>(reader: R, limits: Limits) -> impl Stream<Item = Captured> {
+    let state = (BufReader::new(reader), 0, None);
+    stream::unfold(Some(state), move |state| async move {
+        let (mut reader, mut skipped, pending): (_, usize, Option<Captured>) = state?;
+        if let Some(pending) = pending {
+            return Some((pending, Some((reader, 0, None))));
+        }
+        loop {
+            let (bytes, dropped) = match read_line(&mut reader, limits.max_line_length).await {
+                Ok(Some(line)) => line,
+                Ok(None) | Err(..) => return (skipped > 0).then(|| (skipped_note(skipped), None)),
+            };
+            let mut text = String::from_utf8_lossy(&bytes).into_owned();
+            if is_binary(&bytes, &text) {
+                skipped += 1;
+                continue;
+            }
+            if dropped > 0 {
+                text.push_str(&format!(" [truncated {dropped} bytes]"));
+            }
+            let line = Captured::Line(text);
+            return Some(if skipped > 0 {
+                (skipped_note(skipped), Some((reader, 0, Some(line))))
+            } else {
+                (line, Some((reader, 0, None)))
+            });
+        }
+    })
+}
+
+/// Keeps a job's output within `max` bytes by keeping its head and tail, each up to half
+/// of it, and leaving out the middle. Lines past the head are only kept once the job
+/// finishes, since it is only then that the tail is known, and lines already kept must
+/// not move, but the tail so far can be looked at while it runs.
+pub struct OutputBudget<T> {
+    max: usize,
+    head: usize,
+    head_lines: usize,
+    tail: VecDeque<(T, usize)>,
+    tail_size: usize,
+    truncated: usize,
+    /// How many lines were left out of the middle.
+    dropped: usize,
+    /// How many lines the tail held when it was let go of.
+    released: usize,
+}
+
+/// Where the `n`th line admitted to a budget ended up.
+pub enum Position {
+    /// In the head, as the `n`th line.
+    Head(usize),
+    /// Left out of the middle.
+    Dropped,
+    /// In the tail, as its `n`th line.
+    Tail(usize),
+}
+
+impl<T> OutputBudget<T> {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            head: 0,
+            head_lines: 0,
+            tail: VecDeque::new(),
+            tail_size: 0,
+            truncated: 0,
+            dropped: 0,
+            released: 0,
+        }
+    }
+
+    /// Returns the line if it belongs to the head, or else holds it in the tail, leaving
+    /// out the oldest lines of the tail that no longer fit.
+    pub fn admit(&mut self, line: T, size: usize) -> Option<T> {
+        if self.tail.is_empty() && self.dropped == 0 && self.head + size <= self.max / 2 {
+            self.head += size;
+            self.head_lines += 1;
+            return Some(line);
+        }
+        self.tail.push_back((line, size));
+        self.tail_size += size;
+        while self.tail_size > self.max - self.max / 2 {
+            match self.tail.pop_front() {
+                Some((_, size)) => {
+                    self.tail_size -= size;
+                    self.truncated += size;
+                    self.dropped += 1;
+                }
+                None => break,
+            }
+        }
+        None
+    }
+
+    /// How many lines have been admitted, whether or not they were kept.
+    pub fn admitted(&self) -> usize {
+        self.head_lines + self.dropped + self.tail.len() + self.released
+    }
+
+    /// Where the `n`th line admitted is, or was left out.
+    pub fn position(&self, n: usize) -> Position {
+        if n < self.head_lines {
+            Position::Head(n)
+        } else if n < self.head_lines + self.dropped {
+            Position::Dropped
+        } else {
+            Position::Tail(n - self.head_lines - self.dropped)
+        }
+    }
+
+    /// The `n`th line of the tail, while it is still held.
+    pub fn held(&self, n: usize) -> Option<&T> {
+        self.tail.get(n).map(|(line, _)| line)
+    }
+
+    /// Where the tail starts in the output once it has been let go of: after the head,
+    /// and a note of what was left out, if anything was.
+    pub fn tail_start(&self) -> usize {
+        self.head_lines + usize::from(self.truncated > 0)
+    }
+
+    /// Lets go of the tail, along with how many bytes were left out before it.
+    pub fn release(&mut self) -> (usize, Vec<T>) {
+        self.tail_size = 0;
+        self.released += self.tail.len();
+        let tail = self.tail.drain(..).map(|(line, _)| line).collect();
+        (self.truncated, tail)
+    }
+}
+
+pub fn truncated_note(bytes: usize) -> String {
+    format!(
+        "[deploy-server] {:.1} MB of output truncated",
+        bytes as f64 / 1_000_000.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_lines_can_be_followed_before_they_are_kept() {
+        let mut budget = OutputBudget::new(4);
+        assert_eq!(budget.admit("a", 1), Some("a"));
+        assert_eq!(budget.admit("b", 1), Some("b"));
+        assert_eq!(budget.admit("c", 1), None);
+        assert_eq!(budget.admit("d", 1), None);
+        assert_eq!(budget.admit("e", 1), None);
+        assert_eq!(budget.admitted(), 5);
+        assert!(matches!(budget.position(2), Position::Dropped));
+        assert!(matches!(budget.position(4), Position::Tail(1)));
+        assert_eq!(budget.held(1), Some(&"e"));
+
+        assert_eq!(budget.release(), (1, vec!["d", "e"]));
+        assert_eq!(budget.admitted(), 5);
+        assert_eq!(budget.tail_start(), 3);
+    }
+}
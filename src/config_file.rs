@@ -0,0 +1,327 @@
+//! A TOML configuration file, for settings that are unwieldy as environment variables.
+//!
+//! ```toml
+//! console_address = "0.0.0.0"
+//! console_port = 8080
+//! github_actions_secret = "..."
+//! github_webhook_secret = ["old", "new"]
+//! scripts_directory = "/srv/deploy"
+//! tls_certificate = "/etc/letsencrypt/live/deploy.example.com/fullchain.pem"
+//! tls_key = "/etc/letsencrypt/live/deploy.example.com/privkey.pem"
+//! job_store = "sqlite:/var/lib/deploy-server/jobs.db"
+//! max_concurrent_deploys = 4
+//!
+//! [deploy_tokens]
+//! ci = "..."
+//!
+//! [apps.web]
+//! owner = "web-team"
+//! branches = ["main"]
+//!
+//! [apps."web.staging"]
+//! branches = ["develop"]
+//! ```
+//!
+//! Each setting is the environment variable of the same name, which takes precedence when
+//! it is also set, and any environment variable the server reads may be set. Secrets may
+//! be lists, to accept any of them while rotating them, as may other comma separated
+//! settings, and those of `name=value` pairs may be tables. Each table in `apps` is the
+//! settings of an app, or of an environment of an app when named `{app}.{environment}`,
+//! as `{app}.json` would be. Anything else is refused.
+
+use crate::settings::{self, AppSettings};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// The settings the file may set besides those it checks itself, which it passes on to
+/// their environment variables as they are.
+const SETTINGS: &[&str] = &[
+    "any_ref_tokens",
+    "console_github_org",
+    "console_locale",
+    "console_locales",
+    "console_session_secret",
+    "deploy_tokens",
+    "display_timezone",
+    "github_oauth_client_id",
+    "github_oauth_client_secret",
+    "github_proxy",
+    "github_token",
+    "hook_batch_window",
+    "hook_on_batch",
+    "hook_on_failure",
+    "hook_on_finish",
+    "hook_on_stall",
+    "hook_on_start",
+    "hook_on_trigger",
+    "job_history",
+    "job_store",
+    "job_timeout",
+    "max_concurrent_deploys",
+    "max_job_age",
+    "max_jobs",
+    "max_line_length",
+    "max_output",
+    "max_queue_wait",
+    "mdns_name",
+    "nomad_addr",
+    "nomad_proxy",
+    "nomad_token",
+    "oidc_client_id",
+    "oidc_client_secret",
+    "oidc_issuer",
+    "oidc_proxy",
+    "outbound_proxy",
+    "public_url",
+    "record_signing_key",
+    "replica_proxy",
+    "replica_url",
+    "require_signed_requests",
+    "shutdown_grace_period",
+    "signed_request_max_skew",
+    "signing_secret",
+    "stall_after",
+    "token_namespaces",
+    "trigger_response",
+    "trusted_proxies",
+    "user_namespaces",
+    "webhook_provider",
+];
+
+#[derive(Deserialize)]
+pub struct ConfigFile {
+    console_address: Option<IpAddr>,
+    console_port: Option<u16>,
+    #[serde(default, deserialize_with = "settings::deserialize_secrets")]
+    github_actions_secret: Vec<String>,
+    #[serde(default, deserialize_with = "settings::deserialize_secrets")]
+    github_webhook_secret: Vec<String>,
+    replication_secret: Option<String>,
+    /// Where the deploy scripts and settings of apps are.
+    scripts_directory: Option<PathBuf>,
+    read_only: Option<bool>,
+    console_username: Option<String>,
+    /// The argon2 PHC string or bcrypt hash of the console password.
+    console_password_hash: Option<String>,
+    /// `basic`, `oidc` or `github`.
+    console_auth: Option<String>,
+    /// Who may log in to the console with `oidc` or `github`.
+    #[serde(default)]
+    console_allowed_users: Vec<String>,
+    /// PEM files to serve HTTPS with.
+    tls_certificate: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    #[serde(default)]
+    apps: BTreeMap<String, toml::Value>,
+    /// Everything else, which must be in `SETTINGS`.
+    #[serde(flatten)]
+    settings: BTreeMap<String, toml::Value>,
+}
+
+impl ConfigFile {
+    /// Reads and checks the file, describing where it is wrong if it is.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| format!("Failed to read {}: {error}", path.display()))?;
+        let file: Self = toml::from_str(&contents)
+            .map_err(|error| format!("Invalid configuration in {}: {error}", path.display()))?;
+        file.check()
+            .map_err(|error| format!("Invalid configuration in {}: {error}", path.display()))?;
+        Ok(file)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.console_port == Some(0) {
+            return Err("`console_port` must not be 0".to_owned());
+        }
+        if matches!(&self.replication_secret, Some(secret) if secret.is_empty()) {
+            return Err("`replication_secret` must not be empty".to_owned());
+        }
+        for (name, secrets) in [
+            ("github_actions_secret", &self.github_actions_secret),
+            ("github_webhook_secret", &self.github_webhook_secret),
+        ] {
+            if secrets.iter().any(|secret| secret.contains(',')) {
+                return Err(format!("`{name}` must not contain commas"));
+            }
+        }
+        if let Some(directory) = &self.scripts_directory {
+            if !directory.is_dir() {
+                return Err(format!(
+                    "`scripts_directory` {} is not a directory",
+                    directory.display()
+                ));
+            }
+        }
+        for (app, settings) in &self.apps {
+            settings
+                .clone()
+                .try_into::<AppSettings>()
+                .map_err(|error| format!("invalid settings for `{app}`: {error}"))?;
+        }
+        for (name, value) in &self.settings {
+            if !SETTINGS.contains(&name.as_str()) {
+                return Err(format!(
+                    "unknown setting `{name}`, which is not an environment variable the \
+                     server reads"
+                ));
+            }
+            variable(value).map_err(|error| format!("`{name}` {error}"))?;
+        }
+        Ok(())
+    }
+
+    /// Sets the environment variables the file sets that are not already set, and makes
+    /// its app settings available to deploys.
+    pub fn apply(self) {
+        let variables: [(&str, Option<OsString>); 13] = [
+            (
+                "console_address",
+                self.console_address
+                    .map(|address| address.to_string().into()),
+            ),
+            (
+                "console_port",
+                self.console_port.map(|port| port.to_string().into()),
+            ),
+            ("github_actions_secret", list(self.github_actions_secret)),
+            ("github_webhook_secret", list(self.github_webhook_secret)),
+            (
+                "replication_secret",
+                self.replication_secret.map(Into::into),
+            ),
+            ("scripts_directory", self.scripts_directory.map(Into::into)),
+            (
+                "read_only",
+                self.read_only.map(|read_only| read_only.to_string().into()),
+            ),
+            ("console_username", self.console_username.map(Into::into)),
+            (
+                "console_password_hash",
+                self.console_password_hash.map(Into::into),
+            ),
+            ("console_auth", self.console_auth.map(Into::into)),
+            ("console_allowed_users", list(self.console_allowed_users)),
+            ("tls_certificate", self.tls_certificate.map(Into::into)),
+            ("tls_key", self.tls_key.map(Into::into)),
+        ];
+        let settings = self.settings.iter().map(|(name, value)| {
+            let value = variable(value).expect("the file was checked");
+            (name.as_str(), Some(value.into()))
+        });
+        for (name, value) in IntoIterator::into_iter(variables).chain(settings) {
+            if let (Some(value), None) = (value, std::env::var_os(name)) {
+                std::env::set_var(name, value);
+            }
+        }
+        settings::configure(self.apps);
+    }
+}
+
+/// A list of secrets as an environment variable would give it, if there are any.
+fn list(secrets: Vec<String>) -> Option<OsString> {
+    (!secrets.is_empty()).then(|| secrets.join(",").into())
+}
+
+/// A setting as its environment variable would give it: lists comma separated, and tables
+/// as comma separated `name=value` pairs.
+fn variable(value: &toml::Value) -> Result<String, String> {
+    let item = |value: &toml::Value| match value {
+        toml::Value::Table(..) | toml::Value::Array(..) => {
+            Err("must not have lists or tables inside it".to_owned())
+        }
+        value => match variable(value)? {
+            item if item.contains(',') => Err("must not contain commas".to_owned()),
+            item => Ok(item),
+        },
+    };
+    Ok(match value {
+        toml::Value::String(value) => value.clone(),
+        toml::Value::Integer(value) => value.to_string(),
+        toml::Value::Float(value) => value.to_string(),
+        toml::Value::Boolean(value) => value.to_string(),
+        toml::Value::Datetime(value) => value.to_string(),
+        toml::Value::Array(items) => items
+            .iter()
+            .map(item)
+            .collect::<Result<Vec<_>, _>>()?
+            .join(","),
+        toml::Value::Table(pairs) => pairs
+            .iter()
+            .map(|(name, value)| Ok(format!("{name}={}", item(value)?)))
+            .collect::<Result<Vec<_>, String>>()?
+            .join(","),
+    })
+}
+
+/// The configuration file named by `--config`, or else the `config_file` environment
+/// variable, if either is set.
+pub fn path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let path = args.next().expect("`--config` must be followed by a path");
+            return Some(path.into());
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    std::env::var_os("config_file").map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<ConfigFile, String> {
+        let file: ConfigFile = toml::from_str(contents).map_err(|error| error.to_string())?;
+        file.check()?;
+        Ok(file)
+    }
+
+    #[test]
+    fn other_settings_are_passed_on_as_environment_variables() {
+        let file = parse(
+            r#"
+                job_store = "sqlite:jobs.db"
+                max_concurrent_deploys = 4
+                read_only = false
+                trusted_proxies = ["10.0.0.1", "10.0.0.2"]
+
+                [deploy_tokens]
+                ci = "secret"
+                release = "other"
+            "#,
+        )
+        .unwrap();
+        let variables: BTreeMap<_, _> = file
+            .settings
+            .iter()
+            .map(|(name, value)| (name.as_str(), variable(value).unwrap()))
+            .collect();
+        assert_eq!(variables["job_store"], "sqlite:jobs.db");
+        assert_eq!(variables["max_concurrent_deploys"], "4");
+        assert_eq!(variables["trusted_proxies"], "10.0.0.1,10.0.0.2");
+        assert_eq!(variables["deploy_tokens"], "ci=secret,release=other");
+        assert!(!variables.contains_key("read_only"));
+        assert_eq!(file.read_only, Some(false));
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        let error = parse("job_stor = \"sqlite:jobs.db\"").err().unwrap();
+        assert!(error.contains("unknown setting `job_stor`"), "{}", error);
+        assert!(parse("config_file = \"other.toml\"").is_err());
+    }
+
+    #[test]
+    fn settings_cannot_be_nested_or_contain_commas() {
+        assert!(parse("trusted_proxies = [[\"10.0.0.1\"]]").is_err());
+        assert!(parse("trusted_proxies = [\"10.0.0.1,10.0.0.2\"]").is_err());
+        assert!(parse("[deploy_tokens.ci]\nsecret = \"x\"").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Webhook deliveries that were refused, kept so that an admin can process them again
+//! once whatever refused them has been fixed, rather than asking the sender to redeliver.
+
+use crate::auth::{self, WebhookProvider};
+use crate::timezone::DisplayTimezone;
+use crate::{iso8601, unix_millis};
+use bytes::Bytes;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use warp::http::HeaderMap;
+
+/// How many refused deliveries to keep. Older ones are forgotten first.
+const KEPT: usize = 50;
+
+/// How many bytes of bodies to keep across all refused deliveries, however few there are,
+/// so that whoever can reach the server cannot fill its memory with unsigned deliveries.
+const KEPT_BYTES: usize = 64 * 1024 * 1024;
+
+/// A webhook delivery as it was received.
+pub struct Delivery {
+    pub id: Uuid,
+    pub received_at: SystemTime,
+    pub target: (String, Option<String>),
+    pub headers: HeaderMap,
+    /// Kept even if the delivery was not signed with a known secret, as it is verified
+    /// again when it is processed again, perhaps once the secret has been fixed.
+    pub body: Bytes,
+    pub reason: &'static str,
+}
+
+/// A refused delivery as /admin/deliveries lists it.
+#[derive(Serialize)]
+struct RefusedDelivery<'a> {
+    id: Uuid,
+    received_at: u64,
+    received_at_iso: String,
+    app: &'a str,
+    environment: Option<&'a str>,
+    reason: &'static str,
+    size: usize,
+}
+
+/// A refused delivery as the console lists it.
+pub struct RecentDelivery {
+    pub id: Uuid,
+    pub received_at: String,
+    pub app: String,
+    pub environment: Option<String>,
+    pub reason: &'static str,
+}
+
+/// How webhook deliveries are verified, and those that were recently refused.
+pub struct Webhooks {
+    provider: Arc<dyn WebhookProvider>,
+    secrets: Vec<String>,
+    refused: RwLock<VecDeque<Delivery>>,
+}
+
+impl Webhooks {
+    /// Deliveries are signed by the `webhook_provider` (GitHub by default) with the
+    /// `github_webhook_secret`, or any of them while it is rotated, as a comma separated
+    /// list.
+    pub fn from_env() -> Self {
+        Self {
+            provider: auth::provider(
+                &std::env::var("webhook_provider").unwrap_or_else(|_| "github".to_owned()),
+            )
+            .expect("`webhook_provider` environment variable must be a known provider"),
+            secrets: auth::secrets(&std::env::var("github_webhook_secret").unwrap_or_default()),
+            refused: RwLock::default(),
+        }
+    }
+
+    /// The provider that apps use unless their settings name another.
+    pub fn provider(&self) -> &Arc<dyn WebhookProvider> {
+        &self.provider
+    }
+
+    /// Checks that a delivery is signed with one of `secrets`, from the app's settings,
+    /// or else one of the server's secrets if the app has none.
+    pub fn verify(
+        &self,
+        provider: &dyn WebhookProvider,
+        secrets: &[String],
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> bool {
+        let secrets = if secrets.is_empty() {
+            &self.secrets
+        } else {
+            secrets
+        };
+        secrets
+            .iter()
+            .any(|secret| provider.verify(headers, body, secret))
+    }
+
+    pub async fn refuse(&self, delivery: Delivery) {
+        let mut refused = self.refused.write().await;
+        refused.push_back(delivery);
+        while refused.len() > KEPT || kept_bytes(&refused) > KEPT_BYTES {
+            refused.pop_front();
+        }
+    }
+
+    /// Removes a refused delivery, to process it again.
+    pub async fn take(&self, id: Uuid) -> Option<Delivery> {
+        let mut refused = self.refused.write().await;
+        let index = refused.iter().position(|delivery| delivery.id == id)?;
+        refused.remove(index)
+    }
+
+    /// The refused deliveries as JSON, most recent first.
+    pub async fn list(&self) -> serde_json::Value {
+        let refused = self.refused.read().await;
+        let deliveries: Vec<_> = refused
+            .iter()
+            .rev()
+            .map(|delivery| RefusedDelivery {
+                id: delivery.id,
+                received_at: unix_millis(delivery.received_at),
+                received_at_iso: iso8601(delivery.received_at),
+                app: &delivery.target.0,
+                environment: delivery.target.1.as_deref(),
+                reason: delivery.reason,
+                size: delivery.body.len(),
+            })
+            .collect();
+        serde_json::to_value(deliveries).unwrap()
+    }
+
+    /// The refused deliveries for the console, most recent first.
+    pub async fn recent(&self, timezone: DisplayTimezone) -> Vec<RecentDelivery> {
+        self.refused
+            .read()
+            .await
+            .iter()
+            .rev()
+            .map(|delivery| RecentDelivery {
+                id: delivery.id,
+                received_at: timezone.format(delivery.received_at),
+                app: delivery.target.0.clone(),
+                environment: delivery.target.1.clone(),
+                reason: delivery.reason,
+            })
+            .collect()
+    }
+}
+
+fn kept_bytes(refused: &VecDeque<Delivery>) -> usize {
+    refused.iter().map(|delivery| delivery.body.len()).sum()
+}
@@ -0,0 +1,211 @@
+//! Filter scripts, which decide what a webhook delivery deploys when an app's `events`,
+//! `branches` and `tags` cannot say. They are written in Rhai, and need the `scripting`
+//! feature.
+//!
+//! A script sees the delivery's decoded payload as `payload`, the provider's name for its
+//! event as `event`, and the app and environment it was sent for as `app` and
+//! `environment`. It returns `false` to skip the delivery, `true` or nothing to deploy it
+//! as it is, the name of another app to deploy instead, or a map with any of:
+//!
+//! - `app`: the app to deploy instead.
+//! - `env`: environment variables to set for the deploy, over those of the app's settings.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The most operations a script may run, so that one that loops forever cannot hold up the
+/// delivery.
+#[cfg(feature = "scripting")]
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// What a filter script decided to do with a delivery.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub enum Decision {
+    Skip,
+    Deploy {
+        /// The app to deploy instead of the one the delivery was sent for.
+        app: Option<String>,
+        env: BTreeMap<String, String>,
+    },
+}
+
+/// What a delivery is, for a filter script to decide about.
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub struct Delivery {
+    pub app: String,
+    pub environment: Option<String>,
+    pub event: Option<String>,
+    /// The decoded body of the delivery. Bodies that are not JSON are seen as nothing.
+    pub payload: Vec<u8>,
+}
+
+/// Runs the filter script at `script` on a delivery, returning why if it fails. Reading
+/// and running the script blocks, so it is done off the runtime's thread.
+pub async fn run(script: PathBuf, delivery: Delivery) -> Result<Decision, String> {
+    tokio::task::spawn_blocking(move || {
+        let source = std::fs::read_to_string(&script)
+            .map_err(|error| format!("Failed to read {}: {error}", script.display()))?;
+        evaluate(&source, &delivery)
+    })
+    .await
+    .map_err(|error| error.to_string())?
+}
+
+#[cfg(not(feature = "scripting"))]
+fn evaluate(_: &str, _: &Delivery) -> Result<Decision, String> {
+    Err("Filter scripts need the server to be built with the `scripting` feature".to_owned())
+}
+
+#[cfg(feature = "scripting")]
+fn evaluate(source: &str, delivery: &Delivery) -> Result<Decision, String> {
+    use rhai::{Dynamic, Engine, Map, Scope};
+
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    let payload = match serde_json::from_slice::<serde_json::Value>(&delivery.payload) {
+        Ok(payload) => rhai::serde::to_dynamic(payload).map_err(|error| error.to_string())?,
+        Err(..) => Dynamic::UNIT,
+    };
+    let optional = |value: &Option<String>| value.clone().map_or(Dynamic::UNIT, Dynamic::from);
+    let mut scope = Scope::new();
+    scope.push_constant("payload", payload);
+    scope.push_constant("event", optional(&delivery.event));
+    scope.push_constant("app", delivery.app.clone());
+    scope.push_constant("environment", optional(&delivery.environment));
+    let result = engine
+        .eval_with_scope::<Dynamic>(&mut scope, source)
+        .map_err(|error| error.to_string())?;
+
+    if result.is_unit() {
+        return Ok(Decision::Deploy {
+            app: None,
+            env: BTreeMap::new(),
+        });
+    }
+    if let Some(deploy) = result.clone().try_cast::<bool>() {
+        return Ok(match deploy {
+            true => Decision::Deploy {
+                app: None,
+                env: BTreeMap::new(),
+            },
+            false => Decision::Skip,
+        });
+    }
+    if result.is_string() {
+        return Ok(Decision::Deploy {
+            app: Some(result.to_string()),
+            env: BTreeMap::new(),
+        });
+    }
+    let type_name = result.type_name();
+    let map = result
+        .try_cast::<Map>()
+        .ok_or_else(|| format!("The script returned {type_name}, not a decision"))?;
+    let mut app = None;
+    let mut env = BTreeMap::new();
+    for (key, value) in map {
+        match key.as_str() {
+            "app" if value.is_string() => app = Some(value.to_string()),
+            "env" => {
+                let variables = value
+                    .try_cast::<Map>()
+                    .ok_or("The script's `env` must be a map")?;
+                env.extend(
+                    variables
+                        .into_iter()
+                        .map(|(name, value)| (name.to_string(), value.to_string())),
+                );
+            }
+            _ => return Err(format!("The script returned an unexpected `{key}`")),
+        }
+    }
+    Ok(Decision::Deploy { app, env })
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+
+    fn decide(source: &str, payload: &str) -> Result<Decision, String> {
+        let delivery = Delivery {
+            app: "app".to_owned(),
+            environment: Some("staging".to_owned()),
+            event: Some("push".to_owned()),
+            payload: payload.as_bytes().to_vec(),
+        };
+        evaluate(source, &delivery)
+    }
+
+    fn deploy(app: Option<&str>, env: &[(&str, &str)]) -> Decision {
+        Decision::Deploy {
+            app: app.map(str::to_owned),
+            env: env
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn scripts_see_the_payload_and_delivery() {
+        let source = r#"
+            payload.head_commit.message.contains("[skip deploy]")
+                || event != "push"
+                || app != "app"
+                || environment != "staging"
+        "#;
+        let skipped = r#"{ "head_commit": { "message": "Fix [skip deploy]" } }"#;
+        let deployed = r#"{ "head_commit": { "message": "Fix" } }"#;
+        assert_eq!(decide(&format!("!({source})"), skipped), Ok(Decision::Skip));
+        assert_eq!(
+            decide(&format!("!({source})"), deployed),
+            Ok(deploy(None, &[]))
+        );
+    }
+
+    #[test]
+    fn returning_nothing_deploys() {
+        assert_eq!(decide("let x = 1;", "{}"), Ok(deploy(None, &[])));
+    }
+
+    #[test]
+    fn returning_a_name_deploys_that_app() {
+        assert_eq!(
+            decide(r#""app-" + payload.service"#, r#"{ "service": "api" }"#),
+            Ok(deploy(Some("app-api"), &[]))
+        );
+    }
+
+    #[test]
+    fn returning_a_map_deploys_an_app_with_env() {
+        assert_eq!(
+            decide(
+                r#"#{ app: "other", env: #{ REVISION: payload.after, SHARDS: 3 } }"#,
+                r#"{ "after": "abc123" }"#
+            ),
+            Ok(deploy(
+                Some("other"),
+                &[("REVISION", "abc123"), ("SHARDS", "3")]
+            ))
+        );
+        assert_eq!(
+            decide(r#"#{ env: #{ FAST: "1" } }"#, "{}"),
+            Ok(deploy(None, &[("FAST", "1")]))
+        );
+    }
+
+    #[test]
+    fn payloads_that_are_not_json_are_nothing() {
+        assert_eq!(decide("payload == ()", "not json"), Ok(deploy(None, &[])));
+    }
+
+    #[test]
+    fn unexpected_results_and_errors_fail() {
+        assert!(decide("42", "{}").is_err());
+        assert!(decide(r#"#{ environment: "production" }"#, "{}").is_err());
+        assert!(decide(r#"#{ env: "FAST=1" }"#, "{}").is_err());
+        assert!(decide("payload.missing.field", "{}").is_err());
+        assert!(decide("loop {}", "{}").is_err());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::outbound;
+use serde::Deserialize;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+const API: &str = "https://api.github.com";
+
+/// Client for the GitHub API, authenticated with the `github_token` environment variable.
+pub struct GitHub {
+    client: reqwest::Client,
+    token: Option<String>,
+}
+
+/// A workflow run artifact to download before running the deploy script, as passed to
+/// /deploy2 in the `repository`, `run_id` and `artifact` query parameters.
+pub use deploy_server_types::Artifact as ArtifactSource;
+
+/// The label on issues opened about failing deploys, used to find them again.
+const FAILURE_LABEL: &str = "deploy-failure";
+
+#[derive(Deserialize)]
+struct Issue {
+    number: u64,
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct Deployment {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct Environment {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct EnvironmentSettings {
+    #[serde(default)]
+    protection_rules: Vec<ProtectionRule>,
+}
+
+#[derive(Deserialize)]
+struct ProtectionRule {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// The rules an environment's deploys must wait for.
+pub struct Protection {
+    pub required_reviewers: bool,
+    pub wait_timer: bool,
+}
+
+impl Protection {
+    pub fn is_protected(&self) -> bool {
+        self.required_reviewers || self.wait_timer
+    }
+}
+
+/// A workflow run, as far as deploys approved by it care.
+#[derive(Deserialize)]
+pub struct WorkflowRun {
+    pub head_sha: String,
+    pub repository: Repository,
+    pub status: String,
+    pub conclusion: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Repository {
+    pub full_name: String,
+}
+
+#[derive(Deserialize)]
+struct PendingDeployment {
+    environment: Environment,
+}
+
+#[derive(Deserialize)]
+struct Review {
+    state: String,
+    environments: Vec<Environment>,
+}
+
+/// Where a workflow run's job in an environment stands with its protection rules.
+pub enum Approval {
+    /// Waiting for a reviewer or a wait timer.
+    Pending,
+    Approved,
+    Rejected,
+    /// Neither waiting nor reviewed: the run has no job in the environment, or its wait
+    /// timer passed without anyone needing to review it.
+    Unrequested,
+}
+
+#[derive(Deserialize)]
+struct ArtifactList {
+    artifacts: Vec<Artifact>,
+}
+
+#[derive(Deserialize)]
+struct Artifact {
+    name: String,
+    expired: bool,
+    archive_download_url: String,
+}
+
+impl GitHub {
+    pub fn from_env() -> Self {
+        Self {
+            client: outbound::client("github"),
+            token: std::env::var("github_token").ok(),
+        }
+    }
+
+    fn token(&self) -> Result<&str, String> {
+        self.token
+            .as_deref()
+            .ok_or_else(|| "`github_token` environment variable must be set".to_owned())
+    }
+
+    fn request(
+        &self,
+        method: reqwest::Method,
+        url: String,
+    ) -> Result<reqwest::RequestBuilder, String> {
+        Ok(self
+            .client
+            .request(method, url)
+            .bearer_auth(self.token()?)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json"))
+    }
+
+    async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, String> {
+        request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| error.to_string())
+    }
+
+    /// Finds the open failure issue with the given title.
+    async fn find_failure_issue(
+        &self,
+        repository: &str,
+        title: &str,
+    ) -> Result<Option<u64>, String> {
+        let issues: Vec<Issue> = Self::send(
+            self.request(
+                reqwest::Method::GET,
+                format!("{API}/repos/{repository}/issues"),
+            )?
+            .query(&[("state", "open"), ("labels", FAILURE_LABEL)]),
+        )
+        .await?
+        .json()
+        .await
+        .map_err(|error| error.to_string())?;
+        Ok(issues
+            .into_iter()
+            .find(|issue| issue.title == title)
+            .map(|issue| issue.number))
+    }
+
+    async fn comment(&self, repository: &str, number: u64, body: &str) -> Result<(), String> {
+        Self::send(
+            self.request(
+                reqwest::Method::POST,
+                format!("{API}/repos/{repository}/issues/{number}/comments"),
+            )?
+            .json(&serde_json::json!({ "body": body })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Opens an issue about a failing deploy, or comments on the one that is already open.
+    pub async fn report_failure(
+        &self,
+        repository: &str,
+        title: &str,
+        body: &str,
+    ) -> Result<(), String> {
+        if let Some(number) = self.find_failure_issue(repository, title).await? {
+            return self.comment(repository, number, body).await;
+        }
+        Self::send(
+            self.request(
+                reqwest::Method::POST,
+                format!("{API}/repos/{repository}/issues"),
+            )?
+            .json(&serde_json::json!({
+                "title": title,
+                "body": body,
+                "labels": [FAILURE_LABEL],
+            })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Closes the open issue about a failing deploy, if there is one, with a comment.
+    pub async fn resolve_failure(
+        &self,
+        repository: &str,
+        title: &str,
+        body: &str,
+    ) -> Result<(), String> {
+        let number = match self.find_failure_issue(repository, title).await? {
+            Some(number) => number,
+            None => return Ok(()),
+        };
+        self.comment(repository, number, body).await?;
+        Self::send(
+            self.request(
+                reqwest::Method::PATCH,
+                format!("{API}/repos/{repository}/issues/{number}"),
+            )?
+            .json(&serde_json::json!({ "state": "closed" })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Records a deployment of `reference` to an environment, returning its id.
+    pub async fn create_deployment(
+        &self,
+        repository: &str,
+        reference: &str,
+        environment: &str,
+        description: &str,
+    ) -> Result<u64, String> {
+        let deployment: Deployment = Self::send(
+            self.request(
+                reqwest::Method::POST,
+                format!("{API}/repos/{repository}/deployments"),
+            )?
+            .json(&serde_json::json!({
+                "ref": reference,
+                "environment": environment,
+                "description": description,
+                // The deploy is already underway, so GitHub should neither merge the
+                // default branch into it nor wait for checks.
+                "auto_merge": false,
+                "required_contexts": [],
+            })),
+        )
+        .await?
+        .json()
+        .await
+        .map_err(|error| error.to_string())?;
+        Ok(deployment.id)
+    }
+
+    /// Sets the state of a deployment, such as `in_progress`, `success` or `failure`,
+    /// linking to the job.
+    pub async fn set_deployment_status(
+        &self,
+        repository: &str,
+        deployment: u64,
+        state: &str,
+        log_url: &str,
+    ) -> Result<(), String> {
+        Self::send(
+            self.request(
+                reqwest::Method::POST,
+                format!("{API}/repos/{repository}/deployments/{deployment}/statuses"),
+            )?
+            .json(&serde_json::json!({ "state": state, "log_url": log_url })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The rules that deploys to an environment must wait for: required reviewers or a
+    /// wait timer.
+    pub async fn protection(
+        &self,
+        repository: &str,
+        environment: &str,
+    ) -> Result<Protection, String> {
+        let settings: EnvironmentSettings = Self::send(self.request(
+            reqwest::Method::GET,
+            format!("{API}/repos/{repository}/environments/{environment}"),
+        )?)
+        .await?
+        .json()
+        .await
+        .map_err(|error| error.to_string())?;
+        let has = |kind: &str| {
+            settings
+                .protection_rules
+                .iter()
+                .any(|rule| rule.kind == kind)
+        };
+        Ok(Protection {
+            required_reviewers: has("required_reviewers"),
+            wait_timer: has("wait_timer"),
+        })
+    }
+
+    /// A workflow run of the repository.
+    pub async fn workflow_run(&self, repository: &str, run_id: u64) -> Result<WorkflowRun, String> {
+        Self::send(self.request(
+            reqwest::Method::GET,
+            format!("{API}/repos/{repository}/actions/runs/{run_id}"),
+        )?)
+        .await?
+        .json()
+        .await
+        .map_err(|error| error.to_string())
+    }
+
+    /// Where a workflow run's job in an environment stands with its protection rules.
+    pub async fn approval(
+        &self,
+        repository: &str,
+        run_id: u64,
+        environment: &str,
+    ) -> Result<Approval, String> {
+        let pending: Vec<PendingDeployment> = Self::send(self.request(
+            reqwest::Method::GET,
+            format!("{API}/repos/{repository}/actions/runs/{run_id}/pending_deployments"),
+        )?)
+        .await?
+        .json()
+        .await
+        .map_err(|error| error.to_string())?;
+        if pending
+            .iter()
+            .any(|pending| pending.environment.name == environment)
+        {
+            return Ok(Approval::Pending);
+        }
+        let reviews: Vec<Review> = Self::send(self.request(
+            reqwest::Method::GET,
+            format!("{API}/repos/{repository}/actions/runs/{run_id}/approvals"),
+        )?)
+        .await?
+        .json()
+        .await
+        .map_err(|error| error.to_string())?;
+        let states: Vec<_> = reviews
+            .iter()
+            .filter(|review| {
+                review
+                    .environments
+                    .iter()
+                    .any(|reviewed| reviewed.name == environment)
+            })
+            .map(|review| review.state.as_str())
+            .collect();
+        // Any rejection stops the job, whoever else approved it.
+        Ok(if states.contains(&"rejected") {
+            Approval::Rejected
+        } else if states.is_empty() {
+            Approval::Unrequested
+        } else {
+            Approval::Approved
+        })
+    }
+
+    /// Downloads and extracts an artifact into a directory named after it within `into`,
+    /// returning the path to that directory.
+    pub async fn download_artifact(
+        &self,
+        source: &ArtifactSource,
+        into: &Path,
+    ) -> Result<PathBuf, String> {
+        let token = self.token()?;
+        let list: ArtifactList = self
+            .client
+            .get(format!(
+                "{API}/repos/{}/actions/runs/{}/artifacts",
+                source.repository, source.run_id
+            ))
+            .query(&[("name", &source.artifact)])
+            .bearer_auth(token)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| format!("Failed to list artifacts: {error}"))?
+            .json()
+            .await
+            .map_err(|error| format!("Failed to list artifacts: {error}"))?;
+        let artifact = list
+            .artifacts
+            .into_iter()
+            .find(|artifact| artifact.name == source.artifact && !artifact.expired)
+            .ok_or_else(|| {
+                format!(
+                    "No artifact named {} in run {} of {}",
+                    source.artifact, source.run_id, source.repository
+                )
+            })?;
+
+        let archive = self
+            .client
+            .get(&artifact.archive_download_url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| format!("Failed to download artifact {}: {error}", artifact.name))?
+            .bytes()
+            .await
+            .map_err(|error| format!("Failed to download artifact {}: {error}", artifact.name))?;
+
+        let directory = into.join(&artifact.name);
+        tokio::task::spawn_blocking(move || -> Result<PathBuf, String> {
+            std::fs::create_dir_all(&directory).map_err(|error| error.to_string())?;
+            zip::ZipArchive::new(Cursor::new(archive))
+                .and_then(|mut zip| zip.extract(&directory))
+                .map_err(|error| format!("Failed to extract artifact: {error}"))?;
+            Ok(directory)
+        })
+        .await
+        .map_err(|error| error.to_string())?
+    }
+}
@@ -0,0 +1,213 @@
+use crate::Job;
+use deploy_server_types::{BatchEvent, JobEvent};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+#[derive(Clone, Copy, Debug)]
+pub enum HookPoint {
+    Trigger,
+    Start,
+    Finish,
+    Failure,
+    Stall,
+}
+
+impl HookPoint {
+    fn name(self) -> &'static str {
+        match self {
+            HookPoint::Trigger => "on_trigger",
+            HookPoint::Start => "on_start",
+            HookPoint::Finish => "on_finish",
+            HookPoint::Failure => "on_failure",
+            HookPoint::Stall => "on_stall",
+        }
+    }
+}
+
+/// How long the `on_batch` hook waits for more jobs to finish, unless `hook_batch_window`
+/// says.
+const DEFAULT_BATCH_WINDOW: Duration = Duration::from_secs(10);
+
+/// External executables run at points in a job's lifecycle. Each receives the job
+/// context as JSON on stdin.
+///
+/// The `on_batch` hook instead receives every job that finished within a window starting
+/// when the first of them did, so that a burst of deploys can be notified about in one
+/// message rather than one each. Jobs are batched by who is to be told about them, the
+/// contact of their app or failing that its owner, so no one is told of another's jobs.
+/// When it is configured, it replaces `on_finish` and `on_failure`, which are ignored with
+/// a warning, so that no job is notified about twice.
+#[derive(Default)]
+pub struct Hooks {
+    on_trigger: Option<PathBuf>,
+    on_start: Option<PathBuf>,
+    on_finish: Option<PathBuf>,
+    on_failure: Option<PathBuf>,
+    on_stall: Option<PathBuf>,
+    on_batch: Option<PathBuf>,
+    batch_window: Duration,
+    /// The jobs that have finished since each destination's batch window started.
+    batches: Mutex<HashMap<Option<String>, Vec<deploy_server_types::Job>>>,
+}
+
+impl Hooks {
+    pub fn from_env() -> Self {
+        let hook = |name: &str| std::env::var_os(name).map(PathBuf::from);
+        let on_batch = hook("hook_on_batch");
+        // `on_batch` replaces them, so they are left out rather than never run.
+        let replaced = |name: &str| {
+            let command = hook(name);
+            if command.is_some() && on_batch.is_some() {
+                eprintln!("Ignoring `{name}`, as `hook_on_batch` notifies about jobs instead");
+                return None;
+            }
+            command
+        };
+        Self {
+            on_trigger: hook("hook_on_trigger"),
+            on_start: hook("hook_on_start"),
+            on_finish: replaced("hook_on_finish"),
+            on_failure: replaced("hook_on_failure"),
+            on_stall: hook("hook_on_stall"),
+            on_batch,
+            batch_window: std::env::var("hook_batch_window")
+                .map(|seconds| {
+                    seconds.parse().map(Duration::from_secs).expect(
+                        "`hook_batch_window` environment variable must be a number of seconds",
+                    )
+                })
+                .unwrap_or(DEFAULT_BATCH_WINDOW),
+            batches: Mutex::default(),
+        }
+    }
+
+    /// The names of the hook points that have a command configured.
+    pub fn configured(&self) -> Vec<&'static str> {
+        [
+            HookPoint::Trigger,
+            HookPoint::Start,
+            HookPoint::Finish,
+            HookPoint::Failure,
+            HookPoint::Stall,
+        ]
+        .iter()
+        .copied()
+        .filter(|point| self.command(*point).is_some())
+        .map(HookPoint::name)
+        .chain(self.on_batch.as_ref().map(|_| "on_batch"))
+        .collect()
+    }
+
+    fn command(&self, point: HookPoint) -> Option<&PathBuf> {
+        match point {
+            HookPoint::Trigger => self.on_trigger.as_ref(),
+            HookPoint::Start => self.on_start.as_ref(),
+            HookPoint::Finish => self.on_finish.as_ref(),
+            HookPoint::Failure => self.on_failure.as_ref(),
+            HookPoint::Stall => self.on_stall.as_ref(),
+        }
+    }
+
+    /// Runs the hook configured for `point`, if any. Hook failures are logged but never
+    /// affect the job itself.
+    pub async fn run(&self, point: HookPoint, job: &Job) {
+        let command = match self.command(point) {
+            Some(command) => command,
+            None => return,
+        };
+        let event = JobEvent {
+            hook: point.name().to_owned(),
+            job: job.summary(),
+        };
+        execute(point.name(), command, serde_json::to_vec(&event).unwrap()).await;
+    }
+
+    /// Notifies about a finished job: by `on_batch` if it is configured, otherwise by
+    /// `on_finish`, and `on_failure` if it did not succeed.
+    pub async fn finish(self: &Arc<Self>, job: &Job) {
+        if self.on_batch.is_some() {
+            self.batch(job);
+            return;
+        }
+        self.run(HookPoint::Finish, job).await;
+        let status = job.result.borrow().status;
+        if status != Some(0) {
+            self.run(HookPoint::Failure, job).await;
+        }
+    }
+
+    /// Adds a finished job to its destination's batch for the `on_batch` hook, which runs
+    /// once the batch window has passed since the first job in the batch finished.
+    fn batch(self: &Arc<Self>, job: &Job) {
+        let destination = job.contact.clone().or_else(|| job.owner.clone());
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.entry(destination.clone()).or_default();
+        batch.push(job.summary());
+        if batch.len() == 1 {
+            let hooks = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(hooks.batch_window).await;
+                hooks.run_batch(destination).await;
+            });
+        }
+    }
+
+    /// Runs the `on_batch` hook for every batch that is still waiting for its window to
+    /// pass, so that none are lost when the server stops.
+    pub async fn flush(&self) {
+        let destinations: Vec<_> = self.batches.lock().unwrap().keys().cloned().collect();
+        for destination in destinations {
+            self.run_batch(destination).await;
+        }
+    }
+
+    async fn run_batch(&self, destination: Option<String>) {
+        let command = match &self.on_batch {
+            Some(command) => command,
+            None => return,
+        };
+        // The batch is gone if it was flushed before its window passed.
+        let jobs = match self.batches.lock().unwrap().remove(&destination) {
+            Some(jobs) => jobs,
+            None => return,
+        };
+        let event = BatchEvent {
+            hook: "on_batch".to_owned(),
+            destination,
+            jobs,
+        };
+        execute("on_batch", command, serde_json::to_vec(&event).unwrap()).await;
+    }
+}
+
+/// Runs a hook with the payload on stdin.
+async fn execute(name: &str, command: &Path, payload: Vec<u8>) {
+    let child = Command::new(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(error) => {
+            eprintln!("Failed to run {name} hook {}: {error}", command.display());
+            return;
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(error) = stdin.write_all(&payload).await {
+            eprintln!("Failed to write to {name} hook: {error}");
+        }
+    }
+
+    match child.wait().await {
+        Ok(status) if !status.success() => eprintln!("The {name} hook exited with {status}"),
+        Err(error) => eprintln!("Failed to wait for {name} hook: {error}"),
+        Ok(..) => {}
+    }
+}
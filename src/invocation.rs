@@ -0,0 +1,164 @@
+//! What each process a job ran was run with, so that it can be checked after the fact.
+
+use crate::settings::AppSettings;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Shown in place of the value of a variable that looks like it holds a secret.
+const REDACTED: &str = "[redacted]";
+
+/// Parts of variable names that suggest the value is a secret.
+const SECRET_NAMES: &[&str] = &["SECRET", "TOKEN", "PASSWORD", "KEY", "CREDENTIAL"];
+
+#[derive(Clone)]
+pub struct Invocation {
+    /// The program, with any symlinks resolved.
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// The variables the job set for the program, and the `DEPLOY_*` variables it
+    /// inherited from the server, redacted as its `Redaction` says.
+    pub env: BTreeMap<String, String>,
+}
+
+impl Invocation {
+    pub fn new(
+        program: &Path,
+        args: &[String],
+        env: &[(String, String)],
+        redaction: &Redaction,
+    ) -> Self {
+        let program = std::fs::canonicalize(program).unwrap_or_else(|_| program.to_owned());
+        let env = std::env::vars()
+            .filter(|(name, _)| name.starts_with("DEPLOY_"))
+            .chain(env.iter().cloned())
+            .map(|(name, value)| {
+                let value = redaction.redact(&name, &value);
+                (name, value)
+            })
+            .collect();
+        Self {
+            program,
+            args: args.to_vec(),
+            env,
+        }
+    }
+}
+
+/// Which values of a job's variables are shown. Those whose names look like secrets never
+/// are, nor are those set by the app's settings unless they are listed in its
+/// `shown_env`. Credentials in URLs are left out of the rest.
+#[derive(Default)]
+pub struct Redaction {
+    configured: BTreeSet<String>,
+    shown: BTreeSet<String>,
+}
+
+impl Redaction {
+    pub fn new(settings: &AppSettings) -> Self {
+        Self {
+            configured: settings.env.keys().cloned().collect(),
+            shown: settings.shown_env.iter().cloned().collect(),
+        }
+    }
+
+    fn redact(&self, name: &str, value: &str) -> String {
+        if self.configured.contains(name) && !self.shown.contains(name) {
+            REDACTED.to_owned()
+        } else {
+            redact(name, value)
+        }
+    }
+}
+
+/// The value of a variable as it may be shown: none of it if its name looks like it holds
+/// a secret, and otherwise without credentials in URLs.
+pub fn redact(name: &str, value: &str) -> String {
+    if is_secret(name) {
+        REDACTED.to_owned()
+    } else {
+        strip_userinfo(value)
+    }
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_uppercase();
+    SECRET_NAMES.iter().any(|secret| name.contains(secret))
+}
+
+/// The value with the user name and password of each URL in it redacted.
+fn strip_userinfo(value: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = value;
+    while let Some(scheme) = rest.find("://") {
+        let (before, after) = rest.split_at(scheme + 3);
+        stripped.push_str(before);
+        let end = after
+            .find(|c: char| matches!(c, '/' | '?' | '#') || c.is_whitespace())
+            .unwrap_or(after.len());
+        let authority = &after[..end];
+        match authority.rfind('@') {
+            Some(at) => {
+                stripped.push_str(REDACTED);
+                stripped.push_str(&authority[at..]);
+            }
+            None => stripped.push_str(authority),
+        }
+        rest = &after[end..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_that_look_secret_are_secret() {
+        assert!(is_secret("GITHUB_TOKEN"));
+        assert!(is_secret("db_password"));
+        assert!(is_secret("AWS_SECRET_ACCESS_KEY"));
+        assert!(!is_secret("DEPLOY_SHA"));
+        assert!(!is_secret("NODE_ENV"));
+    }
+
+    #[test]
+    fn configured_values_are_redacted_unless_shown() {
+        let mut settings = AppSettings::default();
+        settings
+            .env
+            .insert("NODE_ENV".to_owned(), "production".to_owned());
+        settings
+            .env
+            .insert("REGION".to_owned(), "eu-west-1".to_owned());
+        settings
+            .env
+            .insert("API_TOKEN".to_owned(), "abc".to_owned());
+        settings.shown_env = vec!["NODE_ENV".to_owned(), "API_TOKEN".to_owned()];
+        let redaction = Redaction::new(&settings);
+        assert_eq!(redaction.redact("NODE_ENV", "production"), "production");
+        assert_eq!(redaction.redact("REGION", "eu-west-1"), REDACTED);
+        assert_eq!(redaction.redact("API_TOKEN", "abc"), REDACTED);
+        assert_eq!(redaction.redact("DEPLOY_SHA", "abc123"), "abc123");
+    }
+
+    #[test]
+    fn credentials_in_urls_are_redacted() {
+        let redaction = Redaction::default();
+        assert_eq!(
+            redaction.redact("DATABASE_URL", "postgres://user:pass@db:5432/app"),
+            "postgres://[redacted]@db:5432/app"
+        );
+        assert_eq!(
+            redaction.redact(
+                "MIRRORS",
+                "https://a:b@one.example https://two.example/x?y@z"
+            ),
+            "https://[redacted]@one.example https://two.example/x?y@z"
+        );
+        assert_eq!(
+            redaction.redact("DEPLOY_REF", "refs/heads/main"),
+            "refs/heads/main"
+        );
+    }
+}
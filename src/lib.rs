@@ -0,0 +1,3594 @@
+//! Deploys apps when their repositories are pushed to, serving a console of the jobs it
+//! has run.
+
+use annotation::Annotation;
+use audit::RecordSigner;
+use auth::console::{self, ConsoleAuth};
+use auth::signing::RequestSigning;
+use auth::{InvalidSignature, Tokens, UnauthorizedSender, UnexpectedRef, WebhookProvider};
+use bytes::Bytes;
+use cancellation::{Cancellation, Cancelled, Process, Stop};
+use capture::Captured;
+use deliveries::{Delivery, RecentDelivery, Webhooks};
+use deploy_server_types as types;
+use deploy_server_types::TriggerSource;
+use filter::Decision;
+use futures::{join, Stream, StreamExt};
+use github::{Approval, ArtifactSource, GitHub};
+use hooks::{HookPoint, Hooks};
+use invocation::{Invocation, Redaction};
+use locale::{Locales, Messages};
+use namespaces::{Namespaces, Scope};
+use nomad::Nomad;
+use payload::{Payload, UndecodableBody, UnsupportedEncoding};
+use permits::Permits;
+use record::LogRecord;
+use rejections::Rejections;
+use replication::Replica;
+use request_id::Requested;
+use responses::TriggerResponses;
+use retention::Retention;
+use search::SearchQuery;
+use settings::{AppSettings, DeployDefaults, FailureCategory, FailureIssue, UnexpectedRepository};
+use state::{QueueDump, StateDump};
+use stats::StatsQuery;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::future::{ready, Future};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use store::JobStore;
+use timezone::DisplayTimezone;
+use tokio::io::AsyncRead;
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use usage::ResourceUsage;
+use uuid::Uuid;
+use warp::http::{HeaderMap, StatusCode};
+use warp::{reject, Filter, Rejection, Reply};
+
+mod annotation;
+mod audit;
+mod auth;
+mod cancellation;
+mod capture;
+mod config_file;
+mod deliveries;
+mod filter;
+mod github;
+mod hooks;
+mod invocation;
+mod live;
+mod locale;
+mod mdns;
+mod metrics;
+mod namespaces;
+mod nomad;
+mod outbound;
+mod payload;
+mod permits;
+mod progress;
+mod record;
+mod rejections;
+mod replication;
+mod request_id;
+mod responses;
+mod retention;
+mod search;
+mod settings;
+mod sse;
+mod state;
+mod stats;
+mod store;
+mod systemd;
+mod timezone;
+mod tls;
+mod usage;
+
+#[derive(Clone)]
+enum OutputLine {
+    Stdout(String),
+    Stderr(String),
+    Record(LogRecord),
+    Annotation(Annotation),
+}
+
+impl OutputLine {
+    fn stdout(line: String) -> Self {
+        Annotation::parse(&line, "stdout")
+            .map(OutputLine::Annotation)
+            .or_else(|| LogRecord::parse(&line, "stdout").map(OutputLine::Record))
+            .unwrap_or(OutputLine::Stdout(line))
+    }
+
+    fn stderr(line: String) -> Self {
+        Annotation::parse(&line, "stderr")
+            .map(OutputLine::Annotation)
+            .or_else(|| LogRecord::parse(&line, "stderr").map(OutputLine::Record))
+            .unwrap_or(OutputLine::Stderr(line))
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            OutputLine::Stdout(line) | OutputLine::Stderr(line) => line,
+            OutputLine::Record(record) => &record.message,
+            OutputLine::Annotation(annotation) => &annotation.message,
+        }
+    }
+
+    fn stream(&self) -> &'static str {
+        match self {
+            OutputLine::Stdout(..) => "stdout",
+            OutputLine::Stderr(..) => "stderr",
+            OutputLine::Record(record) => record.stream,
+            OutputLine::Annotation(annotation) => annotation.stream,
+        }
+    }
+}
+
+impl From<&Annotation> for types::Annotation {
+    fn from(annotation: &Annotation) -> Self {
+        Self {
+            level: annotation.level.to_owned(),
+            message: annotation.message.clone(),
+            title: annotation.title.clone(),
+            file: annotation.file.clone(),
+            line: annotation.line,
+        }
+    }
+}
+
+#[derive(Default)]
+struct JobResult {
+    output: Vec<OutputLine>,
+    /// When each line of `output` was read, since the trigger was received. Only `push`
+    /// and `finish` add to either, and always to both, so they line up.
+    output_elapsed: Vec<Duration>,
+    status: Option<i32>,
+    timeline: Vec<TimelineEvent>,
+    /// What the deploy script used, once it has exited.
+    usage: Option<ResourceUsage>,
+    invocations: Vec<Invocation>,
+    /// Limits how much output is kept while the job runs, for apps whose settings say.
+    budget: Option<capture::OutputBudget<(Duration, OutputLine)>>,
+    /// The app's categories of failure, to tag the job with if it fails.
+    failure_categories: Vec<FailureCategory>,
+    category: Option<String>,
+    /// Labels the script set with `::label` commands.
+    labels: BTreeMap<String, String>,
+}
+
+/// A point in a job's lifecycle, timed from when its trigger was received using the
+/// monotonic clock, so wall-clock adjustments cannot distort the gaps between events.
+#[derive(Clone)]
+struct TimelineEvent {
+    /// Events are named by the server, except in jobs replicated from another instance.
+    event: Cow<'static, str>,
+    elapsed: Duration,
+}
+
+impl From<&TimelineEvent> for types::TimelineEvent {
+    fn from(event: &TimelineEvent) -> Self {
+        Self {
+            event: event.event.to_string(),
+            elapsed_ms: event.elapsed.as_millis() as u64,
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A time as APIs show it, alongside the milliseconds since the Unix epoch: in ISO 8601
+/// format, in UTC.
+fn iso8601(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn api_line(line: &OutputLine, elapsed: Duration) -> types::OutputLine {
+    types::OutputLine {
+        stream: line.stream().to_owned(),
+        text: line.text().to_owned(),
+        elapsed_ms: elapsed.as_millis() as u64,
+    }
+}
+
+impl JobResult {
+    fn push(&mut self, elapsed: Duration, line: OutputLine) {
+        if let OutputLine::Stdout(text) = &line {
+            if let Some((name, value)) = annotation::parse_label(text) {
+                self.labels.insert(name, value);
+            }
+        }
+        let (elapsed, line) = match (&mut self.budget, self.status) {
+            (Some(budget), None) => {
+                let size = line.text().len();
+                match budget.admit((elapsed, line), size) {
+                    Some(line) => line,
+                    None => return,
+                }
+            }
+            _ => (elapsed, line),
+        };
+        self.output.push(line);
+        self.output_elapsed.push(elapsed);
+    }
+
+    /// Sets the exit status, first adding the tail of the output that was held back, and
+    /// a note of how much was left out before it.
+    fn finish(&mut self, status: i32) {
+        if let Some(budget) = &mut self.budget {
+            let (truncated, tail) = budget.release();
+            if truncated > 0 {
+                let elapsed = tail.first().map_or(Duration::ZERO, |(elapsed, _)| *elapsed);
+                self.output
+                    .push(OutputLine::Stderr(capture::truncated_note(truncated)));
+                self.output_elapsed.push(elapsed);
+            }
+            for (elapsed, line) in tail {
+                self.output.push(line);
+                self.output_elapsed.push(elapsed);
+            }
+        }
+        if status != 0 && self.category.is_none() {
+            let lines = self.output.iter().map(OutputLine::text);
+            self.category = FailureCategory::classify(&self.failure_categories, lines);
+        }
+        self.status = Some(status);
+    }
+
+    /// A line of output as API consumers see it.
+    fn line(&self, index: usize) -> types::OutputLine {
+        api_line(&self.output[index], self.output_elapsed[index])
+    }
+
+    /// How many lines the job has written, including those past the head of a budgeted
+    /// job's output that are not kept yet, or were left out.
+    fn written(&self) -> usize {
+        match &self.budget {
+            Some(budget) => budget.admitted(),
+            None => self.output.len(),
+        }
+    }
+
+    /// The `index`th line the job wrote, as API consumers see it, so that the tail of a
+    /// budgeted job's output can be followed as it is written, before it is kept. Lines
+    /// that were left out to stay within the budget are `None`.
+    fn written_line(&self, index: usize) -> Option<types::OutputLine> {
+        let budget = match &self.budget {
+            Some(budget) => budget,
+            None => return Some(self.line(index)),
+        };
+        match budget.position(index) {
+            capture::Position::Head(index) => Some(self.line(index)),
+            capture::Position::Dropped => None,
+            capture::Position::Tail(index) if self.status.is_some() => {
+                Some(self.line(budget.tail_start() + index))
+            }
+            capture::Position::Tail(index) => budget
+                .held(index)
+                .map(|(elapsed, line)| api_line(line, *elapsed)),
+        }
+    }
+
+    fn fail(&mut self, elapsed: Duration, message: String) {
+        self.push(elapsed, OutputLine::Stderr(message));
+        self.finish(255);
+    }
+
+    /// The whole output as plain text, one line per line, with records written as the
+    /// console shows them.
+    fn log(&self) -> String {
+        let mut log = String::new();
+        for line in &self.output {
+            log.push_str(line.text());
+            if let OutputLine::Record(record) = line {
+                for field in &record.fields {
+                    log.push_str(&format!(" {}={}", field.key, field.value));
+                }
+            }
+            log.push('\n');
+        }
+        log
+    }
+
+    /// Whether the job is running but has had no output since it was last warned about
+    /// for going without.
+    fn stalled(&self) -> bool {
+        let stalled_at = match self
+            .timeline
+            .iter()
+            .rev()
+            .find(|event| event.event == "stalled")
+        {
+            Some(event) => event.elapsed,
+            None => return false,
+        };
+        self.status.is_none()
+            && self
+                .output_elapsed
+                .last()
+                .is_none_or(|elapsed| *elapsed <= stalled_at)
+    }
+
+    /// Whether the job has started deploying, rather than waiting for its turn.
+    fn started(&self) -> bool {
+        self.timeline.iter().any(|event| event.event == "started")
+    }
+
+    fn annotations(&self) -> impl Iterator<Item = &Annotation> {
+        self.output.iter().filter_map(|line| match line {
+            OutputLine::Annotation(annotation) => Some(annotation),
+            _ => None,
+        })
+    }
+}
+
+struct Job {
+    id: Uuid,
+    app: String,
+    environment: Option<String>,
+    sha: Option<String>,
+    /// The ref, repository and pusher of the commit, when the trigger says.
+    reference: Option<String>,
+    repository: Option<String>,
+    pusher: Option<String>,
+    /// The name of the API token that triggered the job, if it was triggered by one.
+    sender: Option<String>,
+    source: TriggerSource,
+    request_id: String,
+    /// Anything suspicious about the trigger that did not prevent the deploy.
+    flags: Vec<String>,
+    /// Who is responsible for the app, from its settings.
+    owner: Option<String>,
+    contact: Option<String>,
+    /// The namespace of the app, from its settings.
+    namespace: Option<String>,
+    received_at: SystemTime,
+    /// How long the job may wait for its turn before it expires, from its app's settings.
+    max_queue_wait: Option<Duration>,
+    /// How long the job may run before it is stopped, from its app's settings.
+    timeout: Option<Duration>,
+    /// How long the job may go without output before it is warned about.
+    stall_after: Option<Duration>,
+    /// The environment variables its app's settings give the processes it runs.
+    env: Vec<(String, String)>,
+    /// Whether to leave its workspace in place if it fails, from its app's settings.
+    keep_failed_workspace: bool,
+    /// Labels from its app's settings and its trigger. Those its script sets are in its
+    /// result.
+    labels: BTreeMap<String, String>,
+    /// The workflow run artifact it deploys, when its trigger named one.
+    artifact: Option<ArtifactSource>,
+    /// Whether it was queued again after a restart, having been triggered before it.
+    requeued: bool,
+    /// Readers only ever borrow the result briefly (never across an `.await`), so they
+    /// cannot stall the runner that is writing to it.
+    result: watch::Receiver<JobResult>,
+    /// Set once an operator has seen that the job failed.
+    acknowledgement: Mutex<Option<Acknowledgement>>,
+    cancellation: Arc<Cancellation>,
+}
+
+#[derive(Clone)]
+struct Acknowledgement {
+    /// The name of the token the operator acknowledged with.
+    by: String,
+    at: SystemTime,
+}
+
+impl Job {
+    fn new(
+        target: &DeployTarget,
+        artifact: Option<ArtifactSource>,
+        request_id: String,
+        defaults: &DeployDefaults,
+    ) -> (Self, JobWriter) {
+        let (writer, result) = watch::channel(JobResult {
+            timeline: vec![TimelineEvent {
+                event: Cow::Borrowed("received"),
+                elapsed: Duration::ZERO,
+            }],
+            budget: target
+                .settings
+                .max_output(defaults)
+                .map(capture::OutputBudget::new),
+            failure_categories: target.settings.failure_categories.clone(),
+            ..JobResult::default()
+        });
+        let job = Self {
+            id: Uuid::new_v4(),
+            app: target.app.clone(),
+            environment: target.environment.clone(),
+            sha: target.sha.clone(),
+            reference: target.reference.clone(),
+            repository: target.repository.clone(),
+            pusher: target.pusher.clone(),
+            sender: target.sender.clone(),
+            source: target.source,
+            request_id,
+            flags: target.flags.clone(),
+            owner: target.settings.owner.clone(),
+            contact: target.settings.contact.clone(),
+            namespace: target.settings.namespace.clone(),
+            received_at: SystemTime::now(),
+            max_queue_wait: target.settings.max_queue_wait(defaults),
+            timeout: target.settings.timeout(defaults),
+            stall_after: target.settings.stall_after(defaults),
+            env: target.settings.env.clone().into_iter().collect(),
+            keep_failed_workspace: target.settings.keep_failed_workspace,
+            labels: target
+                .settings
+                .labels
+                .clone()
+                .into_iter()
+                .chain(target.labels.clone())
+                .collect(),
+            artifact,
+            requeued: false,
+            result,
+            acknowledgement: Mutex::default(),
+            cancellation: Arc::default(),
+        };
+        let writer = JobWriter {
+            result: writer,
+            received: Instant::now(),
+            cancellation: job.cancellation.clone(),
+            limits: defaults.capture,
+            redaction: Redaction::new(&target.settings),
+        };
+        (job, writer)
+    }
+
+    /// A finished job as it was listed, when it was replicated from another instance or
+    /// saved to the job store.
+    fn from_listing(listing: types::JobListing) -> Self {
+        let job = listing.job;
+        let mut result = JobResult {
+            status: job.status,
+            timeline: job
+                .timeline
+                .into_iter()
+                .map(|event| TimelineEvent {
+                    event: Cow::Owned(event.event),
+                    elapsed: Duration::from_millis(event.elapsed_ms),
+                })
+                .collect(),
+            usage: job.usage.map(|usage| ResourceUsage {
+                peak_rss: usage.peak_rss_bytes,
+                user_time: Duration::from_millis(usage.user_cpu_ms),
+                system_time: Duration::from_millis(usage.system_cpu_ms),
+            }),
+            invocations: job
+                .invocations
+                .into_iter()
+                .map(|invocation| Invocation {
+                    program: PathBuf::from(invocation.program),
+                    args: invocation.args,
+                    env: invocation.env,
+                })
+                .collect(),
+            category: job.category,
+            ..JobResult::default()
+        };
+        // Records keep only their message on the way, and are parsed again from that.
+        for line in listing.output {
+            let elapsed = Duration::from_millis(line.elapsed_ms);
+            let line = match line.stream.as_str() {
+                "stderr" => OutputLine::stderr(line.text),
+                _ => OutputLine::stdout(line.text),
+            };
+            result.push(elapsed, line);
+        }
+        // Nothing writes to a listed job, so the sender is dropped straight away.
+        let (_, result) = watch::channel(result);
+        Self {
+            id: job.id,
+            app: job.app,
+            environment: job.environment,
+            sha: job.sha,
+            reference: job.reference,
+            repository: job.repository,
+            pusher: job.pusher,
+            sender: job.sender,
+            source: job.source,
+            request_id: job.request_id,
+            flags: job.flags,
+            owner: job.owner,
+            contact: job.contact,
+            namespace: job.namespace,
+            received_at: UNIX_EPOCH + Duration::from_millis(job.received_at),
+            max_queue_wait: None,
+            timeout: None,
+            stall_after: None,
+            env: vec![],
+            keep_failed_workspace: false,
+            labels: job.labels,
+            artifact: job.artifact,
+            requeued: false,
+            result,
+            acknowledgement: Mutex::new(job.acknowledgement.map(|acknowledgement| {
+                Acknowledgement {
+                    by: acknowledgement.by,
+                    at: UNIX_EPOCH + Duration::from_millis(acknowledgement.at),
+                }
+            })),
+            cancellation: Arc::new(Cancellation::finished(
+                job.cancellation
+                    .map(|cancellation| {
+                        Stop::Cancelled(Cancelled {
+                            by: cancellation.by,
+                            at: UNIX_EPOCH + Duration::from_millis(cancellation.at),
+                        })
+                    })
+                    .or(job
+                        .timed_out_after_ms
+                        .map(|timeout| Stop::TimedOut(Duration::from_millis(timeout))))
+                    .or(job
+                        .expired_after_ms
+                        .map(|wait| Stop::Expired(Duration::from_millis(wait)))),
+                job.superseded_by,
+            )),
+        }
+    }
+
+    /// The job with the last `lines` lines of its output.
+    fn listing(&self, lines: usize) -> types::JobListing {
+        let job = self.summary();
+        let result = self.result.borrow();
+        let skip = result.output.len().saturating_sub(lines);
+        types::JobListing {
+            state: types::JobState::of(&job),
+            output: (skip..result.output.len())
+                .map(|index| result.line(index))
+                .collect(),
+            job,
+        }
+    }
+
+    /// Whether the job was skipped in favour of a later one, and so says nothing about
+    /// what is deployed.
+    fn superseded(&self) -> bool {
+        self.cancellation.superseded_by().is_some()
+    }
+
+    fn acknowledgement(&self) -> Option<Acknowledgement> {
+        self.acknowledgement.lock().unwrap().clone()
+    }
+
+    /// The job as it is described to hooks and API consumers.
+    fn summary(&self) -> types::Job {
+        let result = self.result.borrow();
+        types::Job {
+            id: self.id,
+            app: self.app.clone(),
+            environment: self.environment.clone(),
+            sha: self.sha.clone(),
+            reference: self.reference.clone(),
+            repository: self.repository.clone(),
+            pusher: self.pusher.clone(),
+            sender: self.sender.clone(),
+            source: self.source,
+            request_id: self.request_id.clone(),
+            flags: self.flags.clone(),
+            owner: self.owner.clone(),
+            contact: self.contact.clone(),
+            namespace: self.namespace.clone(),
+            received_at: unix_millis(self.received_at),
+            received_at_iso: iso8601(self.received_at),
+            status: result.status,
+            lines: result.output.len(),
+            timeline: result.timeline.iter().map(Into::into).collect(),
+            annotations: result.annotations().map(Into::into).collect(),
+            acknowledgement: self
+                .acknowledgement()
+                .map(|acknowledgement| types::Acknowledgement {
+                    by: acknowledgement.by,
+                    at: unix_millis(acknowledgement.at),
+                    at_iso: iso8601(acknowledgement.at),
+                }),
+            cancellation: self
+                .cancellation
+                .cancelled()
+                .map(|cancelled| types::Cancellation {
+                    by: cancelled.by,
+                    at: unix_millis(cancelled.at),
+                    at_iso: iso8601(cancelled.at),
+                }),
+            timed_out_after_ms: self
+                .cancellation
+                .timed_out()
+                .map(|timeout| timeout.as_millis() as u64),
+            expired_after_ms: self
+                .cancellation
+                .expired()
+                .map(|wait| wait.as_millis() as u64),
+            superseded_by: self.cancellation.superseded_by(),
+            category: result.category.clone(),
+            labels: self
+                .labels
+                .clone()
+                .into_iter()
+                .chain(result.labels.clone())
+                .collect(),
+            usage: result.usage.map(|usage| types::ResourceUsage {
+                peak_rss_bytes: usage.peak_rss,
+                user_cpu_ms: usage.user_time.as_millis() as u64,
+                system_cpu_ms: usage.system_time.as_millis() as u64,
+            }),
+            invocations: result
+                .invocations
+                .iter()
+                .map(|invocation| types::Invocation {
+                    program: invocation.program.to_string_lossy().into_owned(),
+                    args: invocation.args.clone(),
+                    env: invocation.env.clone(),
+                })
+                .collect(),
+            artifact: self.artifact.clone(),
+        }
+    }
+}
+
+/// The single writer of a job's result, owned by the task running the job.
+struct JobWriter {
+    result: watch::Sender<JobResult>,
+    received: Instant,
+    cancellation: Arc<Cancellation>,
+    /// How the output of the job's programs is captured.
+    limits: capture::Limits,
+    /// Which of the variables the job's programs are run with may be shown.
+    redaction: Redaction,
+}
+
+impl JobWriter {
+    fn push(&self, line: OutputLine) {
+        self.push_at(Instant::now(), line);
+    }
+
+    /// Adds a line of output that was read at `at`, which may be a little while ago if
+    /// it was buffered on its way here.
+    fn push_at(&self, at: Instant, line: OutputLine) {
+        let elapsed = at.saturating_duration_since(self.received);
+        self.result.send_modify(|result| result.push(elapsed, line));
+    }
+
+    fn event(&self, event: &'static str) {
+        let elapsed = self.received.elapsed();
+        self.result.send_modify(|result| {
+            result.timeline.push(TimelineEvent {
+                event: Cow::Borrowed(event),
+                elapsed,
+            })
+        });
+    }
+
+    fn finish(&self, status: i32) {
+        self.result.send_modify(|result| result.finish(status));
+    }
+
+    fn fail(&self, message: String) {
+        let elapsed = self.received.elapsed();
+        self.result
+            .send_modify(|result| result.fail(elapsed, message));
+    }
+
+    fn usage(&self, usage: ResourceUsage) {
+        self.result.send_modify(|result| result.usage = Some(usage));
+    }
+
+    fn invoked(&self, invocation: Invocation) {
+        self.result
+            .send_modify(|result| result.invocations.push(invocation));
+    }
+
+    fn status(&self) -> Option<i32> {
+        self.result.borrow().status
+    }
+
+    /// Fails the job if it has been cancelled or has timed out and has not finished
+    /// already, so that nothing else is started for it. Returns whether the job has
+    /// finished.
+    fn stopped(&self) -> bool {
+        if let (Some(stop), None) = (self.cancellation.stopped(), self.status()) {
+            self.fail(stop.reason());
+        }
+        self.status().is_some()
+    }
+}
+
+#[derive(Debug)]
+struct InvalidApplication;
+impl reject::Reject for InvalidApplication {}
+
+#[derive(Debug)]
+struct UnknownJob;
+impl reject::Reject for UnknownJob {}
+
+#[derive(Debug)]
+struct InvalidArtifact;
+impl reject::Reject for InvalidArtifact {}
+
+#[derive(Debug)]
+struct InvalidSettings;
+impl reject::Reject for InvalidSettings {}
+
+#[derive(Debug)]
+struct UnexpectedOrigin;
+impl reject::Reject for UnexpectedOrigin {}
+
+/// Ignored triggers are answered with the status the app's settings ask for.
+#[derive(Debug)]
+struct IgnoredRef(StatusCode);
+impl reject::Reject for IgnoredRef {}
+
+#[derive(Debug)]
+struct IgnoredEvent(StatusCode);
+impl reject::Reject for IgnoredEvent {}
+
+/// A webhook delivery the app's filter script skipped.
+#[derive(Debug)]
+struct Filtered(StatusCode);
+impl reject::Reject for Filtered {}
+
+#[derive(Debug)]
+struct DeletedRef;
+impl reject::Reject for DeletedRef {}
+
+#[derive(Debug)]
+struct AlreadyFinished;
+impl reject::Reject for AlreadyFinished {}
+
+/// Only failed jobs can be acknowledged, so that a job acknowledged while it runs still
+/// shows its failure banner if it goes on to fail.
+#[derive(Debug)]
+struct NotFailed;
+impl reject::Reject for NotFailed {}
+
+#[derive(Debug)]
+struct ReadOnly;
+impl reject::Reject for ReadOnly {}
+
+#[derive(Debug)]
+struct InvalidLabels;
+impl reject::Reject for InvalidLabels {}
+
+/// `ENOEXEC`: the kernel did not recognize the file as something it can execute.
+const ENOEXEC: i32 = 8;
+
+fn describe_spawn_error(error: &io::Error, script: &Path) -> String {
+    let hint = match error.kind() {
+        io::ErrorKind::PermissionDenied => format!(
+            "the script is not executable; try `chmod +x {}`",
+            script.display()
+        ),
+        io::ErrorKind::NotFound if !script.exists() => "the file does not exist".to_owned(),
+        io::ErrorKind::NotFound => {
+            "the interpreter named in the script's shebang line could not be found".to_owned()
+        }
+        _ if error.raw_os_error() == Some(ENOEXEC) => {
+            "the script is not a valid executable; check that it starts with a shebang line such as `#!/bin/sh`".to_owned()
+        }
+        _ => return format!("Failed to start {}: {error}", script.display()),
+    };
+    format!("Failed to start {}: {error} ({hint})", script.display())
+}
+
+async fn deploy_app(
+    job: Arc<Job>,
+    writer: JobWriter,
+    runner: Runner,
+    preflight: Option<Preflight>,
+    hooks: Arc<Hooks>,
+    integrations: Arc<Integrations>,
+    artifact: Option<ArtifactSource>,
+) {
+    if let Some(by) = job.cancellation.take_turn() {
+        writer.push(OutputLine::Stdout(format!(
+            "Skipped: superseded by job {by}, which was triggered while this one was queued"
+        )));
+        writer.finish(0);
+        return;
+    }
+    // A job that waited too long for its turn, or could not be locked in the job store,
+    // fails without starting, and is notified about like any other failure.
+    if job.cancellation.stopped_before_turn() {
+        writer.stopped();
+        writer.event("finished");
+        hooks.finish(&job).await;
+        writer.event("notified");
+        return;
+    }
+    writer.event("started");
+    // Saved as started, so that a restart fails it rather than deploying it again.
+    integrations.save(&job).await;
+    let timer = job.timeout.map(|timeout| {
+        let cancellation = job.cancellation.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            cancellation.time_out(timeout);
+        })
+    });
+    hooks.run(HookPoint::Start, &job).await;
+
+    let mut env = job.env.clone();
+    let context = [
+        ("DEPLOY_ENV", &job.environment),
+        ("DEPLOY_SHA", &job.sha),
+        ("DEPLOY_REF", &job.reference),
+        ("DEPLOY_REPOSITORY", &job.repository),
+        ("DEPLOY_PUSHER", &job.pusher),
+    ];
+    for (name, value) in context {
+        if let Some(value) = value {
+            env.push((name.to_owned(), value.clone()));
+        }
+    }
+    // Each job has a directory of its own, holding its artifact and a workspace for its
+    // scripts, so that concurrent deploys never share scratch files.
+    let directory = std::env::temp_dir()
+        .join("deploy-server")
+        .join(job.id.to_string());
+    let workspace = directory.join("workspace");
+    match std::fs::create_dir_all(&workspace) {
+        Ok(()) => env.push((
+            "DEPLOY_WORKSPACE".to_owned(),
+            workspace.to_string_lossy().into_owned(),
+        )),
+        Err(error) => writer.fail(format!(
+            "Failed to create the workspace {}: {error}",
+            workspace.display()
+        )),
+    }
+    let steps = async {
+        if let (Some(preflight), false) = (preflight, writer.stopped()) {
+            let events = ("preflight started", "preflight exited");
+            match run_process(&writer, &preflight.program, &preflight.args, &env, events).await {
+                Ok(0) => {}
+                Ok(status) => {
+                    writer.push(OutputLine::Stderr(format!(
+                        "The pre-flight check refused the deploy, exiting with {status}"
+                    )));
+                    writer.finish(status);
+                }
+                Err(error) => writer.fail(error),
+            }
+        }
+        if let (Some(artifact), false) = (artifact, writer.stopped()) {
+            match integrations
+                .github
+                .download_artifact(&artifact, &directory.join("artifacts"))
+                .await
+            {
+                Ok(path) => {
+                    writer.event("artifact downloaded");
+                    env.push((
+                        "DEPLOY_ARTIFACT".to_owned(),
+                        path.to_string_lossy().into_owned(),
+                    ));
+                }
+                Err(error) => writer.fail(error),
+            }
+        }
+
+        // Systemd units and Nomad jobs cannot be interrupted once they are started, so
+        // cancelling only stops them from being started.
+        if !writer.stopped() {
+            match runner {
+                Runner::Script(script) => run_script(&writer, script, env).await,
+                Runner::Systemd { units, timeout } => {
+                    let status = systemd::restart_units(&writer, &units, timeout).await;
+                    writer.finish(status);
+                }
+                Runner::Nomad { spec, timeout } => {
+                    let status = integrations.nomad.deploy(&writer, &spec, timeout).await;
+                    writer.finish(status);
+                }
+            }
+        }
+    };
+    match job.stall_after {
+        Some(after) => {
+            tokio::select! {
+                _ = steps => {}
+                _ = watch_for_stall(&job, &writer, &hooks, after) => {}
+            }
+        }
+        None => steps.await,
+    }
+    if let Some(timer) = timer {
+        timer.abort();
+    }
+    if job.keep_failed_workspace && writer.status() != Some(0) {
+        writer.push(OutputLine::Stderr(format!(
+            "Kept the workspace at {} for inspection",
+            workspace.display()
+        )));
+    } else if let Err(error) = std::fs::remove_dir_all(&directory) {
+        if error.kind() != io::ErrorKind::NotFound {
+            eprintln!("Failed to clean up {}: {error}", directory.display());
+        }
+    }
+
+    writer.event("finished");
+
+    hooks.finish(&job).await;
+    writer.event("notified");
+}
+
+/// Warns when the job goes `after` without output or other progress, as a hung deploy
+/// usually does long before it times out, and again after each further silence. Never
+/// resolves, so is raced against the deploy.
+async fn watch_for_stall(job: &Job, writer: &JobWriter, hooks: &Hooks, after: Duration) {
+    let mut result = job.result.clone();
+    result.borrow_and_update();
+    loop {
+        match tokio::time::timeout(after, result.changed()).await {
+            Ok(Ok(())) => continue,
+            Ok(Err(..)) => return std::future::pending().await,
+            Err(..) => {}
+        }
+        eprintln!(
+            "Job {} of {} has had no output for {}s",
+            job.id,
+            job.app,
+            after.as_secs()
+        );
+        writer.push(OutputLine::Stderr(format!(
+            "[deploy-server] No output for {}s; the deploy may be stuck",
+            after.as_secs()
+        )));
+        writer.event("stalled");
+        result.borrow_and_update();
+        hooks.run(HookPoint::Stall, job).await;
+        // Only warn again once there has been progress, then another silence.
+        if result.changed().await.is_err() {
+            return std::future::pending().await;
+        }
+    }
+}
+
+async fn run_script(writer: &JobWriter, script: PathBuf, env: Vec<(String, String)>) {
+    let events = ("script started", "script exited");
+    match run_process(writer, &script, &[], &env, events).await {
+        Ok(status) => writer.finish(status),
+        Err(error) => writer.fail(error),
+    }
+}
+
+/// How many lines of output may be waiting to be added to the job before the readers
+/// stop reading.
+const OUTPUT_BUFFER: usize = 256;
+
+/// Reads lines of output, sending each one on with the time it was read.
+async fn read_output<R: AsyncRead + Unpin>(
+    reader: R,
+    limits: capture::Limits,
+    parse: fn(String) -> OutputLine,
+    sender: mpsc::Sender<(Instant, OutputLine)>,
+) {
+    let mut lines = Box::pin(capture::lines(reader, limits));
+    while let Some(captured) = lines.next().await {
+        let line = match captured {
+            Captured::Line(line) => parse(line),
+            Captured::Note(note) => OutputLine::Stderr(note),
+        };
+        if sender.send((Instant::now(), line)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Runs a program to completion, capturing its output into the job and recording the
+/// `(started, exited)` events. Returns its exit status, or why it could not be started.
+async fn run_process(
+    writer: &JobWriter,
+    program: &Path,
+    args: &[String],
+    env: &[(String, String)],
+    (started, exited): (&'static str, &'static str),
+) -> Result<i32, String> {
+    writer.invoked(Invocation::new(program, args, env, &writer.redaction));
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .envs(env.iter().cloned())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // The program leads its own process group, so that cancelling the job also stops
+    // anything it started. On Windows, it is spawned suspended, and put in a job object
+    // before it is resumed.
+    #[cfg(unix)]
+    // SAFETY: `setpgid` is async-signal-safe, so it may be called between fork and exec.
+    unsafe {
+        command.pre_exec(|| match libc::setpgid(0, 0) {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        });
+    }
+    #[cfg(windows)]
+    command.creation_flags(cancellation::SUSPENDED);
+    let child = command.spawn();
+
+    let mut child = match child {
+        Ok(child) => {
+            writer.event(started);
+            writer.cancellation.running(Process::of(&child));
+            child
+        }
+        Err(error) => return Err(describe_spawn_error(&error, program)),
+    };
+
+    // The readers timestamp each line as they read it, and the job is updated by this
+    // task alone, in the order the lines arrive.
+    let limits = writer.limits;
+    let (sender, mut receiver) = mpsc::channel(OUTPUT_BUFFER);
+    let stdout = read_output(
+        child.stdout.take().unwrap(),
+        limits,
+        OutputLine::stdout,
+        sender.clone(),
+    );
+    let stderr = read_output(
+        child.stderr.take().unwrap(),
+        limits,
+        OutputLine::stderr,
+        sender,
+    );
+    let write = async {
+        while let Some((at, line)) = receiver.recv().await {
+            writer.push_at(at, line);
+        }
+    };
+
+    let (_, _, _, result) = join!(stdout, stderr, write, usage::wait(&mut child));
+    writer.cancellation.running(None);
+    writer.event(exited);
+    if let Some(stop) = writer.cancellation.stopped() {
+        return Err(stop.reason());
+    }
+    if let Ok((_, Some(usage))) = result {
+        writer.usage(usage);
+    }
+    let status = result
+        .map(|(status, _)| status.code())
+        .ok()
+        .flatten()
+        .unwrap_or(255);
+    Ok(status)
+}
+
+/// How an app is deployed.
+enum Runner {
+    /// Run the app's deploy script.
+    Script(PathBuf),
+    /// Restart the app's systemd units, waiting up to `timeout` for each to come up.
+    Systemd {
+        units: Vec<String>,
+        timeout: Duration,
+    },
+    /// Submit the Nomad job specification at `spec`, waiting up to `timeout` for its
+    /// deployment to become healthy.
+    Nomad { spec: PathBuf, timeout: Duration },
+}
+
+/// A check that must pass before an app is deployed, letting the app refuse deploys while
+/// it is in no state to take one.
+struct Preflight {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+/// An app, optionally in a specific environment such as staging or production, and how
+/// to deploy it.
+struct DeployTarget {
+    app: String,
+    environment: Option<String>,
+    runner: Runner,
+    preflight: Option<Preflight>,
+    settings: AppSettings,
+    /// The commit being deployed, when the trigger says.
+    sha: Option<String>,
+    /// Where the commit came from, when a webhook delivery or push says.
+    reference: Option<String>,
+    repository: Option<String>,
+    pusher: Option<String>,
+    sender: Option<String>,
+    source: TriggerSource,
+    flags: Vec<String>,
+    /// Labels the trigger gave the job.
+    labels: BTreeMap<String, String>,
+}
+
+/// Matches the remaining path as `{app}` or `{app}/{environment}`.
+fn deploy_target() -> impl Filter<Extract = ((String, Option<String>),), Error = Rejection> + Clone
+{
+    warp::path!(String)
+        .map(|app: String| (app, None))
+        .or(warp::path!(String / String)
+            .map(|app: String, environment: String| (app, Some(environment))))
+        .unify()
+}
+
+/// Where deploy scripts and app settings are: the `scripts_directory` environment
+/// variable, or else the working directory.
+fn scripts_directory() -> PathBuf {
+    std::env::var_os("scripts_directory")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::current_dir().unwrap())
+}
+
+/// Each environment of an app has its own `{app}.{environment}.deploy` script, while an
+/// app without environments is deployed by `{app}.deploy`, unless its settings name
+/// another script. Apps without a script are deployed by restarting their systemd units
+/// or submitting their Nomad job, if their settings name either.
+async fn resolve_deploy_script(
+    (app, environment): (String, Option<String>),
+    source: TriggerSource,
+) -> Result<DeployTarget, Rejection> {
+    let file_name = match &environment {
+        Some(environment) => format!("{app}.{environment}.deploy"),
+        None => format!("{app}.deploy"),
+    };
+    let directory = scripts_directory();
+    let settings =
+        AppSettings::load(&directory, &app, environment.as_deref()).map_err(|error| {
+            eprintln!("{error}");
+            reject::custom(InvalidSettings)
+        })?;
+    let script = match &settings.script {
+        Some(script) if !directory.join(script).is_file() => {
+            eprintln!("{app} has no deploy script at {}", script.display());
+            return Err(reject::custom(InvalidSettings));
+        }
+        Some(script) => directory.join(script),
+        None => directory.join(file_name),
+    };
+    let runner = if script.is_file() {
+        Runner::Script(script)
+    } else if !settings.systemd_units.is_empty() {
+        Runner::Systemd {
+            units: settings.systemd_units.clone(),
+            timeout: Duration::from_secs(settings.systemd_timeout),
+        }
+    } else if let Some(spec) = &settings.nomad_job {
+        Runner::Nomad {
+            spec: directory.join(spec),
+            timeout: Duration::from_secs(settings.nomad_timeout),
+        }
+    } else {
+        return Err(reject::custom(InvalidApplication));
+    };
+    let preflight = match (&settings.preflight_command, &runner) {
+        (Some(command), _) => Some(Preflight {
+            program: directory.join(command),
+            args: vec![],
+        }),
+        (None, Runner::Script(script)) if settings.preflight => Some(Preflight {
+            program: script.clone(),
+            args: vec!["--can-deploy".to_owned()],
+        }),
+        (None, _) if settings.preflight => {
+            eprintln!("{app} has no deploy script to check with, so needs a `preflight_command`");
+            return Err(reject::custom(InvalidSettings));
+        }
+        (None, _) => None,
+    };
+    Ok(DeployTarget {
+        app,
+        environment,
+        runner,
+        preflight,
+        settings,
+        sha: None,
+        reference: None,
+        repository: None,
+        pusher: None,
+        sender: None,
+        source,
+        flags: vec![],
+        labels: BTreeMap::new(),
+    })
+}
+
+/// Resolves the target of a /deploy2 request, checking that the app allows the sender, and
+/// is in the sender's namespace.
+async fn resolve_sender_target(
+    target: (String, Option<String>),
+    sender: String,
+    namespaces: Arc<Namespaces>,
+) -> Result<DeployTarget, Rejection> {
+    let mut target = resolve_deploy_script(target, TriggerSource::Deploy2).await?;
+    auth::authorize_sender(&target.settings.allowed_senders, &sender)?;
+    if !namespaces
+        .of_token(&sender)
+        .includes(target.settings.namespace.as_deref())
+    {
+        return Err(reject::custom(UnauthorizedSender));
+    }
+    target.sender = Some(sender);
+    Ok(target)
+}
+
+/// Records the commit and ref a /deploy2 request names, checking that its sender may
+/// deploy that ref. Deploys of a ref other than the default branch by senders that may
+/// are flagged, so they stand out in the console.
+fn resolve_requested_ref(
+    mut target: DeployTarget,
+    query: DeployQuery,
+    tokens: &Tokens,
+) -> Result<DeployTarget, Rejection> {
+    if let Some(reference) = &query.reference {
+        let default_branch = target.settings.default_branch.as_deref();
+        if !auth::is_default_branch(default_branch, reference) {
+            let sender = target.sender.as_deref().unwrap_or_default();
+            if !tokens.may_deploy_any_ref(sender) {
+                eprintln!(
+                    "Refusing to deploy {reference} of {}, which is not its default branch",
+                    target.app
+                );
+                return Err(reject::custom(UnexpectedRef));
+            }
+            target.flags.push(format!(
+                "{reference} is not the default branch, {}",
+                default_branch.unwrap_or_default()
+            ));
+        }
+    }
+    if let Some(labels) = &query.labels {
+        target.labels = parse_labels(labels).ok_or_else(|| reject::custom(InvalidLabels))?;
+    }
+    target.sha = query.sha;
+    target.reference = query.reference;
+    Ok(target)
+}
+
+/// One ref updated by a push, as a `post-receive` hook reads it from stdin.
+#[derive(serde::Deserialize)]
+struct RefUpdate {
+    old: String,
+    new: String,
+    #[serde(rename = "ref")]
+    name: String,
+}
+
+/// Deploys the new commit of a ref pushed to a repository on this host. Deleted refs have
+/// nothing to deploy.
+fn resolve_push_target(
+    mut target: DeployTarget,
+    update: RefUpdate,
+) -> Result<DeployTarget, Rejection> {
+    if update.new.bytes().all(|digit| digit == b'0') {
+        return Err(reject::custom(DeletedRef));
+    }
+    eprintln!(
+        "Push to {} updated {} from {} to {}",
+        target.app, update.name, update.old, update.new
+    );
+    target.sha = Some(update.new);
+    target.reference = Some(update.name);
+    target.source = TriggerSource::PostReceive;
+    Ok(target)
+}
+
+/// Verifies and resolves the target of a webhook delivery, keeping it to be processed
+/// again later if it is refused.
+async fn receive_delivery(
+    target: (String, Option<String>),
+    headers: HeaderMap,
+    body: Bytes,
+    webhooks: Arc<Webhooks>,
+) -> Result<DeployTarget, Rejection> {
+    // The app's settings say which provider to verify with, but an app that cannot be
+    // resolved is only reported to senders whose delivery is signed.
+    let result = match resolve_deploy_script(target.clone(), TriggerSource::Webhook).await {
+        Ok(deploy) => {
+            let provider = deploy
+                .settings
+                .webhook_provider
+                .clone()
+                .unwrap_or_else(|| webhooks.provider().clone());
+            let secrets = &deploy.settings.webhook_secret;
+            if !webhooks.verify(provider.as_ref(), secrets, &headers, &body) {
+                Err(reject::custom(InvalidSignature))
+            } else if let Some(event) = provider
+                .event(&headers)
+                .filter(|event| !deploy.settings.deploys_event(provider.as_ref(), event))
+            {
+                eprintln!(
+                    "Ignoring {} {event} delivery for {}",
+                    provider.name(),
+                    deploy.app
+                );
+                // Pings only show that deliveries arrive, so there is nothing to keep.
+                if provider.is_ping(event) {
+                    return Err(reject::custom(IgnoredEvent(StatusCode::OK)));
+                }
+                let status = deploy.settings.ignored_status();
+                Err(reject::custom(IgnoredEvent(status)))
+            } else {
+                let event = provider.event(&headers);
+                let encoding = headers
+                    .get(warp::http::header::CONTENT_ENCODING)
+                    .and_then(|encoding| encoding.to_str().ok());
+                match payload::decode(encoding, &body) {
+                    Ok(decoded) => {
+                        filter_delivery(deploy, provider.as_ref(), event, &decoded).await
+                    }
+                    Err(rejection) => Err(rejection),
+                }
+            }
+        }
+        Err(rejection) if webhooks.verify(webhooks.provider().as_ref(), &[], &headers, &body) => {
+            Err(rejection)
+        }
+        Err(..) => Err(reject::custom(InvalidSignature)),
+    };
+    if let Err(rejection) = &result {
+        let reason = if rejection.find::<InvalidSignature>().is_some() {
+            "invalid signature"
+        } else if rejection.find::<UnexpectedOrigin>().is_some() {
+            "unexpected origin"
+        } else if rejection.find::<IgnoredRef>().is_some() {
+            "filtered ref"
+        } else if rejection.find::<IgnoredEvent>().is_some() {
+            "ignored event"
+        } else if rejection.find::<Filtered>().is_some() {
+            "filtered by script"
+        } else if rejection.find::<InvalidApplication>().is_some() {
+            "unknown application"
+        } else if rejection.find::<UnsupportedEncoding>().is_some()
+            || rejection.find::<UndecodableBody>().is_some()
+        {
+            "undecodable body"
+        } else {
+            "invalid app settings"
+        };
+        webhooks
+            .refuse(Delivery {
+                id: Uuid::new_v4(),
+                received_at: SystemTime::now(),
+                target,
+                headers,
+                body,
+                reason,
+            })
+            .await;
+    }
+    result
+}
+
+/// Processes a refused delivery again, as though it had just been received, so it is
+/// verified against the app's settings as they are now.
+async fn redrive_delivery(id: Uuid, webhooks: Arc<Webhooks>) -> Result<DeployTarget, Rejection> {
+    let delivery = webhooks.take(id).await.ok_or_else(reject::not_found)?;
+    let mut target =
+        receive_delivery(delivery.target, delivery.headers, delivery.body, webhooks).await?;
+    target.source = TriggerSource::Redrive;
+    Ok(target)
+}
+
+fn with_webhooks(
+    webhooks: Arc<Webhooks>,
+) -> impl Filter<Extract = (Arc<Webhooks>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || webhooks.clone())
+}
+
+/// Resolves the target of a webhook delivery, refusing or flagging deliveries that came
+/// from somewhere the app does not expect.
+fn resolve_webhook_target(
+    mut target: DeployTarget,
+    payload: Payload,
+) -> Result<DeployTarget, Rejection> {
+    target.sha = payload.sha().map(str::to_owned);
+    target.reference = payload.reference().map(str::to_owned);
+    target.repository = payload.repository().map(str::to_owned);
+    target.pusher = payload.pusher().map(str::to_owned);
+    let problems = target.settings.check_origin(&payload);
+    if !problems.is_empty() {
+        eprintln!(
+            "Unexpected webhook delivery for {}: {}",
+            target.app,
+            problems.join("; ")
+        );
+        match target.settings.unexpected_repository {
+            UnexpectedRepository::Refuse => return Err(reject::custom(UnexpectedOrigin)),
+            UnexpectedRepository::Flag => target.flags.extend(problems),
+        }
+    }
+    if !target.settings.allows_ref(payload.reference()) {
+        eprintln!(
+            "Ignoring webhook delivery for {} of {}",
+            target.app,
+            payload.reference().unwrap_or("an unnamed ref")
+        );
+        return Err(reject::custom(IgnoredRef(target.settings.ignored_status())));
+    }
+    Ok(target)
+}
+
+/// Resolves the target of a decoded webhook delivery, then runs the app's filter script
+/// on it, if it has one. The script may skip the delivery, set environment variables for
+/// the deploy, or deploy another app instead, which must be in the same namespace and
+/// expect the delivery's event, origin and ref too. That app's own filter script is not
+/// run.
+async fn filter_delivery(
+    target: DeployTarget,
+    provider: &dyn WebhookProvider,
+    event: Option<&str>,
+    body: &[u8],
+) -> Result<DeployTarget, Rejection> {
+    let target = resolve_webhook_target(target, provider.payload(body))?;
+    let script = match &target.settings.filter {
+        Some(script) => scripts_directory().join(script),
+        None => return Ok(target),
+    };
+    let delivery = filter::Delivery {
+        app: target.app.clone(),
+        environment: target.environment.clone(),
+        event: event.map(str::to_owned),
+        payload: body.to_vec(),
+    };
+    let decision = filter::run(script, delivery).await.map_err(|error| {
+        eprintln!("The filter script of {} failed: {error}", target.app);
+        reject::custom(InvalidSettings)
+    })?;
+    let (app, env) = match decision {
+        Decision::Skip => {
+            eprintln!("The filter script of {} skipped a delivery", target.app);
+            return Err(reject::custom(Filtered(target.settings.ignored_status())));
+        }
+        Decision::Deploy { app, env } => (app, env),
+    };
+    let mut target = match app {
+        Some(app) if app != target.app => {
+            eprintln!(
+                "The filter script of {} sent a delivery to {app}",
+                target.app
+            );
+            let place = (app, target.environment.clone());
+            let routed = resolve_deploy_script(place, TriggerSource::Webhook).await?;
+            if routed.settings.namespace != target.settings.namespace {
+                eprintln!("{} is in another namespace than {}", routed.app, target.app);
+                return Err(reject::custom(InvalidSettings));
+            }
+            if event.is_some_and(|event| !routed.settings.deploys_event(provider, event)) {
+                return Err(reject::custom(IgnoredEvent(
+                    routed.settings.ignored_status(),
+                )));
+            }
+            resolve_webhook_target(routed, provider.payload(body))?
+        }
+        _ => target,
+    };
+    target.settings.env.extend(env);
+    Ok(target)
+}
+
+/// Whether the commit being deployed is the one the most recent successful deploy of the
+/// same app and environment deployed.
+async fn is_deployed(jobs: &Jobs, target: &DeployTarget) -> bool {
+    let sha = match &target.sha {
+        Some(sha) => sha,
+        None => return false,
+    };
+    jobs.list
+        .read()
+        .await
+        .iter()
+        .rev()
+        .filter(|job| job.app == target.app && job.environment == target.environment)
+        .filter(|job| !job.superseded())
+        .find(|job| job.result.borrow().status == Some(0))
+        .is_some_and(|job| job.sha.as_ref() == Some(sha))
+}
+
+async fn trigger_deploy(
+    target: DeployTarget,
+    artifact: Option<ArtifactSource>,
+    request_id: String,
+    jobs: Jobs,
+    hooks: Arc<Hooks>,
+    integrations: Arc<Integrations>,
+    responses: Arc<TriggerResponses>,
+) -> warp::reply::Response {
+    let (job, writer) = Job::new(
+        &target,
+        artifact,
+        request_id.clone(),
+        &integrations.defaults,
+    );
+    let job = Arc::new(job);
+
+    if target.settings.skip_deployed_sha && is_deployed(&jobs, &target).await {
+        eprintln!(
+            "[{request_id}] Skipping {}, which is already deployed, as job {}",
+            target.app, job.id
+        );
+        writer.push(OutputLine::Stdout(format!(
+            "Skipped: {} is already deployed",
+            target.sha.as_deref().unwrap_or_default()
+        )));
+        writer.finish(0);
+        jobs.list.write().await.push(job.clone());
+        jobs.inserted();
+        integrations.save(&job).await;
+        return responses.reply(job.id, "skipped", 0);
+    }
+
+    eprintln!("[{request_id}] Deploying {} as job {}", target.app, job.id);
+    let job_id = job.id;
+    let queue_position = enqueue(
+        job,
+        writer,
+        target,
+        jobs,
+        hooks,
+        integrations,
+        responses.clone(),
+    )
+    .await;
+    let status = if queue_position == 0 {
+        "started"
+    } else {
+        "queued"
+    };
+    responses.reply(job_id, status, queue_position)
+}
+
+/// A deploy a route accepted, which is triggered once the request has its ID.
+struct Trigger {
+    target: DeployTarget,
+    artifact: Option<ArtifactSource>,
+    /// Whether to send the browser back to the console, for forms on it, rather than
+    /// answering with the job.
+    to_console: bool,
+}
+
+impl Trigger {
+    fn of(target: DeployTarget, artifact: Option<ArtifactSource>) -> Self {
+        Self {
+            target,
+            artifact,
+            to_console: false,
+        }
+    }
+
+    fn for_console(self) -> Self {
+        Self {
+            to_console: true,
+            ..self
+        }
+    }
+}
+
+/// What a route answered a request with, or the deploy it accepted.
+enum Routed {
+    Reply(warp::reply::Response),
+    Trigger(Box<Trigger>),
+}
+
+impl Routed {
+    fn replied(reply: impl Reply) -> Self {
+        Routed::Reply(reply.into_response())
+    }
+}
+
+/// Answers a request as its route did, triggering the deploy it accepted, if any, under
+/// the request's ID.
+async fn respond(
+    requested: Requested,
+    routed: Routed,
+    jobs: Jobs,
+    hooks: Arc<Hooks>,
+    integrations: Arc<Integrations>,
+    responses: Arc<TriggerResponses>,
+) -> (Requested, warp::reply::Response) {
+    let response = match routed {
+        Routed::Reply(response) => response,
+        Routed::Trigger(trigger) => {
+            let Trigger {
+                target,
+                artifact,
+                to_console,
+            } = *trigger;
+            let request_id = requested.id().to_owned();
+            let response = trigger_deploy(
+                target,
+                artifact,
+                request_id,
+                jobs,
+                hooks,
+                integrations,
+                responses,
+            )
+            .await;
+            if to_console {
+                warp::redirect::see_other(warp::http::Uri::from_static("/")).into_response()
+            } else {
+                response
+            }
+        }
+    };
+    (requested, response)
+}
+
+/// Queues a job behind the unfinished jobs of the same app and environment, saving it so
+/// that it is deployed even if the server restarts before its turn, and deploys it once
+/// its turn comes. Returns how many jobs are ahead of it.
+async fn enqueue(
+    job: Arc<Job>,
+    writer: JobWriter,
+    target: DeployTarget,
+    jobs: Jobs,
+    hooks: Arc<Hooks>,
+    integrations: Arc<Integrations>,
+    responses: Arc<TriggerResponses>,
+) -> usize {
+    let request_id = &job.request_id;
+    let queue_position = {
+        let mut jobs = jobs.list.write().await;
+        // A requeued job replaces the copy of it that was listed from the job store.
+        jobs.retain(|other| other.id != job.id);
+        let unfinished = jobs
+            .iter()
+            .filter(|other| other.app == job.app && other.environment == job.environment)
+            .filter(|other| other.result.borrow().status.is_none());
+        let mut ahead = 0;
+        for other in unfinished {
+            if target.settings.coalesce && other.cancellation.supersede(job.id) {
+                eprintln!(
+                    "[{request_id}] Job {} of {} is superseded by job {}",
+                    other.id, job.app, job.id
+                );
+            } else if !other.superseded() {
+                ahead += 1;
+            }
+        }
+        jobs.push(job.clone());
+        ahead
+    };
+    jobs.inserted();
+    integrations.save(&job).await;
+    let in_flight = InFlight::new(integrations.clone());
+    let artifact = job.artifact.clone();
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        // A requeued job ran its trigger hook when it was first triggered.
+        if !job.requeued {
+            hooks.run(HookPoint::Trigger, &job).await;
+        }
+        let delay = jitter(target.settings.start_jitter);
+        if !delay.is_zero() {
+            writer.push(OutputLine::Stdout(format!(
+                "Waiting {:.1}s before deploying, to stagger hosts",
+                delay.as_secs_f64()
+            )));
+            tokio::time::sleep(delay).await;
+            writer.event("jitter elapsed");
+        }
+        let deployment = match &target.settings.github_environment {
+            Some(environment) => {
+                let run = artifact.as_ref();
+                approve_deployment(&job, &writer, environment, run, &integrations).await
+            }
+            None => None,
+        };
+        let deploy = deploy_app(
+            job.clone(),
+            writer,
+            target.runner,
+            target.preflight,
+            hooks,
+            integrations.clone(),
+            artifact,
+        );
+        integrations.exclusively(&job, deploy).await;
+        integrations.save(&job).await;
+        if let Some(deployment) = deployment {
+            let state = match job.result.borrow().status {
+                _ if job.superseded() => "inactive",
+                Some(0) => "success",
+                _ => "failure",
+            };
+            deployment
+                .report(&integrations.github, state, &responses.job_url(job.id))
+                .await;
+        }
+        if let Some(issue) = &target.settings.failure_issue {
+            track_failures(&jobs, &job, issue, &integrations, &responses).await;
+        }
+    });
+
+    queue_position
+}
+
+/// How often a job waiting for its GitHub environment to approve it checks again.
+const APPROVAL_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A deployment of a job recorded on GitHub, to report how the job went to.
+struct GitHubDeployment {
+    repository: String,
+    id: u64,
+}
+
+impl GitHubDeployment {
+    async fn report(&self, github: &GitHub, state: &str, log_url: &str) {
+        if let Err(error) = github
+            .set_deployment_status(&self.repository, self.id, state, log_url)
+            .await
+        {
+            eprintln!(
+                "Failed to set deployment {} of {} to {state}: {error}",
+                self.id, self.repository
+            );
+        }
+    }
+}
+
+/// Waits for the GitHub environment the job deploys to to approve it, failing the job if
+/// it is rejected or cannot be asked, then records it as a deployment to the environment.
+/// The job stays queued while it waits.
+async fn approve_deployment(
+    job: &Job,
+    writer: &JobWriter,
+    environment: &str,
+    run: Option<&ArtifactSource>,
+    integrations: &Integrations,
+) -> Option<GitHubDeployment> {
+    let github = &integrations.github;
+    let repository = match run.map(|run| &run.repository).or(job.repository.as_ref()) {
+        Some(repository) => repository,
+        None => {
+            writer.fail(format!(
+                "Deploys to the {environment} environment must say which repository they are from"
+            ));
+            return None;
+        }
+    };
+    let reference = match job.sha.as_ref().or(job.reference.as_ref()) {
+        Some(reference) => reference,
+        None => {
+            writer.fail(format!(
+                "Deploys to the {environment} environment must say which commit they deploy"
+            ));
+            return None;
+        }
+    };
+    let run_id = run.map(|run| run.run_id);
+    if let Err(error) =
+        wait_for_approval(writer, github, job, repository, run_id, environment).await
+    {
+        writer.fail(error);
+        return None;
+    }
+    if writer.stopped() {
+        return None;
+    }
+    let description = format!("Job {}", job.id);
+    let deployment = match github
+        .create_deployment(repository, reference, environment, &description)
+        .await
+    {
+        Ok(id) => GitHubDeployment {
+            repository: repository.clone(),
+            id,
+        },
+        Err(error) => {
+            eprintln!(
+                "Failed to record job {} as a GitHub deployment: {error}",
+                job.id
+            );
+            return None;
+        }
+    };
+    Some(deployment)
+}
+
+/// Waits until the workflow run's job in the environment is approved, or returns why it
+/// will not be. Unprotected environments need no approval, and so no run. The run must be
+/// of the commit and repository the job deploys, so that the approval of one commit
+/// cannot be used to deploy another.
+async fn wait_for_approval(
+    writer: &JobWriter,
+    github: &GitHub,
+    job: &Job,
+    repository: &str,
+    run_id: Option<u64>,
+    environment: &str,
+) -> Result<(), String> {
+    let run_id = match run_id {
+        Some(run_id) => run_id,
+        None if github.protection(repository, environment).await?.is_protected() => {
+            return Err(format!(
+                "The {environment} environment needs approval, which only a workflow run named with `run_id` can ask for"
+            ))
+        }
+        None => return Ok(()),
+    };
+    let run = github.workflow_run(repository, run_id).await?;
+    if job.sha.as_deref() != Some(run.head_sha.as_str()) {
+        return Err(format!(
+            "Run {run_id} is of commit {}, not the commit being deployed",
+            run.head_sha
+        ));
+    }
+    if job
+        .repository
+        .as_ref()
+        .is_some_and(|repository| !repository.eq_ignore_ascii_case(&run.repository.full_name))
+    {
+        return Err(format!(
+            "Run {run_id} is of {}, not the repository being deployed",
+            run.repository.full_name
+        ));
+    }
+    let mut waited = false;
+    loop {
+        match github.approval(repository, run_id, environment).await? {
+            Approval::Approved => break,
+            Approval::Rejected => {
+                return Err(format!(
+                    "The deploy to {environment} was rejected on GitHub"
+                ))
+            }
+            // Once a wait timer passes, there is nothing left to show that it was waited for,
+            // but a run that was cancelled, or whose review expired, stops waiting too.
+            Approval::Unrequested if waited => {
+                let protection = github.protection(repository, environment).await?;
+                let run = github.workflow_run(repository, run_id).await?;
+                let cancelled =
+                    run.status == "completed" && run.conclusion.as_deref() != Some("success");
+                if protection.wait_timer && !protection.required_reviewers && !cancelled {
+                    break;
+                }
+                return Err(format!(
+                    "Run {run_id} stopped waiting for the {environment} environment without being approved"
+                ));
+            }
+            Approval::Unrequested
+                if github
+                    .protection(repository, environment)
+                    .await?
+                    .is_protected() =>
+            {
+                return Err(format!(
+                    "Run {run_id} has no job in the {environment} environment for GitHub to approve"
+                ))
+            }
+            Approval::Unrequested => return Ok(()),
+            Approval::Pending => {}
+        }
+        if !waited {
+            writer.push(OutputLine::Stdout(format!(
+                "Waiting for the {environment} environment on GitHub to approve run {run_id}"
+            )));
+            waited = true;
+        }
+        tokio::time::sleep(APPROVAL_POLL_INTERVAL).await;
+        if writer.stopped() {
+            return Ok(());
+        }
+    }
+    if waited {
+        writer.event("approved");
+    }
+    Ok(())
+}
+
+/// A random delay of less than `seconds`.
+fn jitter(seconds: u64) -> Duration {
+    match seconds.saturating_mul(1000) {
+        0 => Duration::ZERO,
+        millis => Duration::from_millis((Uuid::new_v4().as_u128() % millis as u128) as u64),
+    }
+}
+
+/// How many lines of output to quote in a failure issue.
+const ISSUE_LOG_TAIL: usize = 30;
+
+/// Opens or updates the app's failure issue once enough consecutive deploys of it have
+/// failed, and closes it once one succeeds.
+async fn track_failures(
+    jobs: &Jobs,
+    job: &Job,
+    issue: &FailureIssue,
+    integrations: &Integrations,
+    responses: &TriggerResponses,
+) {
+    if job.superseded() {
+        return;
+    }
+    let (failed, category) = {
+        let result = job.result.borrow();
+        (result.status != Some(0), result.category.clone())
+    };
+    if failed
+        && !issue.categories.is_empty()
+        && !matches!(&category, Some(category) if issue.categories.contains(category))
+    {
+        return;
+    }
+    let failures = jobs
+        .list
+        .read()
+        .await
+        .iter()
+        .rev()
+        .filter(|other| other.app == job.app && other.environment == job.environment)
+        .filter(|other| !other.superseded())
+        .map(|other| other.result.borrow().status)
+        .filter(Option::is_some)
+        .take_while(|status| *status != Some(0))
+        .count();
+
+    let title = match &job.environment {
+        Some(environment) => format!("Deploys of {} to {environment} are failing", job.app),
+        None => format!("Deploys of {} are failing", job.app),
+    };
+    let url = responses.job_url(job.id);
+    let result = if failures == 0 {
+        let body = format!("Job [{}]({url}) deployed successfully.", job.id);
+        integrations
+            .github
+            .resolve_failure(&issue.repository, &title, &body)
+            .await
+    } else if failures >= issue.after_failures {
+        let (status, tail) = {
+            let result = job.result.borrow();
+            let skip = result.output.len().saturating_sub(ISSUE_LOG_TAIL);
+            let tail = result.output[skip..]
+                .iter()
+                .map(OutputLine::text)
+                .collect::<Vec<_>>()
+                .join("\n");
+            (result.status.unwrap_or_default(), tail)
+        };
+        let mut body = format!(
+            "Job [{}]({url}), triggered at {}, exited with {status}, {failures} failures in a row.\n\n```\n{tail}\n```",
+            job.id,
+            responses.time(job.received_at),
+        );
+        match (&job.owner, &job.contact) {
+            (Some(owner), Some(contact)) => body += &format!("\n\nOwner: {owner} ({contact})"),
+            (Some(owner), None) => body += &format!("\n\nOwner: {owner}"),
+            (None, Some(contact)) => body += &format!("\n\nContact: {contact}"),
+            (None, None) => {}
+        }
+        integrations
+            .github
+            .report_failure(&issue.repository, &title, &body)
+            .await
+    } else {
+        Ok(())
+    };
+    if let Err(error) = result {
+        eprintln!(
+            "[{}] Failed to update the failure issue for {} in {}: {error}",
+            job.request_id, job.app, issue.repository
+        );
+    }
+}
+
+type Jobs = Arc<JobList>;
+
+/// Every job, in the order they were received.
+struct JobList {
+    list: RwLock<Vec<Arc<Job>>>,
+    /// Counts the jobs added to or replaced in the list, so the console can follow it
+    /// without polling.
+    inserted: watch::Sender<usize>,
+}
+
+impl Default for JobList {
+    fn default() -> Self {
+        Self {
+            list: RwLock::default(),
+            inserted: watch::channel(0).0,
+        }
+    }
+}
+
+impl JobList {
+    fn inserted(&self) {
+        self.inserted.send_modify(|inserted| *inserted += 1);
+    }
+}
+
+/// How many lines of each job's output /api/jobs includes.
+const LISTED_OUTPUT_LINES: usize = 20;
+
+/// Replicated jobs carry all of their output, so are allowed to be large.
+const MAX_REPLICATED_JOB: u64 = 64 * 1024 * 1024;
+
+fn with_jobs(
+    jobs: Jobs,
+) -> impl Filter<Extract = (Jobs,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || jobs.clone())
+}
+
+fn with_namespaces(
+    namespaces: Arc<Namespaces>,
+) -> impl Filter<Extract = (Arc<Namespaces>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || namespaces.clone())
+}
+
+/// Clients for the external services that jobs talk to.
+struct Integrations {
+    github: GitHub,
+    nomad: Nomad,
+    replica: Option<Replica>,
+    store: Option<Box<dyn JobStore>>,
+    /// Held while an app and environment is being deployed by this instance, which
+    /// queues the triggers that follow in the order they arrive.
+    deploying: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// Limits how many deploys run at once, from `max_concurrent_deploys`. Deploys wait
+    /// their turn for a permit once nothing else of their app is deploying, and apps take
+    /// turns.
+    permits: Option<Permits>,
+    /// How many triggered deploys have yet to finish and be saved, so that shutting down
+    /// can wait for them.
+    in_flight: watch::Sender<usize>,
+    /// Limits of deploys whose apps do not set their own.
+    defaults: DeployDefaults,
+}
+
+impl Integrations {
+    /// Saves the job to the job store and queues it to be pushed to the standby,
+    /// whichever there are.
+    async fn save(&self, job: &Job) {
+        let listing = job.listing(usize::MAX);
+        if let Some(store) = &self.store {
+            if let Err(error) = store.save(&listing).await {
+                eprintln!("Failed to save job {}: {error}", job.id);
+            }
+        }
+        if let Some(replica) = &self.replica {
+            replica.push(listing);
+        }
+    }
+
+    /// Runs a deploy once no other deploy of the same app and environment is running and
+    /// there is room for another deploy, holding the app's lock in the job store too, if
+    /// there is one, so that instances sharing the store never deploy it at once either.
+    async fn exclusively<F: Future<Output = ()>>(&self, job: &Job, deploy: F) {
+        let key = lock_key(job);
+        let lock = self
+            .deploying
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        // The wait is timed from when the job joins the queue, after any delay to stagger
+        // hosts or approval, and covers every lock and permit it waits for. Expiring
+        // leaves the deploy to fail straight away, so it needs none of them.
+        let deadline = job
+            .max_queue_wait
+            .map(|wait| (wait, tokio::time::Instant::now() + wait));
+        let expire = || {
+            if let Some((wait, _)) = deadline {
+                job.cancellation.expire(wait);
+            }
+        };
+        let deadline = deadline.map(|(_, deadline)| deadline);
+
+        let _deploying = match before(deadline, lock.lock()).await {
+            Some(deploying) => deploying,
+            None => {
+                expire();
+                return deploy.await;
+            }
+        };
+        // The store's lock is taken before a permit, so that a deploy waiting for another
+        // instance holds up no other app. Deploying without it could mean deploying
+        // alongside another instance, so the job is failed if it cannot be taken, and
+        // stopped if it is lost.
+        let lost = match &self.store {
+            Some(store) => match before(deadline, store.lock(&key)).await {
+                Some(Ok(lost)) => Some(lost),
+                Some(Err(error)) => {
+                    eprintln!(
+                        "Failed to lock {key}, so job {} is not deployed: {error}",
+                        job.id
+                    );
+                    job.cancellation.fail_to_lock(error);
+                    return deploy.await;
+                }
+                None => {
+                    // The lock may have been taken just as the wait ran out.
+                    self.unlock(&key).await;
+                    expire();
+                    return deploy.await;
+                }
+            },
+            None => None,
+        };
+        let _permit = match &self.permits {
+            Some(permits) => match before(deadline, permits.acquire(&job.app)).await {
+                Some(permit) => Some(permit),
+                None => {
+                    self.unlock(&key).await;
+                    expire();
+                    return deploy.await;
+                }
+            },
+            None => None,
+        };
+
+        let lost = match lost {
+            Some(lost) => lost,
+            None => return deploy.await,
+        };
+        tokio::pin!(deploy);
+        tokio::select! {
+            _ = &mut deploy => {}
+            _ = lost => {
+                eprintln!("Lost the lock on {key}, stopping job {}", job.id);
+                job.cancellation.lose_lock();
+                deploy.await;
+            }
+        }
+        self.unlock(&key).await;
+    }
+
+    /// Releases the app's lock in the job store, if there is one.
+    async fn unlock(&self, key: &str) {
+        if let Some(store) = &self.store {
+            if let Err(error) = store.unlock(key).await {
+                eprintln!("Failed to unlock {key}: {error}");
+            }
+        }
+    }
+}
+
+/// Waits for `future`, unless the deadline passes first, in which case this is `None`.
+async fn before<F: Future>(deadline: Option<tokio::time::Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Counts a deploy as in flight from when it is made until it is dropped.
+struct InFlight(Arc<Integrations>);
+
+impl InFlight {
+    fn new(integrations: Arc<Integrations>) -> Self {
+        integrations.in_flight.send_modify(|count| *count += 1);
+        Self(integrations)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.send_modify(|count| *count -= 1);
+    }
+}
+
+/// What deploys of the job's app and environment are locked by.
+fn lock_key(job: &Job) -> String {
+    format!(
+        "{}.{}",
+        job.app,
+        job.environment.as_deref().unwrap_or_default()
+    )
+}
+
+/// Adds a finished job to the list in order of when it was received, replacing any copy
+/// of it that is already there, unless this instance is still running that job.
+async fn insert_job(jobs: &Jobs, job: Job) {
+    let job = Arc::new(job);
+    let mut list = jobs.list.write().await;
+    match list.iter_mut().find(|existing| existing.id == job.id) {
+        // Its writer is still alive, so a copy would be cut off from its output and from
+        // the process that cancelling it has to kill.
+        Some(existing) if existing.result.has_changed().is_ok() => return,
+        Some(existing) => *existing = job,
+        None => {
+            list.push(job);
+            list.sort_by_key(|job| job.received_at);
+        }
+    }
+    drop(list);
+    jobs.inserted();
+}
+
+/// Passes requests whose `Accept` header asks for JSON rather than HTML.
+fn accepts_json() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept")
+        .and_then(|accept: Option<String>| {
+            let accept = accept.unwrap_or_default();
+            ready(
+                if accept.contains("application/json") && !accept.contains("text/html") {
+                    Ok(())
+                } else {
+                    Err(reject::not_found())
+                },
+            )
+        })
+        .untuple_one()
+}
+
+/// The job with this ID, if it is in the scope.
+async fn find_job(jobs: &Jobs, scope: &Scope, id: Uuid) -> Option<Arc<Job>> {
+    jobs.list
+        .read()
+        .await
+        .iter()
+        .find(|job| job.id == id && scope.includes(job.namespace.as_deref()))
+        .cloned()
+}
+
+/// The latest job of each app in each environment in the scope, leaving out superseded
+/// jobs.
+async fn app_statuses(jobs: &Jobs, scope: &Scope) -> Vec<types::AppStatus> {
+    let mut latest = BTreeMap::new();
+    for job in jobs
+        .list
+        .read()
+        .await
+        .iter()
+        .filter(|job| !job.superseded() && scope.includes(job.namespace.as_deref()))
+    {
+        latest.insert((job.app.clone(), job.environment.clone()), job.summary());
+    }
+    latest
+        .into_iter()
+        .map(|((app, environment), job)| types::AppStatus {
+            app,
+            environment,
+            state: types::JobState::of(&job),
+            job,
+        })
+        .collect()
+}
+
+/// Authenticates a request to read the console or job APIs, passing it on with the scope
+/// of whoever it is from.
+fn viewer(
+    auth: Option<Arc<dyn ConsoleAuth>>,
+    namespaces: Arc<Namespaces>,
+) -> impl Filter<Extract = (Scope,), Error = Rejection> + Clone {
+    console::authenticate(auth).map(move |user: Option<String>| namespaces.of_user(user.as_deref()))
+}
+
+/// Refuses requests that would trigger or change jobs, when the server is a read-only
+/// mirror.
+fn writable(read_only: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            ready(if read_only {
+                Err(reject::custom(ReadOnly))
+            } else {
+                Ok(())
+            })
+        })
+        .untuple_one()
+}
+
+fn with_integrations(
+    integrations: Arc<Integrations>,
+) -> impl Filter<Extract = (Arc<Integrations>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || integrations.clone())
+}
+
+/// Callers of /deploy2 may say which commit they are deploying, from which ref, and how
+/// to label the job, as comma separated `name=value` pairs.
+#[derive(serde::Deserialize)]
+struct DeployQuery {
+    sha: Option<String>,
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    labels: Option<String>,
+}
+
+/// Parses comma separated `name=value` labels, none of which may have an empty name.
+fn parse_labels(list: &str) -> Option<BTreeMap<String, String>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(|label| match label.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Some((name.to_owned(), value.to_owned())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// /api/jobs may be filtered to the jobs with a label, given as `name=value`, or as just
+/// `name` for jobs with the label whatever its value.
+#[derive(serde::Deserialize)]
+struct JobsQuery {
+    label: Option<String>,
+}
+
+impl JobsQuery {
+    fn matches(&self, job: &types::Job) -> bool {
+        let label = match &self.label {
+            Some(label) => label,
+            None => return true,
+        };
+        match label.split_once('=') {
+            Some((name, value)) => job.labels.get(name).is_some_and(|label| label == value),
+            None => job.labels.contains_key(label),
+        }
+    }
+}
+
+/// Artifacts are optional, but a request that names a workflow run must say which
+/// repository and artifact to fetch from it.
+fn artifact_source() -> impl Filter<Extract = (Option<ArtifactSource>,), Error = Rejection> + Clone
+{
+    #[derive(serde::Deserialize)]
+    struct ArtifactQuery {
+        repository: Option<String>,
+        run_id: Option<u64>,
+        artifact: Option<String>,
+    }
+
+    warp::query::<ArtifactQuery>().and_then(|query: ArtifactQuery| async move {
+        match query {
+            ArtifactQuery {
+                repository: Some(repository),
+                run_id: Some(run_id),
+                artifact: Some(artifact),
+            } => Ok(Some(ArtifactSource {
+                repository,
+                run_id,
+                artifact,
+            })),
+            ArtifactQuery { run_id: None, .. } => Ok(None),
+            _ => Err(reject::custom(InvalidArtifact)),
+        }
+    })
+}
+
+fn with_responses(
+    responses: Arc<TriggerResponses>,
+) -> impl Filter<Extract = (Arc<TriggerResponses>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || responses.clone())
+}
+
+fn with_hooks(
+    hooks: Arc<Hooks>,
+) -> impl Filter<Extract = (Arc<Hooks>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || hooks.clone())
+}
+
+fn with_locales(
+    locales: Arc<Locales>,
+) -> impl Filter<Extract = (Arc<Locales>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || locales.clone())
+}
+
+struct TemplateJob {
+    id: Uuid,
+    app: String,
+    environment: Option<String>,
+    /// When the trigger was received, in the display timezone.
+    received_at: String,
+    summary: String,
+    succeeded: bool,
+    /// Whether the job failed without anyone acknowledging it.
+    unacknowledged_failure: bool,
+    acknowledged_by: Option<String>,
+    cancelled_by: Option<String>,
+    /// What kind of failure it was, going by its app's categories.
+    category: Option<String>,
+    /// Whether the job is still running, and can be cancelled.
+    running: bool,
+    /// Whether the running job has gone quiet for long enough to be warned about.
+    stalled: bool,
+    owner: Option<String>,
+    contact: Option<String>,
+    flags: Vec<String>,
+    annotations: Vec<Annotation>,
+    levels: Vec<String>,
+    timeline: Vec<TimelineEvent>,
+    usage: Option<String>,
+    invocations: Vec<Invocation>,
+    /// The end of the output, after `omitted` earlier lines.
+    output: Vec<OutputLine>,
+    omitted: usize,
+}
+
+/// How many lines of each job's output the console shows before the full log is loaded.
+const PREVIEW_LINES: usize = 200;
+
+impl TemplateJob {
+    fn from(job: &Job, messages: &Messages, timezone: DisplayTimezone) -> Self {
+        let acknowledgement = job.acknowledgement();
+        let result = job.result.borrow();
+        let omitted = result.output.len().saturating_sub(PREVIEW_LINES);
+        let mut levels = vec![];
+        for line in &result.output {
+            if let OutputLine::Record(LogRecord {
+                level: Some(level), ..
+            }) = line
+            {
+                if !levels.contains(level) {
+                    levels.push(level.clone());
+                }
+            }
+        }
+        TemplateJob {
+            id: job.id,
+            app: job.app.clone(),
+            environment: job.environment.clone(),
+            received_at: timezone.format(job.received_at),
+            summary: match (result.status, job.cancellation.stopped()) {
+                (Some(_), None) if job.superseded() => messages.superseded.clone(),
+                (Some(_), Some(Stop::TimedOut(timeout))) => messages.timed_out(timeout),
+                (Some(_), Some(Stop::Expired(wait))) => messages.expired(wait),
+                (Some(status), _) => messages.exit_code(status),
+                (None, _) if !result.started() => messages.queued.clone(),
+                (None, _) => messages.running.clone(),
+            },
+            succeeded: result.status == Some(0),
+            unacknowledged_failure: matches!(result.status, Some(status) if status != 0)
+                && acknowledgement.is_none(),
+            acknowledged_by: acknowledgement.map(|acknowledgement| acknowledgement.by),
+            cancelled_by: job.cancellation.cancelled().map(|cancelled| cancelled.by),
+            category: result.category.clone(),
+            running: result.status.is_none(),
+            stalled: result.stalled(),
+            owner: job.owner.clone(),
+            contact: job.contact.clone(),
+            flags: job.flags.clone(),
+            annotations: result.annotations().cloned().collect(),
+            levels,
+            timeline: result.timeline.clone(),
+            usage: result.usage.as_ref().map(|usage| messages.usage(usage)),
+            invocations: result.invocations.clone(),
+            output: result.output[omitted..].to_vec(),
+            omitted,
+        }
+    }
+}
+
+#[derive(askama::Template)]
+#[template(path = "index.html")]
+struct Index {
+    apps: Vec<AppJobs>,
+    /// Webhook deliveries that were recently refused, most recent first.
+    deliveries: Vec<RecentDelivery>,
+    hide_successful: bool,
+    /// Leaves out the forms that change jobs, which a read-only mirror refuses.
+    read_only: bool,
+    /// The IANA name of the display timezone, for jobs shown as they are created.
+    timezone: &'static str,
+    messages: Arc<Messages>,
+}
+
+/// The jobs for one app, across all of its environments.
+struct AppJobs {
+    app: String,
+    jobs: Vec<TemplateJob>,
+    /// How many successful jobs were left out of `jobs`.
+    hidden: usize,
+    /// The app's latest job, if it failed and nobody has acknowledged it yet.
+    unacknowledged_failure: Option<Uuid>,
+}
+
+impl Index {
+    fn new(
+        jobs: Vec<TemplateJob>,
+        deliveries: Vec<RecentDelivery>,
+        hide_successful: bool,
+        read_only: bool,
+        timezone: DisplayTimezone,
+        messages: Arc<Messages>,
+    ) -> Self {
+        let mut apps = BTreeMap::<String, AppJobs>::new();
+        for job in jobs {
+            let app = apps.entry(job.app.clone()).or_insert_with(|| AppJobs {
+                app: job.app.clone(),
+                jobs: vec![],
+                hidden: 0,
+                unacknowledged_failure: None,
+            });
+            app.unacknowledged_failure = Some(job.id).filter(|_| job.unacknowledged_failure);
+            if hide_successful && job.succeeded {
+                app.hidden += 1;
+            } else {
+                app.jobs.push(job);
+            }
+        }
+        Self {
+            apps: apps.into_values().collect(),
+            deliveries,
+            hide_successful,
+            read_only,
+            timezone: timezone.name(),
+            messages,
+        }
+    }
+}
+
+/// The form acknowledging or cancelling a job, or redriving a delivery.
+#[derive(serde::Deserialize)]
+struct TokenForm {
+    secret: String,
+}
+
+/// The console remembers whether to hide successful jobs in a cookie, which is updated
+/// whenever the `hide_successful` query parameter is given.
+#[derive(serde::Deserialize)]
+struct ConsoleQuery {
+    hide_successful: Option<bool>,
+}
+
+const HIDE_SUCCESSFUL_COOKIE: &str = "hide_successful";
+
+/// Everything the server is configured with.
+pub struct Config {
+    /// Any of these is accepted as the `github_actions_secret`.
+    actions_secrets: Arc<Vec<String>>,
+    port: u16,
+    address: IpAddr,
+    hooks: Arc<Hooks>,
+    integrations: Arc<Integrations>,
+    responses: Arc<TriggerResponses>,
+    signing: Arc<RequestSigning>,
+    tokens: Arc<Tokens>,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    webhooks: Arc<Webhooks>,
+    record_signer: Option<Arc<RecordSigner>>,
+    locales: Arc<Locales>,
+    timezone: DisplayTimezone,
+    retention: Retention,
+    /// Accepts jobs replicated from a primary instance when set.
+    replication_secret: Option<String>,
+    /// Serves the console and API from the job store without triggering or changing
+    /// any jobs.
+    read_only: bool,
+    /// Who may read the console and job APIs, when not everyone may.
+    console_auth: Option<Arc<dyn ConsoleAuth>>,
+    namespaces: Arc<Namespaces>,
+    /// Serves over HTTPS when set.
+    tls: Option<tls::Tls>,
+    /// How long to wait for running deploys when asked to shut down.
+    shutdown_grace_period: Duration,
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file, with environment variables taking
+    /// precedence over it, panicking if it is invalid.
+    pub fn from_file(path: &Path) -> Self {
+        config_file::ConfigFile::read(path)
+            .unwrap_or_else(|error| panic!("{}", error))
+            .apply();
+        Self::from_env()
+    }
+
+    /// Reads the configuration from the file named by `--config` or the `config_file`
+    /// environment variable, if either is set, or else from environment variables alone.
+    pub fn load() -> Self {
+        match config_file::path() {
+            Some(path) => Self::from_file(&path),
+            None => Self::from_env(),
+        }
+    }
+
+    /// Reads the configuration from environment variables, panicking if any are invalid
+    /// or a required one is missing.
+    pub fn from_env() -> Self {
+        let actions_secrets = auth::secrets(
+            &std::env::var("github_actions_secret")
+                .expect("`github_actions_secret` environment variable must be set"),
+        );
+        if actions_secrets.is_empty() {
+            panic!("`github_actions_secret` environment variable must not be empty");
+        }
+        let port = std::env::var("console_port")
+            .expect("`console_port` environment variable must be set")
+            .parse()
+            .expect("`console_port` environment variable must be a number");
+        Self::new(actions_secrets, port)
+    }
+
+    /// Accepts any of `actions_secrets` as the `github_actions_secret` and listens on
+    /// `port`, reading the rest of the configuration from environment variables, for
+    /// services that embed the server and keep those two settings elsewhere.
+    pub fn new(actions_secrets: Vec<String>, port: u16) -> Self {
+        let timezone = DisplayTimezone::from_env();
+        let console_auth = console::from_env();
+        let namespaces = Namespaces::from_env();
+        if namespaces.has_users() && console_auth.is_none() {
+            panic!("`user_namespaces` environment variable needs the console to log users in");
+        }
+        Self {
+            port,
+            address: std::env::var("console_address")
+                .map(|address| {
+                    address
+                        .parse()
+                        .expect("`console_address` environment variable must be an IP address")
+                })
+                .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            hooks: Arc::new(Hooks::from_env()),
+            integrations: Arc::new(Integrations {
+                github: GitHub::from_env(),
+                nomad: Nomad::from_env(),
+                replica: Replica::from_env(),
+                store: store::from_env(),
+                deploying: Mutex::default(),
+                in_flight: watch::channel(0).0,
+                defaults: DeployDefaults::from_env(),
+                permits: std::env::var("max_concurrent_deploys").ok().map(|limit| {
+                    let limit = limit
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|limit| *limit > 0)
+                        .expect(
+                        "`max_concurrent_deploys` environment variable must be a positive number",
+                    );
+                    Permits::new(limit)
+                }),
+            }),
+            replication_secret: std::env::var("replication_secret").ok(),
+            console_auth,
+            namespaces: Arc::new(namespaces),
+            tls: tls::Tls::from_env(),
+            shutdown_grace_period: std::env::var("shutdown_grace_period")
+                .map(|seconds| {
+                    seconds.parse().map(Duration::from_secs).expect(
+                        "`shutdown_grace_period` environment variable must be a number of seconds",
+                    )
+                })
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD),
+            read_only: std::env::var("read_only")
+                .map(|read_only| {
+                    read_only
+                        .parse()
+                        .expect("`read_only` environment variable must be `true` or `false`")
+                })
+                .unwrap_or(false),
+            responses: Arc::new(TriggerResponses::from_env(timezone)),
+            signing: Arc::new(RequestSigning::from_env(&actions_secrets)),
+            tokens: Arc::new(Tokens::from_env(&actions_secrets)),
+            trusted_proxies: request_id::trusted_proxies_from_env(),
+            webhooks: Arc::new(Webhooks::from_env()),
+            record_signer: RecordSigner::from_env().map(Arc::new),
+            locales: Arc::new(Locales::from_env()),
+            timezone,
+            retention: Retention::from_env(),
+            actions_secrets: Arc::new(actions_secrets),
+        }
+    }
+}
+
+/// What the server keeps track of while it runs.
+#[derive(Clone, Default)]
+pub struct State {
+    jobs: Jobs,
+    rejections: Arc<Rejections>,
+}
+
+impl State {
+    /// Follows a job's progress, for services that embed the server rather than calling
+    /// its API: every line of its output, the ones already written first, then the job
+    /// once it has finished. Returns `None` if there is no such job.
+    pub async fn watch_job(&self, id: Uuid) -> Option<impl Stream<Item = types::JobProgress>> {
+        let job = self
+            .jobs
+            .list
+            .read()
+            .await
+            .iter()
+            .find(|job| job.id == id)
+            .cloned()?;
+        Some(progress::job_events(job))
+    }
+
+    /// Adds jobs that were saved elsewhere, such as by a job store, replacing any with the
+    /// same id.
+    pub async fn restore(&self, listings: impl IntoIterator<Item = types::JobListing>) {
+        for listing in listings {
+            insert_job(&self.jobs, Job::from_listing(listing)).await;
+        }
+    }
+}
+
+/// Builds every route the server handles, for it to serve, for a larger warp app to mount,
+/// or for tests to drive with `warp::test`.
+pub fn build_routes(
+    config: &Config,
+    state: &State,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    let jobs = state.jobs.clone();
+    let rejections = state.rejections.clone();
+    let hooks = config.hooks.clone();
+    let integrations = config.integrations.clone();
+    let responses = config.responses.clone();
+    let actions_secrets = config.actions_secrets.clone();
+    let signing = config.signing.clone();
+    let tokens = config.tokens.clone();
+    let trusted_proxies = config.trusted_proxies.clone();
+    let webhooks = config.webhooks.clone();
+    let record_signer = config.record_signer.clone();
+    let locales = config.locales.clone();
+    let timezone = config.timezone;
+    let replication_secret = config.replication_secret.clone();
+    let read_only = config.read_only;
+    let console_auth = config.console_auth.clone();
+    let namespaces = config.namespaces.clone();
+    let viewer = viewer(console_auth.clone(), namespaces.clone());
+    let port = config.port;
+
+    let admin_state = warp::get()
+        .and(warp::path!("admin" / "state"))
+        .and(auth::verify_actions_secret(actions_secrets.clone()))
+        .and(with_jobs(jobs.clone()))
+        .and(with_integrations(integrations.clone()))
+        .and(with_hooks(hooks.clone()))
+        .then(
+            move |jobs: Jobs, integrations: Arc<Integrations>, hooks: Arc<Hooks>| async move {
+                let dump = StateDump::collect(&jobs, &integrations, &hooks, port).await;
+                warp::reply::json(&dump)
+            },
+        );
+
+    // What queued jobs are waiting for, authenticated like /admin/state.
+    let admin_debug = warp::get()
+        .and(warp::path!("admin" / "debug"))
+        .and(auth::verify_actions_secret(actions_secrets.clone()))
+        .and(with_jobs(jobs.clone()))
+        .and(with_integrations(integrations.clone()))
+        .then(|jobs: Jobs, integrations: Arc<Integrations>| async move {
+            warp::reply::json(&QueueDump::collect(&jobs, &integrations).await)
+        });
+
+    let deploy2 = warp::path("deploy2")
+        .and(deploy_target())
+        .and(writable(read_only))
+        .and(auth::signing::verify_deploy_request(
+            tokens.clone(),
+            signing.clone(),
+        ))
+        // The body of a /deploy2 request is only read to check its signature.
+        .map(|target: (String, Option<String>), sender: String, _: Bytes| (target, sender))
+        .untuple_one()
+        .and(with_namespaces(namespaces.clone()))
+        .and_then(resolve_sender_target)
+        .and(warp::query::<DeployQuery>())
+        .and_then({
+            let tokens = tokens.clone();
+            move |target: DeployTarget, query: DeployQuery| {
+                ready(resolve_requested_ref(target, query, &tokens))
+            }
+        })
+        .and(artifact_source())
+        .map(Trigger::of);
+
+    // For `post-receive` hooks of bare repositories on this host, which post one ref
+    // update per request, authenticated like /deploy2.
+    let post_receive = warp::post()
+        .and(warp::path("post-receive"))
+        .and(deploy_target())
+        .and(writable(read_only))
+        .and(auth::signing::verify_deploy_request(
+            tokens.clone(),
+            signing,
+        ))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(with_namespaces(namespaces.clone()))
+        .and_then(
+            |target: (String, Option<String>),
+             sender: String,
+             body: Bytes,
+             encoding: Option<String>,
+             namespaces: Arc<Namespaces>| async move {
+                let target = resolve_sender_target(target, sender, namespaces).await?;
+                let update: RefUpdate = payload::from_json(encoding.as_deref(), &body)?;
+                resolve_push_target(target, update)
+            },
+        )
+        .and(warp::any().map(|| None::<ArtifactSource>))
+        .map(Trigger::of);
+
+    let deploy = warp::post()
+        .and(warp::path("deploy"))
+        .and(deploy_target())
+        .and(writable(read_only))
+        .and(auth::webhook_delivery())
+        .and(with_webhooks(webhooks.clone()))
+        .and_then(receive_delivery)
+        .and(warp::any().map(|| None::<ArtifactSource>))
+        .map(Trigger::of);
+
+    let deliveries = warp::get()
+        .and(warp::path!("admin" / "deliveries"))
+        .and(auth::verify_actions_secret(actions_secrets.clone()))
+        .and(with_webhooks(webhooks.clone()))
+        .then(|webhooks: Arc<Webhooks>| async move { warp::reply::json(&webhooks.list().await) });
+    let redrive = warp::post()
+        .and(warp::path!("admin" / "deliveries" / Uuid / "redrive"))
+        .and(writable(read_only))
+        .and(auth::verify_actions_secret(actions_secrets))
+        .and(with_webhooks(webhooks.clone()))
+        .and_then(redrive_delivery)
+        .and(warp::any().map(|| None::<ArtifactSource>))
+        .map(Trigger::of);
+    // Redrives a delivery from a form on the console, with the secret of one of the API
+    // tokens in no namespace, returning to the console to follow the job.
+    let redrive_tokens = tokens.clone();
+    let redrive_namespaces = namespaces.clone();
+    let console_redrive = warp::post()
+        .and(warp::path!("api" / "deliveries" / Uuid / "redrive"))
+        .and(writable(read_only))
+        .and(warp::body::content_length_limit(4 * 1024))
+        .and(warp::body::form())
+        .and_then(move |id: Uuid, form: TokenForm| {
+            let by = redrive_tokens.find(&form.secret).map(str::to_owned);
+            let namespaces = redrive_namespaces.clone();
+            async move {
+                let by = by.ok_or_else(|| reject::custom(InvalidSignature))?;
+                if namespaces.of_token(&by) != Scope::All {
+                    return Err(reject::custom(UnauthorizedSender));
+                }
+                eprintln!("{by} redrove delivery {id}");
+                Ok::<_, Rejection>(id)
+            }
+        })
+        .and(with_webhooks(webhooks.clone()))
+        .and_then(redrive_delivery)
+        .and(warp::any().map(|| None::<ArtifactSource>))
+        .map(|target: DeployTarget, artifact: Option<ArtifactSource>| {
+            Trigger::of(target, artifact).for_console()
+        });
+
+    let search = warp::get()
+        .and(warp::path!("api" / "search"))
+        .and(viewer.clone())
+        .and(warp::query::<SearchQuery>())
+        .and(with_jobs(jobs.clone()))
+        .and(with_integrations(integrations.clone()))
+        .then(
+            |scope: Scope,
+             query: SearchQuery,
+             jobs: Jobs,
+             integrations: Arc<Integrations>| async move {
+                let store = integrations.store.as_deref();
+                warp::reply::json(&search::search(&jobs, store, &scope, &query.q).await)
+            },
+        );
+
+    let trigger_stats = warp::get()
+        .and(warp::path!("api" / "stats" / "triggers"))
+        .and(viewer.clone())
+        .and(warp::query::<StatsQuery>())
+        .and(with_jobs(jobs.clone()))
+        .then(|scope: Scope, query: StatsQuery, jobs: Jobs| async move {
+            warp::reply::json(&stats::trigger_counts(&jobs, &scope, query.days).await)
+        });
+
+    let signed_record = warp::get()
+        .and(warp::path!("api" / "jobs" / Uuid / "record"))
+        .and(viewer.clone())
+        .and(with_jobs(jobs.clone()))
+        .and(warp::any().map({
+            let record_signer = record_signer.clone();
+            move || record_signer.clone()
+        }))
+        .and_then(
+            |id: Uuid, scope: Scope, jobs: Jobs, signer: Option<Arc<RecordSigner>>| async move {
+                let signer = signer.ok_or_else(reject::not_found)?;
+                let record = find_job(&jobs, &scope, id)
+                    .await
+                    .and_then(|job| audit::record(&job))
+                    .ok_or_else(|| reject::custom(UnknownJob))?;
+                Ok::<_, Rejection>(warp::reply::json(&types::SignedJobRecord {
+                    signature: signer.sign(&record),
+                    record,
+                }))
+            },
+        );
+    // Serves the public key that records are signed with, so that audit consumers can
+    // check them without asking the server.
+    let record_key = warp::get()
+        .and(warp::path!("api" / "records" / "key"))
+        .and(warp::any().map({
+            let record_signer = record_signer.clone();
+            move || record_signer.clone()
+        }))
+        .and_then(|signer: Option<Arc<RecordSigner>>| async move {
+            let signer = signer.ok_or_else(reject::not_found)?;
+            Ok::<_, Rejection>(warp::reply::json(&types::RecordKey {
+                algorithm: "ed25519".to_owned(),
+                public_key: signer.public_key(),
+            }))
+        });
+    // Checks a signed record for consumers that would rather not check it themselves.
+    let verify_record = warp::post()
+        .and(warp::path!("api" / "records" / "verify"))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and(warp::any().map(move || record_signer.clone()))
+        .and_then(
+            |signed: types::SignedJobRecord, signer: Option<Arc<RecordSigner>>| async move {
+                let signer = signer.ok_or_else(reject::not_found)?;
+                let valid = signer.verify(&signed.record, &signed.signature);
+                Ok::<_, Rejection>(warp::reply::json(&serde_json::json!({ "valid": valid })))
+            },
+        );
+
+    let version = warp::get().and(warp::path!("api" / "version")).map(|| {
+        let non_empty = |value: &str| Some(value.to_owned()).filter(|value| !value.is_empty());
+        warp::reply::json(&types::Version {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            git_sha: non_empty(env!("DEPLOY_SERVER_GIT_SHA")),
+            rustc: non_empty(env!("DEPLOY_SERVER_RUSTC_VERSION")),
+            features: env!("DEPLOY_SERVER_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        })
+    });
+
+    let metrics_rejections = rejections.clone();
+    let metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .and(viewer.clone())
+        .and(with_jobs(jobs.clone()))
+        .then(move |scope: Scope, jobs: Jobs| {
+            let rejections = metrics_rejections.clone();
+            async move {
+                warp::reply::with_header(
+                    metrics::render(&jobs, &rejections, &scope).await,
+                    warp::http::header::CONTENT_TYPE,
+                    "text/plain; version=0.0.4",
+                )
+            }
+        });
+
+    let list_jobs = warp::get()
+        .and(warp::path!("api" / "jobs"))
+        .and(viewer.clone())
+        .and(warp::query::<JobsQuery>())
+        .and(with_jobs(jobs.clone()))
+        .then(|scope: Scope, query: JobsQuery, jobs: Jobs| async move {
+            let jobs: Vec<_> = jobs
+                .list
+                .read()
+                .await
+                .iter()
+                .filter(|job| scope.includes(job.namespace.as_deref()))
+                .map(|job| job.listing(LISTED_OUTPUT_LINES))
+                .filter(|listing| query.matches(&listing.job))
+                .collect();
+            warp::reply::json(&jobs)
+        });
+
+    let get_job = warp::get()
+        .and(warp::path!("api" / "jobs" / Uuid))
+        .and(viewer.clone())
+        .and(with_jobs(jobs.clone()))
+        .and_then(|id: Uuid, scope: Scope, jobs: Jobs| async move {
+            let job = find_job(&jobs, &scope, id)
+                .await
+                .ok_or_else(|| reject::custom(UnknownJob))?;
+            Ok::<_, Rejection>(warp::reply::json(&job.listing(usize::MAX)))
+        });
+
+    // The whole output of a job as plain text, which the console loads on demand.
+    let job_log = warp::get()
+        .and(warp::path!("api" / "jobs" / Uuid / "log"))
+        .and(viewer.clone())
+        .and(with_jobs(jobs.clone()))
+        .and_then(|id: Uuid, scope: Scope, jobs: Jobs| async move {
+            let job = find_job(&jobs, &scope, id)
+                .await
+                .ok_or_else(|| reject::custom(UnknownJob))?;
+            let log = job.result.borrow().log();
+            Ok::<_, Rejection>(warp::reply::with_header(
+                log,
+                "content-type",
+                "text/plain; charset=utf-8",
+            ))
+        });
+
+    let stream_job = warp::get()
+        .and(warp::path!("api" / "jobs" / Uuid / "stream"))
+        .and(viewer.clone())
+        .and(with_jobs(jobs.clone()))
+        .and_then(|id: Uuid, scope: Scope, jobs: Jobs| async move {
+            let job = find_job(&jobs, &scope, id)
+                .await
+                .ok_or_else(|| reject::custom(UnknownJob))?;
+            Ok::<_, Rejection>(warp::sse::reply(
+                warp::sse::keep_alive().stream(sse::job_events(job)),
+            ))
+        });
+
+    let updates = warp::path!("ws")
+        .and(viewer.clone())
+        .and(warp::ws())
+        .and(with_jobs(jobs.clone()))
+        .map(|scope: Scope, ws: warp::ws::Ws, jobs: Jobs| {
+            ws.on_upgrade(move |socket| live::console_updates(socket, jobs, scope))
+        });
+
+    let cancel_tokens = tokens.clone();
+    let cancel_namespaces = namespaces.clone();
+    // Acknowledges a failed job, from a form on the console or from a script, with the
+    // secret of one of the API tokens.
+    let acknowledge = warp::post()
+        .and(warp::path!("api" / "jobs" / Uuid / "acknowledge"))
+        .and(writable(read_only))
+        .and(warp::body::content_length_limit(4 * 1024))
+        .and(warp::body::form())
+        .and(with_jobs(jobs.clone()))
+        .and(with_integrations(integrations.clone()))
+        .and_then(
+            move |id: Uuid, form: TokenForm, jobs: Jobs, integrations: Arc<Integrations>| {
+                let by = tokens.find(&form.secret).map(str::to_owned);
+                let namespaces = namespaces.clone();
+                async move {
+                    let by = by.ok_or_else(|| reject::custom(InvalidSignature))?;
+                    let job = find_job(&jobs, &namespaces.of_token(&by), id)
+                        .await
+                        .ok_or_else(|| reject::custom(UnknownJob))?;
+                    if !matches!(job.result.borrow().status, Some(status) if status != 0) {
+                        return Err(reject::custom(NotFailed));
+                    }
+                    eprintln!("{by} acknowledged job {id} of {}", job.app);
+                    *job.acknowledgement.lock().unwrap() = Some(Acknowledgement {
+                        by,
+                        at: SystemTime::now(),
+                    });
+                    integrations.save(&job).await;
+                    let location = format!("/#{id}").parse::<warp::http::Uri>().unwrap();
+                    Ok::<_, Rejection>(warp::redirect::see_other(location))
+                }
+            },
+        );
+
+    // Cancels a running job, killing whatever process it is running, from a form on the
+    // console or from a script, with the secret of one of the API tokens.
+    let cancel = warp::post()
+        .and(warp::path!("api" / "jobs" / Uuid / "cancel"))
+        .and(writable(read_only))
+        .and(warp::body::content_length_limit(4 * 1024))
+        .and(warp::body::form())
+        .and(with_jobs(jobs.clone()))
+        .and_then(move |id: Uuid, form: TokenForm, jobs: Jobs| {
+            let by = cancel_tokens.find(&form.secret).map(str::to_owned);
+            let namespaces = cancel_namespaces.clone();
+            async move {
+                let by = by.ok_or_else(|| reject::custom(InvalidSignature))?;
+                let job = find_job(&jobs, &namespaces.of_token(&by), id)
+                    .await
+                    .ok_or_else(|| reject::custom(UnknownJob))?;
+                if job.result.borrow().status.is_some() {
+                    return Err(reject::custom(AlreadyFinished));
+                }
+                if job.cancellation.cancel(by.clone()) {
+                    eprintln!("{by} cancelled job {id} of {}", job.app);
+                }
+                let location = format!("/#{id}").parse::<warp::http::Uri>().unwrap();
+                Ok::<_, Rejection>(warp::redirect::see_other(location))
+            }
+        });
+
+    // Receives jobs pushed by a primary instance, when this is its standby.
+    let replica = warp::post()
+        .and(warp::path!("api" / "replica" / "jobs"))
+        .and(writable(read_only))
+        .and(warp::header::optional::<String>(replication::SECRET_HEADER))
+        .and(warp::body::content_length_limit(MAX_REPLICATED_JOB))
+        .and(warp::body::json())
+        .and(with_jobs(jobs.clone()))
+        .and_then(
+            move |secret: Option<String>, listing: types::JobListing, jobs: Jobs| {
+                let authorized = matches!(
+                    (&replication_secret, &secret),
+                    (Some(expected), Some(secret)) if auth::secret_matches(expected, secret)
+                );
+                async move {
+                    if !authorized {
+                        return Err(reject::custom(InvalidSignature));
+                    }
+                    insert_job(&jobs, Job::from_listing(listing)).await;
+                    Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+                }
+            },
+        );
+
+    // The same path as the console, for monitoring scripts that ask for JSON.
+    let status = warp::get()
+        .and(warp::filters::path::end())
+        .and(accepts_json())
+        .and(viewer.clone())
+        .and(with_jobs(jobs.clone()))
+        .then(|scope: Scope, jobs: Jobs| async move {
+            warp::reply::with_header(
+                warp::reply::json(&app_statuses(&jobs, &scope).await),
+                warp::http::header::VARY,
+                "accept",
+            )
+        });
+
+    let console = warp::get()
+        .and(warp::filters::path::end())
+        .and(viewer.clone())
+        .and(warp::query::<ConsoleQuery>())
+        .and(warp::cookie::optional(HIDE_SUCCESSFUL_COOKIE))
+        .and(warp::header::optional::<String>("accept-language"))
+        .and(with_jobs(jobs.clone()))
+        .and(with_webhooks(webhooks))
+        .and(with_locales(locales))
+        .then(
+            move |scope: Scope,
+                  query: ConsoleQuery,
+                  cookie: Option<String>,
+                  accept_language: Option<String>,
+                  jobs: Jobs,
+                  webhooks: Arc<Webhooks>,
+                  locales: Arc<Locales>| async move {
+                let messages = locales.negotiate(accept_language.as_deref());
+                let hide_successful = query
+                    .hide_successful
+                    .or_else(|| cookie?.parse().ok())
+                    .unwrap_or(false);
+                let jobs: Vec<_> = jobs
+                    .list
+                    .read()
+                    .await
+                    .iter()
+                    .filter(|job| scope.includes(job.namespace.as_deref()))
+                    .map(|job| TemplateJob::from(job, &messages, timezone))
+                    .collect();
+                // Refused deliveries may be for any app, or none, so only operators see them.
+                let deliveries = match scope {
+                    Scope::All => webhooks.recent(timezone).await,
+                    Scope::Namespace(..) => vec![],
+                };
+                let page = Index::new(
+                    jobs,
+                    deliveries,
+                    hide_successful,
+                    read_only,
+                    timezone,
+                    messages,
+                )
+                .into_response();
+                match query.hide_successful {
+                    Some(hide) => {
+                        let cookie = format!(
+                            "{HIDE_SUCCESSFUL_COOKIE}={hide}; Path=/; Max-Age=31536000; SameSite=Lax"
+                        );
+                        warp::reply::with_header(page, warp::http::header::SET_COOKIE, cookie)
+                            .into_response()
+                    }
+                    None => page,
+                }
+            },
+        );
+
+    let triggers = deploy2
+        .or(deploy)
+        .unify()
+        .or(post_receive)
+        .unify()
+        .or(redrive)
+        .unify()
+        .or(console_redrive)
+        .unify()
+        .map(|trigger| Routed::Trigger(Box::new(trigger)));
+
+    // Triggers are only deployed once the request has its ID, which is given to every
+    // request once, before it is routed.
+    request_id::requested(trusted_proxies)
+        .and(
+            triggers
+                .or(admin_state
+                    .or(admin_debug)
+                    // Boxed partway, as the future of the whole chain is nested too deeply
+                    // for the compiler to lay out.
+                    .boxed()
+                    .or(deliveries)
+                    .or(search)
+                    .or(trigger_stats)
+                    .or(list_jobs)
+                    .or(get_job)
+                    .or(stream_job)
+                    .or(job_log)
+                    .or(updates)
+                    .or(replica)
+                    .or(metrics)
+                    .or(signed_record.or(record_key).or(verify_record))
+                    .or(acknowledge)
+                    .or(cancel)
+                    .or(version)
+                    .or(status)
+                    .or(console)
+                    .or(console::routes(console_auth))
+                    .map(Routed::replied))
+                .unify()
+                .recover(|rejection| async {
+                    request_id::handle_rejection(rejection)
+                        .await
+                        .map(Routed::Reply)
+                })
+                .unify(),
+        )
+        .and(with_jobs(jobs))
+        .and(with_hooks(hooks))
+        .and(with_integrations(integrations))
+        .and(with_responses(responses))
+        .then(respond)
+        .untuple_one()
+        .and(warp::any().map(move || rejections.clone()))
+        .map(request_id::tag_response)
+}
+
+/// How often a read-only mirror reloads jobs from a store that cannot be watched.
+const STORE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Adds the jobs that other instances sharing the job store save, as they save them.
+async fn follow_store(
+    mut saved: mpsc::UnboundedReceiver<Uuid>,
+    integrations: Arc<Integrations>,
+    jobs: Jobs,
+) {
+    let store = match &integrations.store {
+        Some(store) => store,
+        None => return,
+    };
+    while let Some(id) = saved.recv().await {
+        match store.get(id).await {
+            Ok(Some(listing)) => insert_job(&jobs, Job::from_listing(listing)).await,
+            Ok(None) => {}
+            Err(error) => eprintln!("Failed to read job {id} from the job store: {error}"),
+        }
+    }
+}
+
+/// Reloads the most recent jobs from the job store every `STORE_POLL_INTERVAL`, for a
+/// read-only mirror of a store that cannot say when jobs are saved.
+async fn poll_store(integrations: Arc<Integrations>, jobs: Jobs) {
+    let store = match &integrations.store {
+        Some(store) => store,
+        None => return,
+    };
+    let mut interval = tokio::time::interval(STORE_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        match store.load(store::history_from_env()).await {
+            Ok(listings) => {
+                for listing in listings {
+                    insert_job(&jobs, Job::from_listing(listing)).await;
+                }
+            }
+            Err(error) => eprintln!("Failed to reload jobs from the job store: {error}"),
+        }
+    }
+}
+
+/// How long shutting down waits for running deploys, unless `shutdown_grace_period` says.
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(300);
+
+/// Resolves once the process is asked to stop, by `SIGTERM` or Ctrl+C.
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminations) => {
+                tokio::select! {
+                    _ = terminations.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(error) => eprintln!("Failed to listen for SIGTERM: {error}"),
+        }
+    }
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Waits for up to `timeout` for every deploy in flight to finish, returning whether they
+/// did.
+async fn deploys_finished(integrations: &Integrations, timeout: Duration) -> bool {
+    let mut in_flight = integrations.in_flight.subscribe();
+    tokio::time::timeout(timeout, async move {
+        loop {
+            if *in_flight.borrow() == 0 {
+                break;
+            }
+            if in_flight.changed().await.is_err() {
+                break;
+            }
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Lets the deploys in flight finish, for up to the grace period, then cancels those
+/// still running and gives them a moment to stop and be saved. Then the jobs still
+/// waiting to be notified about in a batch are notified about straight away.
+async fn finish_deploys(config: &Config, jobs: &Jobs) {
+    let count = *config.integrations.in_flight.borrow();
+    if count > 0 {
+        eprintln!(
+            "Waiting up to {}s for {count} deploys to finish",
+            config.shutdown_grace_period.as_secs()
+        );
+        if !deploys_finished(&config.integrations, config.shutdown_grace_period).await {
+            for job in jobs.list.read().await.iter() {
+                if job.result.borrow().status.is_none()
+                    && job.cancellation.cancel("shutdown".to_owned())
+                {
+                    eprintln!("Cancelled job {} of {} to shut down", job.id, job.app);
+                }
+            }
+            deploys_finished(&config.integrations, cancellation::GRACE * 2).await;
+        }
+    }
+    config.hooks.flush().await;
+}
+
+/// Queues the jobs that were still queued when the server that took them stopped again,
+/// and fails those that were running, saying why, as nothing is running them any more.
+async fn resume(
+    listings: Vec<types::JobListing>,
+    stopped: &str,
+    hooks: &Arc<Hooks>,
+    integrations: &Arc<Integrations>,
+    responses: &Arc<TriggerResponses>,
+    jobs: &Jobs,
+) {
+    for listing in listings {
+        if listing.state != types::JobState::Queued {
+            abandon(listing, stopped, integrations, jobs).await;
+            continue;
+        }
+        let job = &listing.job;
+        let place = (job.app.clone(), job.environment.clone());
+        let mut target = match resolve_deploy_script(place, job.source).await {
+            Ok(target) => target,
+            Err(..) => {
+                let reason = "The job was queued when the server stopped, and its app can no \
+                              longer be deployed";
+                abandon(listing, reason, integrations, jobs).await;
+                continue;
+            }
+        };
+        target.sha = job.sha.clone();
+        target.reference = job.reference.clone();
+        target.repository = job.repository.clone();
+        target.pusher = job.pusher.clone();
+        target.sender = job.sender.clone();
+        target.flags = job.flags.clone();
+        target.labels = job.labels.clone();
+        let (mut resumed, writer) = Job::new(
+            &target,
+            job.artifact.clone(),
+            job.request_id.clone(),
+            &integrations.defaults,
+        );
+        resumed.id = job.id;
+        resumed.received_at = UNIX_EPOCH + Duration::from_millis(job.received_at);
+        resumed.requeued = true;
+        eprintln!(
+            "Queuing job {} of {} again, which was queued when the server stopped",
+            job.id, job.app
+        );
+        enqueue(
+            Arc::new(resumed),
+            writer,
+            target,
+            jobs.clone(),
+            hooks.clone(),
+            integrations.clone(),
+            responses.clone(),
+        )
+        .await;
+    }
+}
+
+/// Fails a job that will never finish, saying why.
+async fn abandon(
+    mut listing: types::JobListing,
+    reason: &str,
+    integrations: &Integrations,
+    jobs: &Jobs,
+) {
+    listing.output.push(types::OutputLine {
+        stream: "stderr".to_owned(),
+        text: reason.to_owned(),
+        elapsed_ms: listing.output.last().map_or(0, |line| line.elapsed_ms),
+    });
+    listing.job.lines = listing.output.len();
+    listing.job.status = Some(255);
+    listing.state = types::JobState::Failed;
+    let job = Job::from_listing(listing);
+    integrations.save(&job).await;
+    insert_job(jobs, job).await;
+}
+
+/// How often an instance sharing the job store checks for jobs left unfinished by another
+/// that stopped.
+const ORPHAN_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Takes over the unfinished jobs of instances sharing the job store once they have
+/// stopped, including those of this server's previous run: those that were still queued
+/// are queued here, and those that had started are failed, as nothing is running them
+/// any more.
+async fn take_over_orphans(
+    hooks: Arc<Hooks>,
+    integrations: Arc<Integrations>,
+    responses: Arc<TriggerResponses>,
+    jobs: Jobs,
+) {
+    let store = match &integrations.store {
+        Some(store) => store,
+        None => return,
+    };
+    let mut interval = tokio::time::interval(ORPHAN_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let orphans = match store.orphans().await {
+            Ok(orphans) => orphans,
+            Err(error) => {
+                eprintln!("Failed to check the job store for abandoned jobs: {error}");
+                continue;
+            }
+        };
+        for listing in &orphans {
+            eprintln!(
+                "Taking over job {} of {}, as the instance that took it has stopped",
+                listing.job.id, listing.job.app
+            );
+        }
+        let stopped = "The instance that took the job stopped before it finished";
+        resume(orphans, stopped, &hooks, &integrations, &responses, &jobs).await;
+    }
+}
+
+/// Serves the console and trigger endpoints until the process is stopped. Once it is,
+/// no more requests are accepted, and running deploys are given time to finish.
+pub async fn serve(mut config: Config, state: State) {
+    if config.read_only && config.integrations.store.is_none() {
+        eprintln!("Serving read-only without a job store, so there are no jobs to show");
+    }
+    if let Some(store) = &config.integrations.store {
+        let listings = match store.load(store::history_from_env()).await {
+            Ok(listings) => {
+                eprintln!("Loaded {} jobs from the job store", listings.len());
+                listings
+            }
+            Err(error) => {
+                eprintln!("Failed to load jobs from the job store: {error}");
+                vec![]
+            }
+        };
+        let shared = match store.watch().await {
+            Ok(Some(saved)) => {
+                tokio::spawn(follow_store(
+                    saved,
+                    config.integrations.clone(),
+                    state.jobs.clone(),
+                ));
+                true
+            }
+            Ok(None) if config.read_only => {
+                tokio::spawn(poll_store(config.integrations.clone(), state.jobs.clone()));
+                false
+            }
+            Ok(None) => false,
+            Err(error) => {
+                eprintln!("Failed to watch the job store, so it is not shared: {error}");
+                false
+            }
+        };
+        // Other instances sharing the store may still be running its unfinished jobs, so
+        // they are only taken over once those instances have stopped.
+        let (unfinished, finished): (Vec<_>, Vec<_>) = listings
+            .into_iter()
+            .partition(|listing| listing.job.status.is_none() && !shared && !config.read_only);
+        state.restore(finished).await;
+        resume(
+            unfinished,
+            "The server stopped while the job was running",
+            &config.hooks,
+            &config.integrations,
+            &config.responses,
+            &state.jobs,
+        )
+        .await;
+        if shared && !config.read_only {
+            tokio::spawn(take_over_orphans(
+                config.hooks.clone(),
+                config.integrations.clone(),
+                config.responses.clone(),
+                state.jobs.clone(),
+            ));
+        }
+    }
+
+    tokio::spawn(config.retention.run(state.jobs.clone()));
+
+    #[cfg(unix)]
+    tokio::spawn(crate::state::dump_on_signal(
+        state.jobs.clone(),
+        config.integrations.clone(),
+        config.hooks.clone(),
+        config.port,
+    ));
+
+    let _announcement = mdns::announce_from_env(config.address, config.port);
+    let routes = build_routes(&config, &state);
+    let address = SocketAddr::new(config.address, config.port);
+    let (stop, stopped) = oneshot::channel::<()>();
+    let stopped = async move {
+        stopped.await.ok();
+    };
+    let mut server = match config.tls.take() {
+        Some(tls) => tokio::spawn(tls::serve(routes, address, tls, stopped)),
+        None => tokio::spawn(
+            warp::serve(routes)
+                .bind_with_graceful_shutdown(address, stopped)
+                .1,
+        ),
+    };
+    tokio::select! {
+        _ = &mut server => return,
+        _ = terminated() => {}
+    }
+    eprintln!("Shutting down");
+    stop.send(()).ok();
+    finish_deploys(&config, &state.jobs).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saved_copies_do_not_replace_running_jobs() {
+        let target = DeployTarget {
+            app: "web".to_owned(),
+            environment: None,
+            runner: Runner::Script(PathBuf::from("web")),
+            preflight: None,
+            settings: AppSettings::default(),
+            sha: None,
+            reference: None,
+            repository: None,
+            pusher: None,
+            sender: None,
+            source: TriggerSource::Deploy2,
+            flags: vec![],
+            labels: BTreeMap::new(),
+        };
+        let (job, writer) = Job::new(
+            &target,
+            None,
+            "test".to_owned(),
+            &DeployDefaults::from_env(),
+        );
+        let job = Arc::new(job);
+        let jobs = Jobs::default();
+        jobs.list.write().await.push(job.clone());
+
+        // As it is read back from a job store that it was saved to while running.
+        insert_job(&jobs, Job::from_listing(job.listing(0))).await;
+
+        let listed = find_job(&jobs, &Scope::All, job.id).await.unwrap();
+        assert!(Arc::ptr_eq(&listed, &job));
+        assert!(listed.cancellation.cancel("ci".to_owned()));
+        assert!(writer.stopped());
+        assert!(job.result.borrow().status.is_some());
+    }
+}
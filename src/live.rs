@@ -0,0 +1,120 @@
+//! Live updates for the console over a WebSocket, so it can follow jobs without being
+//! refreshed.
+
+use crate::namespaces::Scope;
+use crate::{JobResult, Jobs};
+use deploy_server_types::{ConsoleEvent, Job, JobState};
+use futures::stream::FuturesUnordered;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use tokio::sync::watch;
+use uuid::Uuid;
+use warp::ws::{Message, WebSocket};
+
+/// What the client has been told about a job: its status, whether it had started, and
+/// how many lines it had written.
+type Seen = HashMap<Uuid, ((Option<i32>, bool), usize)>;
+
+/// The changes to the jobs in the scope since they were last seen, marking them seen.
+async fn changes(jobs: &Jobs, scope: &Scope, seen: &mut Seen) -> Vec<ConsoleEvent> {
+    let mut events = vec![];
+    for job in jobs
+        .list
+        .read()
+        .await
+        .iter()
+        .filter(|job| scope.includes(job.namespace.as_deref()))
+    {
+        let previous = seen.get(&job.id).copied();
+        // `summary` borrows the result too, so the borrow ends before it is called.
+        let (phase, lines, written) = {
+            let result = job.result.borrow();
+            let sent = previous.map_or(result.written(), |(_, sent)| sent);
+            let lines: Vec<_> = (sent..result.written())
+                .filter_map(|index| result.written_line(index))
+                .collect();
+            ((result.status, result.started()), lines, result.written())
+        };
+        seen.insert(job.id, (phase, written));
+
+        if previous.is_none() {
+            events.push(job_event(job.summary()));
+        }
+        events.extend(lines.into_iter().map(|line| ConsoleEvent::Line {
+            job_id: job.id,
+            line,
+        }));
+        if matches!(previous, Some((previous, _)) if previous != phase) {
+            events.push(job_event(job.summary()));
+        }
+    }
+    events
+}
+
+fn job_event(job: Job) -> ConsoleEvent {
+    ConsoleEvent::Job {
+        state: JobState::of(&job),
+        job: Box::new(job),
+    }
+}
+
+/// The results of the jobs in the scope that are still running, marked seen, to wait for
+/// changes to.
+async fn unfinished(jobs: &Jobs, scope: &Scope) -> Vec<watch::Receiver<JobResult>> {
+    jobs.list
+        .read()
+        .await
+        .iter()
+        .filter(|job| scope.includes(job.namespace.as_deref()))
+        .filter(|job| job.result.borrow().status.is_none())
+        .map(|job| {
+            let mut result = job.result.clone();
+            result.borrow_and_update();
+            result
+        })
+        .collect()
+}
+
+/// Waits until any of the results changes. Those that are no longer being written, such
+/// as jobs replicated from elsewhere, never do.
+async fn any_change(results: Vec<watch::Receiver<JobResult>>) {
+    let mut changes: FuturesUnordered<_> = results
+        .into_iter()
+        .map(|mut result| async move {
+            if result.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        })
+        .collect();
+    if changes.next().await.is_none() {
+        futures::future::pending::<()>().await;
+    }
+}
+
+/// Sends the console every job in the scope that is queued or starts, line of output that
+/// is written, and job that finishes after it connects, until it disconnects.
+pub async fn console_updates(socket: WebSocket, jobs: Jobs, scope: Scope) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut inserted = jobs.inserted.subscribe();
+    let mut seen = Seen::new();
+    changes(&jobs, &scope, &mut seen).await;
+
+    loop {
+        // Subscribing before looking for changes means none are missed in between.
+        let results = unfinished(&jobs, &scope).await;
+        for event in changes(&jobs, &scope, &mut seen).await {
+            let message = Message::text(serde_json::to_string(&event).unwrap());
+            if sender.send(message).await.is_err() {
+                return;
+            }
+        }
+        tokio::select! {
+            _ = inserted.changed() => {}
+            _ = any_change(results) => {}
+            message = receiver.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => return,
+            },
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! The console's user-facing text, in each language it can be read in.
+
+use crate::usage::ResourceUsage;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The catalogs that ship with the server.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.json")),
+    ("ja", include_str!("../locales/ja.json")),
+];
+
+/// Every message the console shows, in one language. Messages may contain `{name}`
+/// placeholders, which are filled in when they are shown.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Messages {
+    /// The language tag the messages are written in, set from the catalog's name.
+    #[serde(skip)]
+    pub locale: String,
+    pub title: String,
+    pub app: String,
+    pub environment: String,
+    pub received_at: String,
+    pub owner: String,
+    pub contact: String,
+    pub acknowledged_by: String,
+    pub cancelled_by: String,
+    pub category: String,
+    pub warning: String,
+    pub stalled: String,
+    pub level: String,
+    pub all_levels: String,
+    latest_deploy_failed: String,
+    pub api_token: String,
+    pub acknowledge: String,
+    pub cancel: String,
+    pub refused_deliveries: String,
+    pub reason: String,
+    pub redrive: String,
+    pub showing_unsuccessful: String,
+    pub show_all: String,
+    pub showing_all: String,
+    pub hide_successful: String,
+    hidden_one: String,
+    hidden_other: String,
+    omitted_one: String,
+    omitted_other: String,
+    pub load_full_log: String,
+    /// Shown as it is, placeholder and all, for the console to fill in as jobs finish.
+    pub exit_code: String,
+    pub timed_out: String,
+    pub expired: String,
+    pub queued: String,
+    pub superseded: String,
+    pub running: String,
+    pub ran: String,
+    usage: String,
+}
+
+fn fill(message: &str, values: &[(&str, &dyn std::fmt::Display)]) -> String {
+    values
+        .iter()
+        .fold(message.to_owned(), |message, (name, value)| {
+            message.replace(&format!("{{{name}}}"), &value.to_string())
+        })
+}
+
+impl Messages {
+    pub fn latest_deploy_failed(&self, app: &str) -> String {
+        fill(&self.latest_deploy_failed, &[("app", &app)])
+    }
+
+    pub fn hidden(&self, count: &usize) -> String {
+        let message = match *count {
+            1 => &self.hidden_one,
+            _ => &self.hidden_other,
+        };
+        fill(message, &[("count", &count)])
+    }
+
+    pub fn omitted(&self, count: &usize) -> String {
+        let message = match *count {
+            1 => &self.omitted_one,
+            _ => &self.omitted_other,
+        };
+        fill(message, &[("count", &count)])
+    }
+
+    pub fn exit_code(&self, status: i32) -> String {
+        fill(&self.exit_code, &[("status", &status)])
+    }
+
+    pub fn timed_out(&self, timeout: Duration) -> String {
+        fill(&self.timed_out, &[("seconds", &timeout.as_secs())])
+    }
+
+    pub fn expired(&self, wait: Duration) -> String {
+        fill(&self.expired, &[("seconds", &wait.as_secs())])
+    }
+
+    pub fn usage(&self, usage: &ResourceUsage) -> String {
+        fill(
+            &self.usage,
+            &[
+                (
+                    "memory",
+                    &format!("{:.1}", usage.peak_rss as f64 / (1024.0 * 1024.0)),
+                ),
+                ("user", &format!("{:.2}", usage.user_time.as_secs_f64())),
+                ("system", &format!("{:.2}", usage.system_time.as_secs_f64())),
+            ],
+        )
+    }
+}
+
+/// The message catalogs, and which one to use when the browser accepts none of them.
+pub struct Locales {
+    default: String,
+    catalogs: HashMap<String, Arc<Messages>>,
+}
+
+fn parse(locale: &str, contents: &str) -> Result<Messages, serde_json::Error> {
+    let mut messages: Messages = serde_json::from_str(contents)?;
+    messages.locale = locale.to_owned();
+    Ok(messages)
+}
+
+impl Locales {
+    /// The built-in catalogs, along with any `{locale}.json` catalogs in the directory named
+    /// by `console_locales`, which replace built-in catalogs of the same locale. The default
+    /// locale is `console_locale`, or English.
+    pub fn from_env() -> Self {
+        let mut catalogs: HashMap<_, _> = BUILT_IN
+            .iter()
+            .map(|(locale, contents)| {
+                let messages = parse(locale, contents).expect("built-in catalogs are valid");
+                (locale.to_string(), Arc::new(messages))
+            })
+            .collect();
+        if let Some(directory) = std::env::var_os("console_locales") {
+            let entries = std::fs::read_dir(&directory)
+                .expect("`console_locales` environment variable must name a directory");
+            for entry in entries {
+                let path = entry
+                    .expect("`console_locales` directory must be readable")
+                    .path();
+                if path.extension().is_none_or(|extension| extension != "json") {
+                    continue;
+                }
+                let (locale, messages) = Self::read(&path);
+                catalogs.insert(locale, Arc::new(messages));
+            }
+        }
+        let default = std::env::var("console_locale")
+            .map(|locale| locale.to_lowercase())
+            .unwrap_or_else(|_| "en".to_owned());
+        assert!(
+            catalogs.contains_key(&default),
+            "`console_locale` environment variable must name a locale with a catalog",
+        );
+        Self { default, catalogs }
+    }
+
+    fn read(path: &Path) -> (String, Messages) {
+        let locale = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .expect("catalog names must be locales")
+            .to_lowercase();
+        let contents = std::fs::read_to_string(path)
+            .unwrap_or_else(|error| panic!("Failed to read {}: {error}", path.display()));
+        let messages = parse(&locale, &contents)
+            .unwrap_or_else(|error| panic!("Invalid catalog {}: {error}", path.display()));
+        (locale, messages)
+    }
+
+    /// The catalog for the most preferred language in an `Accept-Language` header that
+    /// there is one for, trying `ja` for `ja-JP` if there is no catalog for `ja-JP` itself.
+    pub fn negotiate(&self, accept_language: Option<&str>) -> Arc<Messages> {
+        let mut preferences: Vec<(f32, String)> = accept_language
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|preference| {
+                let mut parts = preference.split(';').map(str::trim);
+                let tag = parts.next().filter(|tag| !tag.is_empty())?;
+                let quality = parts
+                    .find_map(|parameter| parameter.strip_prefix("q="))
+                    .map_or(Some(1.0), |quality| quality.parse().ok())?;
+                Some((quality, tag.to_lowercase()))
+            })
+            .filter(|(quality, _)| *quality > 0.0)
+            .collect();
+        // A stable sort keeps the header's order among equally preferred languages.
+        preferences.sort_by(|a, b| b.0.total_cmp(&a.0));
+        preferences
+            .iter()
+            .find_map(|(_, tag)| {
+                let language = tag.split('-').next().unwrap_or_default();
+                self.catalogs
+                    .get(tag)
+                    .or_else(|| self.catalogs.get(language))
+            })
+            .unwrap_or(&self.catalogs[&self.default])
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The built-in catalogs, and a `pt-br` one, which has no `pt` catalog to fall back to.
+    fn locales() -> Locales {
+        let english = BUILT_IN[0].1;
+        let catalogs = BUILT_IN
+            .iter()
+            .copied()
+            .chain([("pt-br", english)])
+            .map(|(locale, contents)| {
+                (
+                    locale.to_owned(),
+                    Arc::new(parse(locale, contents).unwrap()),
+                )
+            })
+            .collect();
+        Locales {
+            default: "en".to_owned(),
+            catalogs,
+        }
+    }
+
+    fn negotiate(accept_language: &str) -> String {
+        locales().negotiate(Some(accept_language)).locale.clone()
+    }
+
+    #[test]
+    fn the_most_preferred_language_with_a_catalog_is_used() {
+        assert_eq!(negotiate("ja"), "ja");
+        assert_eq!(negotiate("fr, ja;q=0.5, en;q=0.4"), "ja");
+        assert_eq!(negotiate("en;q=0.4, ja;q=0.8"), "ja");
+        assert_eq!(negotiate("en;q=0.8, ja;q=0.4"), "en");
+        assert_eq!(negotiate("JA"), "ja");
+    }
+
+    #[test]
+    fn equally_preferred_languages_keep_their_order() {
+        assert_eq!(negotiate("ja;q=0.5, en;q=0.5"), "ja");
+        assert_eq!(negotiate("en, ja"), "en");
+    }
+
+    #[test]
+    fn regional_tags_fall_back_to_their_language() {
+        assert_eq!(negotiate("ja-JP"), "ja");
+        assert_eq!(negotiate("ja-JP, en;q=0.9"), "ja");
+        assert_eq!(negotiate("pt-BR"), "pt-br");
+        // A regional catalog does not stand in for the bare language.
+        assert_eq!(negotiate("pt, ja;q=0.5"), "ja");
+    }
+
+    #[test]
+    fn languages_with_no_quality_are_not_accepted() {
+        assert_eq!(negotiate("ja;q=0, en;q=0.1"), "en");
+        assert_eq!(negotiate("ja;q=0.0"), "en");
+    }
+
+    #[test]
+    fn malformed_preferences_are_skipped() {
+        assert_eq!(negotiate("ja;q=high, en;q=0.1"), "en");
+        assert_eq!(negotiate(", ;q=0.9, ja;q=0.5"), "ja");
+        assert_eq!(negotiate(";;;"), "en");
+        assert_eq!(negotiate(""), "en");
+    }
+
+    #[test]
+    fn the_default_is_used_without_a_header_or_a_match() {
+        assert_eq!(locales().negotiate(None).locale, "en");
+        assert_eq!(negotiate("fr, de;q=0.5"), "en");
+        assert_eq!(negotiate("*"), "en");
+    }
+}
@@ -0,0 +1,53 @@
+//! Announces the console over mDNS, so it can be found on the local network without
+//! remembering which port it was given.
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+const SERVICE_TYPE: &str = "_http._tcp.local.";
+
+/// Announces the console as `{mdns_name}.local` when the `mdns_name` environment variable
+/// is set. The announcement lasts as long as the returned daemon is kept alive.
+pub fn announce_from_env(address: IpAddr, port: u16) -> Option<ServiceDaemon> {
+    let name = std::env::var("mdns_name").ok()?;
+    if address.is_loopback() {
+        eprintln!(
+            "Not announcing {name}.local over mDNS: the console only listens on {address}, so set `console_address` to make it reachable"
+        );
+        return None;
+    }
+
+    let announced = ServiceDaemon::new().and_then(|daemon| {
+        // An unspecified address listens everywhere, so announce every interface's.
+        let addresses = if address.is_unspecified() {
+            String::new()
+        } else {
+            address.to_string()
+        };
+        let properties: Option<HashMap<String, String>> = None;
+        let mut service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &name,
+            &format!("{name}.local."),
+            addresses.as_str(),
+            port,
+            properties,
+        )?;
+        if address.is_unspecified() {
+            service = service.enable_addr_auto();
+        }
+        daemon.register(service)?;
+        Ok(daemon)
+    });
+    match announced {
+        Ok(daemon) => {
+            eprintln!("Announcing the console as http://{name}.local:{port} over mDNS");
+            Some(daemon)
+        }
+        Err(error) => {
+            eprintln!("Failed to announce {name}.local over mDNS: {error}");
+            None
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Prometheus metrics, for alerting on apps that have stopped deploying successfully.
+
+use crate::namespaces::Scope;
+use crate::rejections::Rejections;
+use crate::{Job, Jobs};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Default)]
+struct AppMetrics {
+    last_status: Option<i32>,
+    last_success: Option<SystemTime>,
+}
+
+/// When the job finished, going by its timeline.
+fn finished_at(job: &Job) -> Option<SystemTime> {
+    let result = job.result.borrow();
+    result
+        .timeline
+        .iter()
+        .find(|event| event.event == "finished")
+        .map(|event| job.received_at + event.elapsed)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders the metrics of the jobs in the scope in the Prometheus text format. Refused
+/// requests are for no app in particular, so are only counted for operators.
+pub async fn render(jobs: &Jobs, rejections: &Rejections, scope: &Scope) -> String {
+    let mut apps = BTreeMap::<(String, String), AppMetrics>::new();
+    let mut failures = BTreeMap::<(String, String, String), usize>::new();
+    for job in jobs
+        .list
+        .read()
+        .await
+        .iter()
+        .filter(|job| !job.superseded() && scope.includes(job.namespace.as_deref()))
+    {
+        let (status, category) = {
+            let result = job.result.borrow();
+            match result.status {
+                Some(status) => (status, result.category.clone()),
+                None => continue,
+            }
+        };
+        let key = (job.app.clone(), job.environment.clone().unwrap_or_default());
+        if status != 0 {
+            let (app, environment) = key.clone();
+            *failures
+                .entry((app, environment, category.unwrap_or_default()))
+                .or_default() += 1;
+        }
+        let metrics = apps.entry(key).or_default();
+        metrics.last_status = Some(status);
+        if status == 0 {
+            metrics.last_success = finished_at(job).or(Some(job.received_at));
+        }
+    }
+
+    let mut output = String::new();
+    gauge(
+        &mut output,
+        "deploy_last_status",
+        "Exit status of the latest finished deploy.",
+        apps.iter()
+            .filter_map(|(labels, metrics)| Some((labels, metrics.last_status?.to_string()))),
+    );
+    gauge(
+        &mut output,
+        "deploy_last_success_timestamp_seconds",
+        "When the latest successful deploy finished.",
+        apps.iter().filter_map(|(labels, metrics)| {
+            let seconds = metrics
+                .last_success?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            Some((labels, format!("{seconds:.3}")))
+        }),
+    );
+    writeln!(
+        output,
+        "# HELP deploy_failures Failed deploys among the jobs kept, by category."
+    )
+    .unwrap();
+    writeln!(output, "# TYPE deploy_failures gauge").unwrap();
+    for ((app, environment, category), count) in failures {
+        writeln!(
+            output,
+            "deploy_failures{{app=\"{}\",environment=\"{}\",category=\"{}\"}} {count}",
+            escape(&app),
+            escape(&environment),
+            escape(&category)
+        )
+        .unwrap();
+    }
+    writeln!(
+        output,
+        "# HELP deploy_rejections_total Requests refused, by reason, endpoint and source."
+    )
+    .unwrap();
+    writeln!(output, "# TYPE deploy_rejections_total counter").unwrap();
+    let rejections = match scope {
+        Scope::All => rejections.counts(),
+        Scope::Namespace(..) => vec![],
+    };
+    for ((reason, endpoint, source), count) in rejections {
+        writeln!(
+            output,
+            "deploy_rejections_total{{reason=\"{reason}\",endpoint=\"{}\",source=\"{}\"}} {count}",
+            escape(endpoint),
+            escape(&source)
+        )
+        .unwrap();
+    }
+    output
+}
+
+fn gauge<'a>(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a (String, String), String)>,
+) {
+    writeln!(output, "# HELP {name} {help}").unwrap();
+    writeln!(output, "# TYPE {name} gauge").unwrap();
+    for ((app, environment), value) in values {
+        writeln!(
+            output,
+            "{name}{{app=\"{}\",environment=\"{}\"}} {value}",
+            escape(app),
+            escape(environment)
+        )
+        .unwrap();
+    }
+}
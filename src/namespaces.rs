@@ -0,0 +1,132 @@
+//! Namespaces, so that one server can deploy the apps of teams that must not see or trigger
+//! each other's. An app is in the namespace its settings name. API tokens are put in one
+//! by `token_namespaces`, and console users by `user_namespaces`, both comma separated
+//! `name=namespace` pairs.
+//!
+//! Tokens and users in a namespace may only deploy, see and act on the jobs of its apps.
+//! Those in none are the server's operators, who may deploy and see every app, and are
+//! the only ones who see refused deliveries and requests, which cannot be told apart by
+//! namespace.
+
+use std::collections::HashMap;
+
+/// Which apps someone may deploy, and whose jobs they may see and act on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scope {
+    All,
+    Namespace(String),
+}
+
+impl Scope {
+    /// Whether the scope includes apps in `namespace`, or in none when `None`.
+    pub fn includes(&self, namespace: Option<&str>) -> bool {
+        match self {
+            Self::All => true,
+            Self::Namespace(own) => namespace == Some(own.as_str()),
+        }
+    }
+}
+
+/// The namespaces of API tokens and console users, by name.
+#[derive(Default)]
+pub struct Namespaces {
+    tokens: HashMap<String, String>,
+    users: HashMap<String, String>,
+}
+
+impl Namespaces {
+    pub fn from_env() -> Self {
+        let read = |variable: &str| {
+            parse(&std::env::var(variable).unwrap_or_default()).unwrap_or_else(|error| {
+                panic!(
+                    "`{}` environment variable must be a list of `name=namespace` pairs: {}",
+                    variable, error
+                )
+            })
+        };
+        Self {
+            tokens: read("token_namespaces"),
+            users: read("user_namespaces"),
+        }
+    }
+
+    /// Whether any console users are in a namespace, which needs the console to know who
+    /// they are.
+    pub fn has_users(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// The scope of the API token with this name.
+    pub fn of_token(&self, token: &str) -> Scope {
+        scope(self.tokens.get(token))
+    }
+
+    /// The scope of a console user, who is anyone when the console lets everyone in.
+    pub fn of_user(&self, user: Option<&str>) -> Scope {
+        scope(user.and_then(|user| self.users.get(user)))
+    }
+}
+
+fn scope(namespace: Option<&String>) -> Scope {
+    namespace.map_or(Scope::All, |namespace| Scope::Namespace(namespace.clone()))
+}
+
+fn parse(pairs: &str) -> Result<HashMap<String, String>, String> {
+    pairs
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, namespace)) if !name.is_empty() && !namespace.is_empty() => {
+                Ok((name.trim().to_owned(), namespace.trim().to_owned()))
+            }
+            _ => Err(format!("invalid pair `{pair}`")),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_include_their_own_namespace_only() {
+        let team = Scope::Namespace("team".to_owned());
+        assert!(team.includes(Some("team")));
+        assert!(!team.includes(Some("other")));
+        assert!(!team.includes(None));
+        assert!(Scope::All.includes(Some("team")));
+        assert!(Scope::All.includes(None));
+    }
+
+    #[test]
+    fn tokens_and_users_without_a_namespace_see_everything() {
+        let namespaces = Namespaces {
+            tokens: parse("ci=team, ops-ci = ops").unwrap(),
+            users: parse("alice=team").unwrap(),
+        };
+        assert_eq!(
+            namespaces.of_token("ci"),
+            Scope::Namespace("team".to_owned())
+        );
+        assert_eq!(
+            namespaces.of_token("ops-ci"),
+            Scope::Namespace("ops".to_owned())
+        );
+        assert_eq!(namespaces.of_token("default"), Scope::All);
+        assert_eq!(
+            namespaces.of_user(Some("alice")),
+            Scope::Namespace("team".to_owned())
+        );
+        assert_eq!(namespaces.of_user(Some("bob")), Scope::All);
+        assert_eq!(namespaces.of_user(None), Scope::All);
+    }
+
+    #[test]
+    fn pairs_need_a_name_and_namespace() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("ci").is_err());
+        assert!(parse("ci=").is_err());
+        assert!(parse("=team").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! A runner that submits a Nomad job and waits for its deployment to become healthy.
+
+use crate::outbound;
+use crate::{JobWriter, OutputLine};
+use reqwest::{Method, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Jobs that never create a deployment, such as batch jobs, are considered deployed once
+/// they have been registered for this long.
+const DEPLOYMENT_GRACE: Duration = Duration::from_secs(30);
+
+/// Client for the Nomad HTTP API at `nomad_addr`, authenticated with `nomad_token`.
+pub struct Nomad {
+    client: reqwest::Client,
+    address: String,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Registration {
+    job_modify_index: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Deployment {
+    #[serde(rename = "ID")]
+    id: String,
+    job_spec_modify_index: u64,
+    status: String,
+    status_description: String,
+    #[serde(default)]
+    task_groups: BTreeMap<String, TaskGroupState>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TaskGroupState {
+    desired_total: u64,
+    placed_allocs: u64,
+    healthy_allocs: u64,
+    unhealthy_allocs: u64,
+}
+
+impl Deployment {
+    fn describe(&self) -> String {
+        let groups: Vec<_> = self
+            .task_groups
+            .iter()
+            .map(|(name, group)| {
+                format!(
+                    "{name}: {}/{} placed, {} healthy, {} unhealthy",
+                    group.placed_allocs,
+                    group.desired_total,
+                    group.healthy_allocs,
+                    group.unhealthy_allocs
+                )
+            })
+            .collect();
+        format!(
+            "Deployment {} {}: {} ({})",
+            self.id,
+            self.status,
+            self.status_description,
+            groups.join("; ")
+        )
+    }
+}
+
+impl Nomad {
+    pub fn from_env() -> Self {
+        Self {
+            client: outbound::client("nomad"),
+            address: std::env::var("nomad_addr")
+                .map(|address| address.trim_end_matches('/').to_owned())
+                .unwrap_or_else(|_| "http://127.0.0.1:4646".to_owned()),
+            token: std::env::var("nomad_token").ok(),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{path}", self.address));
+        match &self.token {
+            Some(token) => request.header("X-Nomad-Token", token),
+            None => request,
+        }
+    }
+
+    async fn register(&self, spec: &Path) -> Result<(String, u64), String> {
+        let spec = std::fs::read_to_string(spec)
+            .map_err(|error| format!("Failed to read {}: {error}", spec.display()))?;
+        let spec: Value = serde_json::from_str(&spec)
+            .map_err(|error| format!("Invalid Nomad job specification: {error}"))?;
+        let spec = match spec.get("Job") {
+            Some(..) => spec,
+            None => json!({ "Job": spec }),
+        };
+        let id = spec["Job"]["ID"]
+            .as_str()
+            .ok_or("The Nomad job specification has no `ID`")?
+            .to_owned();
+
+        let registration: Registration = self
+            .request(Method::POST, "/v1/jobs")
+            .json(&spec)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| format!("Failed to register Nomad job {id}: {error}"))?
+            .json()
+            .await
+            .map_err(|error| format!("Failed to register Nomad job {id}: {error}"))?;
+        Ok((id, registration.job_modify_index))
+    }
+
+    async fn latest_deployment(&self, id: &str) -> Result<Option<Deployment>, String> {
+        self.request(Method::GET, &format!("/v1/job/{id}/deployment"))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| format!("Failed to check Nomad deployment: {error}"))?
+            .json()
+            .await
+            .map_err(|error| format!("Failed to check Nomad deployment: {error}"))
+    }
+
+    async fn watch(
+        &self,
+        writer: &JobWriter,
+        id: &str,
+        modify_index: u64,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        let started = Instant::now();
+        let mut last = String::new();
+        loop {
+            match self.latest_deployment(id).await? {
+                Some(deployment) if deployment.job_spec_modify_index >= modify_index => {
+                    let description = deployment.describe();
+                    if description != last {
+                        writer.push(OutputLine::Stdout(description.clone()));
+                        last = description;
+                    }
+                    match deployment.status.as_str() {
+                        "successful" => return Ok(()),
+                        "failed" | "cancelled" => {
+                            return Err(format!(
+                                "Deployment {}: {}",
+                                deployment.status, deployment.status_description
+                            ))
+                        }
+                        _ => {}
+                    }
+                }
+                _ if started.elapsed() > DEPLOYMENT_GRACE && last.is_empty() => {
+                    writer.push(OutputLine::Stdout(format!(
+                        "Nomad job {id} has no deployment to wait for"
+                    )));
+                    return Ok(());
+                }
+                _ => {}
+            }
+            if started.elapsed() > timeout {
+                return Err(format!("Timed out waiting for Nomad job {id} to deploy"));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Submits the job specification at `spec` and follows its deployment until it is
+    /// healthy or fails. Returns the job's exit status.
+    pub async fn deploy(&self, writer: &JobWriter, spec: &Path, timeout: Duration) -> i32 {
+        let (id, modify_index) = match self.register(spec).await {
+            Ok(registration) => registration,
+            Err(error) => {
+                writer.push(OutputLine::Stderr(error));
+                return 255;
+            }
+        };
+        writer.push(OutputLine::Stdout(format!(
+            "Registered Nomad job {id} at index {modify_index}"
+        )));
+        match self.watch(writer, &id, modify_index, timeout).await {
+            Ok(()) => 0,
+            Err(error) => {
+                writer.push(OutputLine::Stderr(error));
+                1
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use futures::{Stream, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::io::Read;
+use warp::{reject, Filter, Rejection};
+
+/// Decompressed bodies larger than this are refused, however small they were compressed.
+const MAX_DECODED: u64 = 25 * 1024 * 1024;
+
+/// The request body is compressed in a way the server does not understand.
+#[derive(Debug)]
+pub struct UnsupportedEncoding;
+impl reject::Reject for UnsupportedEncoding {}
+
+/// The request body is corrupt, too large once decompressed, or not what was expected.
+#[derive(Debug)]
+pub struct UndecodableBody;
+impl reject::Reject for UndecodableBody {}
+
+/// The request body is longer than the route accepts.
+#[derive(Debug)]
+pub struct BodyTooLarge;
+impl reject::Reject for BodyTooLarge {}
+
+/// Decompresses a request body according to its `Content-Encoding`. Signatures are
+/// always checked against the body as it was received, before this.
+pub fn decode<'a>(encoding: Option<&str>, body: &'a [u8]) -> Result<Cow<'a, [u8]>, Rejection> {
+    let reader: Box<dyn Read + 'a> = match encoding.map(str::trim) {
+        None | Some("") | Some("identity") => return Ok(Cow::Borrowed(body)),
+        Some("gzip") | Some("x-gzip") => Box::new(MultiGzDecoder::new(body)),
+        Some("deflate") => Box::new(ZlibDecoder::new(body)),
+        Some(..) => return Err(reject::custom(UnsupportedEncoding)),
+    };
+    let mut decoded = vec![];
+    reader
+        .take(MAX_DECODED + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|_| reject::custom(UndecodableBody))?;
+    if decoded.len() as u64 > MAX_DECODED {
+        return Err(reject::custom(UndecodableBody));
+    }
+    Ok(Cow::Owned(decoded))
+}
+
+/// Decodes a JSON request body that has already been read, which may be compressed.
+pub fn from_json<T: DeserializeOwned>(encoding: Option<&str>, body: &[u8]) -> Result<T, Rejection> {
+    let body = decode(encoding, body)?;
+    serde_json::from_slice(&body).map_err(|_| reject::custom(UndecodableBody))
+}
+
+/// The request body as it was received, if it is no longer than `limit`. Unlike warp's
+/// `content_length_limit`, this accepts requests that do not give a length, such as those
+/// without a body at all.
+pub fn bytes(limit: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::body::stream().and_then(move |stream| read_up_to(stream, limit))
+}
+
+async fn read_up_to<B: Buf>(
+    stream: impl Stream<Item = Result<B, warp::Error>>,
+    limit: u64,
+) -> Result<Bytes, Rejection> {
+    let mut stream = Box::pin(stream);
+    let mut body = BytesMut::new();
+    while let Some(chunk) = stream
+        .try_next()
+        .await
+        .map_err(|_| reject::custom(UndecodableBody))?
+    {
+        if (body.len() + chunk.remaining()) as u64 > limit {
+            return Err(reject::custom(BodyTooLarge));
+        }
+        body.put(chunk);
+    }
+    Ok(body.freeze())
+}
+
+/// The parts of a webhook payload the server cares about. GitHub, Gitea and Bitbucket
+/// describe the source as `repository`, GitLab as `project`.
+#[derive(Default, Deserialize)]
+pub struct Payload {
+    repository: Option<Repository>,
+    project: Option<Project>,
+    /// The ref a push updated, such as `refs/heads/main` or `refs/tags/v1.0.0`.
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+    /// The commit a push moved the ref to.
+    after: Option<String>,
+    /// GitLab's name for the commit to check out, which differs from `after` when a
+    /// push deletes a branch.
+    checkout_sha: Option<String>,
+    /// Bitbucket's description of a push, in place of `ref` and `after`.
+    push: Option<Push>,
+    /// Who pushed, as GitHub and Gitea describe them.
+    pusher: Option<Pusher>,
+    /// GitLab's name for who pushed.
+    user_username: Option<String>,
+    /// Who pushed, as Bitbucket describes them.
+    actor: Option<Actor>,
+    /// GitHub's description of a release, whose tag stands in for `ref`.
+    release: Option<Release>,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    full_name: String,
+    #[serde(default)]
+    fork: bool,
+}
+
+#[derive(Deserialize)]
+struct Project {
+    path_with_namespace: String,
+}
+
+/// GitHub names the pusher by `name`, Gitea by `login` or `username`.
+#[derive(Deserialize)]
+struct Pusher {
+    name: Option<String>,
+    login: Option<String>,
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Actor {
+    nickname: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+#[derive(Deserialize)]
+struct Push {
+    changes: Vec<Change>,
+}
+
+#[derive(Deserialize)]
+struct Change {
+    /// Where the ref ended up, or `None` if the push deleted it.
+    new: Option<RefState>,
+}
+
+#[derive(Deserialize)]
+struct RefState {
+    /// `branch` or `tag`.
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    target: Target,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    hash: String,
+}
+
+impl Payload {
+    /// Parses a webhook payload. Payloads that are not JSON, or not in a recognized shape,
+    /// are treated as describing nothing.
+    pub fn parse(body: &[u8]) -> Self {
+        let mut payload: Self = serde_json::from_slice(body).unwrap_or_default();
+        // Bitbucket pushes are described in the shape GitHub uses, by their first change.
+        let change = payload
+            .push
+            .take()
+            .and_then(|push| push.changes.into_iter().find_map(|change| change.new));
+        if let Some(change) = change {
+            let namespace = match change.kind.as_str() {
+                "tag" => "tags",
+                _ => "heads",
+            };
+            payload
+                .reference
+                .get_or_insert(format!("refs/{namespace}/{}", change.name));
+            payload.after.get_or_insert(change.target.hash);
+        }
+        if let Some(release) = payload.release.take() {
+            payload
+                .reference
+                .get_or_insert(format!("refs/tags/{}", release.tag_name));
+        }
+        payload
+    }
+
+    /// The `owner/name` of the repository the delivery came from.
+    pub fn repository(&self) -> Option<&str> {
+        self.repository
+            .as_ref()
+            .map(|repository| repository.full_name.as_str())
+            .or_else(|| {
+                self.project
+                    .as_ref()
+                    .map(|project| project.path_with_namespace.as_str())
+            })
+    }
+
+    /// The commit that should be deployed for this delivery.
+    pub fn sha(&self) -> Option<&str> {
+        self.checkout_sha.as_deref().or(self.after.as_deref())
+    }
+
+    /// The name of whoever pushed the commit.
+    pub fn pusher(&self) -> Option<&str> {
+        self.pusher
+            .as_ref()
+            .and_then(|pusher| {
+                pusher
+                    .login
+                    .as_deref()
+                    .or(pusher.username.as_deref())
+                    .or(pusher.name.as_deref())
+            })
+            .or(self.user_username.as_deref())
+            .or_else(|| self.actor.as_ref()?.nickname.as_deref())
+    }
+
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    pub fn is_fork(&self) -> bool {
+        self.repository
+            .as_ref()
+            .is_some_and(|repository| repository.fork)
+    }
+}
@@ -0,0 +1,139 @@
+//! Limits how many deploys run at once, handing out turns round-robin across apps, so
+//! that an app that is triggered over and over cannot keep the others waiting.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+pub struct Permits {
+    state: Mutex<State>,
+}
+
+struct State {
+    available: usize,
+    /// Each app with deploys waiting for a permit, in the order they get their next one,
+    /// with its deploys in the order they asked.
+    waiting: VecDeque<(String, VecDeque<oneshot::Sender<()>>)>,
+}
+
+/// Lets another deploy run once it is dropped.
+pub struct Permit<'a>(&'a Permits);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A deploy waiting for a permit, which passes it on if it gives up after being handed
+/// one.
+struct Waiting<'a> {
+    permits: &'a Permits,
+    /// Taken once the permit has been received.
+    turn: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(mut turn) = self.turn.take() {
+            turn.close();
+            if turn.try_recv().is_ok() {
+                self.permits.release();
+            }
+        }
+    }
+}
+
+impl Permits {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                available: limit,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().available
+    }
+
+    /// Waits for a permit for a deploy of `app`, after those of every other app waiting
+    /// before it has had one.
+    pub async fn acquire(&self, app: &str) -> Permit<'_> {
+        let turn = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 && state.waiting.is_empty() {
+                state.available -= 1;
+                return Permit(self);
+            }
+            let (sender, turn) = oneshot::channel();
+            match state.waiting.iter_mut().find(|(waiting, _)| waiting == app) {
+                Some((_, deploys)) => deploys.push_back(sender),
+                None => state
+                    .waiting
+                    .push_back((app.to_owned(), VecDeque::from([sender]))),
+            }
+            turn
+        };
+        let mut waiting = Waiting {
+            permits: self,
+            turn: Some(turn),
+        };
+        // The sender is only dropped after sending, so this always receives.
+        if let Some(turn) = &mut waiting.turn {
+            turn.await.ok();
+        }
+        waiting.turn = None;
+        Permit(self)
+    }
+
+    /// Hands the permit to the next app's first waiting deploy, moving the app to the
+    /// back of the line, or makes it available if none is waiting.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some((app, mut deploys)) = state.waiting.pop_front() {
+            let sender = match deploys.pop_front() {
+                Some(sender) => sender,
+                None => continue,
+            };
+            if !deploys.is_empty() {
+                state.waiting.push_back((app, deploys));
+            }
+            // Deploys that gave up waiting are skipped.
+            if sender.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn permits_go_round_robin_across_apps() {
+        let permits = Permits::new(1);
+        let first = permits.acquire("flood").await;
+        let mut waiting: Vec<_> = ["flood", "flood", "flood", "other"]
+            .iter()
+            .map(|app| Box::pin(permits.acquire(app)))
+            .collect();
+        for deploy in &mut waiting {
+            assert!(deploy.as_mut().now_or_never().is_none());
+        }
+        drop(first);
+        // The first flood deploy asked first, and then the other app gets its turn.
+        let permit = waiting[0].as_mut().now_or_never().unwrap();
+        assert!(waiting[3].as_mut().now_or_never().is_none());
+        drop(permit);
+        let permit = waiting[3].as_mut().now_or_never().unwrap();
+        assert!(waiting[1].as_mut().now_or_never().is_none());
+        drop(permit);
+        assert!(waiting[1].as_mut().now_or_never().is_some());
+        assert_eq!(permits.available(), 0);
+    }
+}
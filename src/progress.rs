@@ -0,0 +1,45 @@
+//! Follows a job's progress as it runs.
+
+use crate::{Job, JobResult};
+use deploy_server_types::{JobProgress, JobState};
+use futures::stream::{self, Stream};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Every line of the job's output, the ones already written first, followed by the job
+/// itself once it has finished.
+pub fn job_events(job: Arc<Job>) -> impl Stream<Item = JobProgress> {
+    let result = job.result.clone();
+    stream::unfold(Some((job, result, 0)), |state| async move {
+        let (job, mut result, sent): (Arc<Job>, watch::Receiver<JobResult>, usize) = state?;
+        loop {
+            let (line, finished) = {
+                let current = result.borrow_and_update();
+                // Lines left out to keep the output within budget are skipped.
+                let line = (sent..current.written())
+                    .find_map(|index| Some((index, current.written_line(index)?)));
+                (line, current.status.is_some())
+            };
+            if let Some((index, line)) = line {
+                return Some((JobProgress::Line { line }, Some((job, result, index + 1))));
+            }
+            if finished {
+                // `summary` borrows the result too, so it is only called once the borrow
+                // above has ended.
+                let job = job.summary();
+                let state = JobState::of(&job);
+                return Some((
+                    JobProgress::Finished {
+                        job: Box::new(job),
+                        state,
+                    },
+                    None,
+                ));
+            }
+            // The writer is gone, so nothing more will be written.
+            if result.changed().await.is_err() {
+                return None;
+            }
+        }
+    })
+}
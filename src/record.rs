@@ -0,0 +1,60 @@
+use serde_json::{Map, Value};
+
+const MESSAGE_KEYS: &[&str] = &["message", "msg"];
+const LEVEL_KEYS: &[&str] = &["level", "severity", "lvl"];
+
+/// A JSON-lines log record emitted by a deploy script.
+#[derive(Clone)]
+pub struct LogRecord {
+    pub stream: &'static str,
+    pub level: Option<String>,
+    pub message: String,
+    pub fields: Vec<LogField>,
+}
+
+#[derive(Clone)]
+pub struct LogField {
+    pub key: String,
+    pub value: String,
+}
+
+fn take_string(object: &mut Map<String, Value>, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .find_map(|key| object.remove(*key))
+        .map(|value| match value {
+            Value::String(string) => string,
+            value => value.to_string(),
+        })
+}
+
+impl LogRecord {
+    /// Parses a line as a log record. Only JSON objects with a message are treated as
+    /// records; anything else is left as plain output.
+    pub fn parse(line: &str, stream: &'static str) -> Option<Self> {
+        if !line.trim_start().starts_with('{') {
+            return None;
+        }
+        let mut object = match serde_json::from_str(line) {
+            Ok(Value::Object(object)) => object,
+            _ => return None,
+        };
+        let message = take_string(&mut object, MESSAGE_KEYS)?;
+        let level = take_string(&mut object, LEVEL_KEYS).map(|level| level.to_lowercase());
+        let fields = object
+            .into_iter()
+            .map(|(key, value)| LogField {
+                key,
+                value: match value {
+                    Value::String(string) => string,
+                    value => value.to_string(),
+                },
+            })
+            .collect();
+        Some(Self {
+            stream,
+            level,
+            message,
+            fields,
+        })
+    }
+}
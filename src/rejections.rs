@@ -0,0 +1,117 @@
+//! Counts of refused requests, by why they were refused, where they were sent and who sent
+//! them, to tell someone probing the server from a sender that is misconfigured.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Requests from more sources than this are counted together under `other`, so that a
+/// probe from many addresses cannot grow the counts without bound.
+const MAX_COUNTED: usize = 1024;
+
+/// The first segments of the paths the server has routes under. Requests to any other
+/// path are counted under `/other`, as the client chooses the path.
+const ENDPOINTS: &[&str] = &[
+    "/",
+    "/admin",
+    "/api",
+    "/auth",
+    "/deploy",
+    "/deploy2",
+    "/metrics",
+    "/post-receive",
+    "/ws",
+];
+
+/// Why a request was refused, attached to its response by `handle_rejection`.
+#[derive(Clone, Copy)]
+pub struct Rejected(pub &'static str);
+
+/// How many requests were refused, by reason, endpoint and source.
+#[derive(Default)]
+pub struct Rejections {
+    counts: Mutex<BTreeMap<(&'static str, &'static str, String), u64>>,
+}
+
+impl Rejections {
+    pub fn record(&self, reason: &'static str, endpoint: &'static str, source: String) {
+        let mut counts = self.counts.lock().unwrap();
+        let key = (reason, endpoint, source);
+        let key = if counts.len() < MAX_COUNTED || counts.contains_key(&key) {
+            key
+        } else {
+            (key.0, key.1, "other".to_owned())
+        };
+        *counts.entry(key).or_default() += 1;
+    }
+
+    /// Every count, in order.
+    pub fn counts(&self) -> Vec<((&'static str, &'static str, String), u64)> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect()
+    }
+}
+
+/// The endpoint a request was sent to, by the first segment of its path, which is all the
+/// routes need to be told apart.
+pub fn endpoint(path: &str) -> &'static str {
+    let segment = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    ENDPOINTS
+        .iter()
+        .find(|endpoint| endpoint[1..] == *segment)
+        .copied()
+        .unwrap_or("/other")
+}
+
+/// Who sent a request: if it came through a trusted proxy, the last address in
+/// `X-Forwarded-For` that is not another trusted proxy, as those before it are whatever
+/// the client said. Otherwise, the address it came from.
+pub fn source(remote: Option<IpAddr>, forwarded_for: Option<&str>, trusted: &[IpAddr]) -> String {
+    match (remote, forwarded_for) {
+        (Some(remote), Some(forwarded_for)) if trusted.contains(&remote) => forwarded_for
+            .rsplit(',')
+            .map(str::trim)
+            .find(|hop| {
+                hop.parse::<IpAddr>()
+                    .map_or(true, |hop| !trusted.contains(&hop))
+            })
+            .unwrap_or("unknown")
+            .to_owned(),
+        (Some(remote), _) => remote.to_string(),
+        (None, _) => "unknown".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_unknown_paths_together() {
+        assert_eq!(endpoint("/deploy2/app"), "/deploy2");
+        assert_eq!(endpoint("/"), "/");
+        assert_eq!(endpoint("/random1"), "/other");
+        assert_eq!(endpoint("/random2/deploy"), "/other");
+    }
+
+    #[test]
+    fn sources_are_the_last_untrusted_hop() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let inner: IpAddr = "10.0.0.2".parse().unwrap();
+        let trusted = [proxy, inner];
+        let forwarded = Some("1.1.1.1, 2.2.2.2, 10.0.0.2");
+        assert_eq!(source(Some(proxy), forwarded, &trusted), "2.2.2.2");
+        assert_eq!(
+            source(Some("3.3.3.3".parse().unwrap()), forwarded, &trusted),
+            "3.3.3.3"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Replication of jobs to a warm standby, so that deploy history survives the loss of the
+//! primary instance.
+
+use crate::outbound;
+use deploy_server_types::JobListing;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Header carrying the secret shared between the primary and the standby.
+pub const SECRET_HEADER: &str = "X-Replication-Secret";
+
+/// How long to wait to connect to the standby.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a push may take, which covers sending all of a job's output.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A standby instance to push jobs to.
+pub struct Replica {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+    /// Jobs waiting to be pushed, by a task started on the first push, since the
+    /// configuration is read before the runtime is available to start it on.
+    queue: Mutex<Option<mpsc::UnboundedSender<JobListing>>>,
+}
+
+impl Replica {
+    /// The standby at `replica_url`, which must be configured with the same
+    /// `replication_secret` as this instance, if one is set.
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("replica_url").ok()?;
+        let secret = std::env::var("replication_secret")
+            .expect("`replication_secret` environment variable must be set to use a replica");
+        Some(Self {
+            client: outbound::builder("replica")
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(PUSH_TIMEOUT)
+                .build()
+                .unwrap(),
+            url: url.trim_end_matches('/').to_owned(),
+            secret,
+            queue: Mutex::default(),
+        })
+    }
+
+    /// Queues a job, with all of its output, to be pushed to the standby. The standby
+    /// replaces any copy of the job it already has, so a job may be pushed again whenever
+    /// it changes. Jobs are pushed one at a time, in the order they were queued, so the
+    /// standby is left with the latest copy of each. Failures are logged, and never
+    /// affect the job itself, nor hold it up.
+    pub fn push(&self, job: JobListing) {
+        let mut queue = self.queue.lock().unwrap();
+        let sender = queue.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(push_queued(
+                receiver,
+                self.client.clone(),
+                format!("{}/api/replica/jobs", self.url),
+                self.secret.clone(),
+            ));
+            sender
+        });
+        sender.send(job).ok();
+    }
+}
+
+/// Pushes the queued jobs to the standby at `url`, one at a time.
+async fn push_queued(
+    mut queue: mpsc::UnboundedReceiver<JobListing>,
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+) {
+    while let Some(job) = queue.recv().await {
+        let response = client
+            .post(&url)
+            .header(SECRET_HEADER, &secret)
+            .json(&job)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(error) = response {
+            eprintln!("Failed to replicate job {}: {error}", job.job.id);
+        }
+    }
+}
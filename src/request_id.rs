@@ -0,0 +1,313 @@
+use crate::auth::basic::{self, Unauthenticated};
+use crate::auth::console::{self, LoginFailed, LoginRequired};
+use crate::auth::{InvalidSignature, UnauthorizedSender, UnexpectedRef};
+use crate::payload::{BodyTooLarge, UndecodableBody, UnsupportedEncoding};
+use crate::rejections::{self, Rejected, Rejections};
+use crate::{
+    AlreadyFinished, DeletedRef, Filtered, IgnoredEvent, IgnoredRef, InvalidApplication,
+    InvalidArtifact, InvalidLabels, InvalidSettings, NotFailed, ReadOnly, UnexpectedOrigin,
+    UnknownJob,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{reject, Filter, Rejection, Reply};
+
+pub const HEADER: &str = "X-Request-Id";
+
+/// Proxies whose `X-Request-Id` header is honored, from the comma separated
+/// `trusted_proxies` environment variable.
+pub fn trusted_proxies_from_env() -> Arc<Vec<IpAddr>> {
+    let proxies = std::env::var("trusted_proxies")
+        .map(|proxies| {
+            proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy.parse().expect(
+                        "`trusted_proxies` environment variable must be a list of IP addresses",
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Arc::new(proxies)
+}
+
+/// Extracts the ID of the current request: the incoming `X-Request-Id` if the request came
+/// from a trusted proxy, otherwise a newly generated one.
+fn request_id(
+    trusted: Arc<Vec<IpAddr>>,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(HEADER)
+        .and(warp::addr::remote())
+        .map(
+            move |header: Option<String>, remote: Option<SocketAddr>| match (header, remote) {
+                (Some(id), Some(remote)) if trusted.contains(&remote.ip()) => id,
+                _ => Uuid::new_v4().to_string(),
+            },
+        )
+}
+
+/// What `tag_response` needs to know about the request a response answers.
+pub struct Requested {
+    id: String,
+    endpoint: &'static str,
+    source: String,
+}
+
+impl Requested {
+    /// The request's ID, which jobs it triggers are tagged with.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Captures what `tag_response` needs to know about the request: its ID, and where it was
+/// sent and who sent it, in case it is refused.
+pub fn requested(
+    trusted: Arc<Vec<IpAddr>>,
+) -> impl Filter<Extract = (Requested,), Error = Rejection> + Clone {
+    request_id(trusted.clone())
+        .and(warp::path::full())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            move |id: String,
+                  path: FullPath,
+                  remote: Option<SocketAddr>,
+                  forwarded_for: Option<String>| Requested {
+                id,
+                endpoint: rejections::endpoint(path.as_str()),
+                source: rejections::source(
+                    remote.map(|remote| remote.ip()),
+                    forwarded_for.as_deref(),
+                    &trusted,
+                ),
+            },
+        )
+}
+
+/// Echoes the request ID on a response, and logs failed requests under it. Refused
+/// requests are counted, and logged with why and who from.
+pub fn tag_response(
+    requested: Requested,
+    reply: impl Reply,
+    rejections: Arc<Rejections>,
+) -> Response {
+    let mut response = reply.into_response();
+    if let Ok(value) = requested.id.parse() {
+        response.headers_mut().insert(HEADER, value);
+    }
+    let request_id = requested.id;
+    if let Some(Rejected(reason)) = response.extensions().get::<Rejected>().copied() {
+        eprintln!(
+            "[{request_id}] Refused {} from {} with {}: {reason}",
+            requested.endpoint,
+            requested.source,
+            response.status()
+        );
+        rejections.record(reason, requested.endpoint, requested.source);
+    } else if response.status().is_client_error() || response.status().is_server_error() {
+        eprintln!("[{request_id}] Responded with {}", response.status());
+    }
+    response
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    let (status, reason, message) = if rejection.find::<InvalidSignature>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "invalid_signature",
+            "Invalid signature",
+        )
+    } else if rejection.find::<Unauthenticated>().is_some() {
+        (
+            StatusCode::UNAUTHORIZED,
+            "unauthenticated",
+            "The console needs a username and password",
+        )
+    } else if rejection.find::<LoginRequired>().is_some() {
+        (
+            StatusCode::SEE_OTHER,
+            "login_required",
+            "The console needs you to log in",
+        )
+    } else if rejection.find::<LoginFailed>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "login_failed",
+            "Logging in failed, or is not allowed for this user",
+        )
+    } else if rejection.find::<UnauthorizedSender>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "unauthorized_sender",
+            "This token may not deploy this app",
+        )
+    } else if rejection.find::<UnexpectedRef>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "unexpected_ref",
+            "This token may only deploy the app's default branch",
+        )
+    } else if rejection.find::<InvalidApplication>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_app", "Unknown application")
+    } else if rejection.find::<UnknownJob>().is_some() {
+        (StatusCode::NOT_FOUND, "unknown_job", "Unknown job")
+    } else if rejection.find::<InvalidArtifact>().is_some() {
+        (
+            StatusCode::BAD_REQUEST,
+            "invalid_artifact",
+            "Artifact downloads need `repository`, `run_id` and `artifact`",
+        )
+    } else if rejection.find::<InvalidLabels>().is_some() {
+        (
+            StatusCode::BAD_REQUEST,
+            "invalid_labels",
+            "Labels must be comma separated `name=value` pairs",
+        )
+    } else if rejection.find::<UnexpectedOrigin>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "unexpected_origin",
+            "Deliveries from this repository may not deploy this app",
+        )
+    } else if let Some(IgnoredRef(status)) = rejection.find::<IgnoredRef>() {
+        (
+            *status,
+            "ignored_ref",
+            "Ignored: the app does not deploy pushes to this ref",
+        )
+    } else if let Some(IgnoredEvent(status)) = rejection.find::<IgnoredEvent>() {
+        (
+            *status,
+            "ignored_event",
+            "Ignored: the app does not deploy this event",
+        )
+    } else if let Some(Filtered(status)) = rejection.find::<Filtered>() {
+        (
+            *status,
+            "filtered",
+            "Ignored: the app's filter script skipped this delivery",
+        )
+    } else if rejection.find::<DeletedRef>().is_some() {
+        (
+            StatusCode::BAD_REQUEST,
+            "deleted_ref",
+            "Deleting a ref does not deploy anything",
+        )
+    } else if rejection.find::<UnsupportedEncoding>().is_some() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_encoding",
+            "Bodies may only be compressed with gzip or deflate",
+        )
+    } else if rejection.find::<UndecodableBody>().is_some() {
+        (
+            StatusCode::BAD_REQUEST,
+            "undecodable_body",
+            "The request body could not be decoded",
+        )
+    } else if rejection.find::<reject::PayloadTooLarge>().is_some()
+        || rejection.find::<BodyTooLarge>().is_some()
+    {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+            "The request body is too large",
+        )
+    } else if rejection.find::<AlreadyFinished>().is_some() {
+        (
+            StatusCode::CONFLICT,
+            "already_finished",
+            "The job has already finished",
+        )
+    } else if rejection.find::<NotFailed>().is_some() {
+        (
+            StatusCode::CONFLICT,
+            "not_failed",
+            "Only failed jobs can be acknowledged",
+        )
+    } else if rejection.find::<ReadOnly>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "read_only",
+            "This server is a read-only mirror",
+        )
+    } else if rejection.find::<InvalidSettings>().is_some() {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "invalid_settings",
+            "Invalid app settings",
+        )
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "Not found")
+    } else {
+        return Err(rejection);
+    };
+    let mut response = warp::reply::with_status(message, status).into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        let challenge = format!("Basic realm=\"{}\"", basic::REALM);
+        response.headers_mut().insert(
+            warp::http::header::WWW_AUTHENTICATE,
+            challenge.parse().unwrap(),
+        );
+    } else if status == StatusCode::SEE_OTHER {
+        response.headers_mut().insert(
+            warp::http::header::LOCATION,
+            console::LOGIN_PATH.parse().unwrap(),
+        );
+    }
+    // Ignored deliveries and paths nothing is served under are not refusals, so they are
+    // neither logged nor counted as one.
+    if !matches!(
+        reason,
+        "ignored_ref" | "ignored_event" | "filtered" | "not_found"
+    ) {
+        response.extensions_mut().insert(Rejected(reason));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn rejected(rejection: Rejection) -> Option<&'static str> {
+        let response = handle_rejection(rejection).await.unwrap();
+        response
+            .extensions()
+            .get::<Rejected>()
+            .map(|Rejected(reason)| *reason)
+    }
+
+    #[tokio::test]
+    async fn refusals_are_marked_as_rejected() {
+        assert_eq!(
+            rejected(reject::custom(InvalidSignature)).await,
+            Some("invalid_signature")
+        );
+    }
+
+    #[tokio::test]
+    async fn ignored_deliveries_and_unknown_paths_are_not_rejected() {
+        assert_eq!(
+            rejected(reject::custom(IgnoredRef(StatusCode::ACCEPTED))).await,
+            None
+        );
+        assert_eq!(
+            rejected(reject::custom(IgnoredEvent(StatusCode::ACCEPTED))).await,
+            None
+        );
+        assert_eq!(
+            rejected(reject::custom(Filtered(StatusCode::ACCEPTED))).await,
+            None
+        );
+        assert_eq!(rejected(reject::not_found()).await, None);
+    }
+}
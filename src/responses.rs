@@ -0,0 +1,56 @@
+use crate::timezone::DisplayTimezone;
+use deploy_server_types::TriggerResponse;
+use std::time::SystemTime;
+use uuid::Uuid;
+use warp::reply::Response;
+use warp::Reply;
+
+/// How trigger endpoints and notifications describe the job they created.
+pub struct TriggerResponses {
+    plain: bool,
+    public_url: String,
+    timezone: DisplayTimezone,
+}
+
+impl TriggerResponses {
+    /// Trigger endpoints reply with JSON unless `trigger_response` is `plain`. Status URLs
+    /// are relative unless `public_url` says where the console is served from.
+    pub fn from_env(timezone: DisplayTimezone) -> Self {
+        let plain = match std::env::var("trigger_response").as_deref() {
+            Ok("plain") => true,
+            Ok("json") | Err(..) => false,
+            Ok(..) => panic!("`trigger_response` environment variable must be `json` or `plain`"),
+        };
+        Self {
+            plain,
+            public_url: std::env::var("public_url")
+                .map(|url| url.trim_end_matches('/').to_owned())
+                .unwrap_or_default(),
+            timezone,
+        }
+    }
+
+    /// A time, as notifications show it.
+    pub fn time(&self, time: SystemTime) -> String {
+        self.timezone.format(time)
+    }
+
+    /// Where the console shows a job.
+    pub fn job_url(&self, job_id: Uuid) -> String {
+        format!("{}/#{job_id}", self.public_url)
+    }
+
+    pub fn reply(&self, job_id: Uuid, status: &'static str, queue_position: usize) -> Response {
+        if self.plain {
+            warp::reply::reply().into_response()
+        } else {
+            warp::reply::json(&TriggerResponse {
+                job_id,
+                status: status.to_owned(),
+                status_url: format!("{}/api/jobs/{job_id}", self.public_url),
+                queue_position,
+            })
+            .into_response()
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Forgetting old jobs, so that a long-running server does not keep every job's output in
+//! memory forever. Jobs in the job store are kept there regardless.
+
+use crate::{Job, Jobs};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// How often old jobs are looked for.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Which finished jobs to keep: at most `max_jobs` of them, none older than `max_age`.
+/// Jobs that are still running, and each app's latest successful job, are always kept.
+#[derive(Clone, Copy, Default)]
+pub struct Retention {
+    max_jobs: Option<usize>,
+    max_age: Option<Duration>,
+}
+
+impl Retention {
+    /// Reads `max_jobs`, and `max_job_age` in days. Jobs are kept forever without either.
+    pub fn from_env() -> Self {
+        Self {
+            max_jobs: std::env::var("max_jobs").ok().map(|max| {
+                max.parse()
+                    .expect("`max_jobs` environment variable must be a number")
+            }),
+            max_age: std::env::var("max_job_age").ok().map(|days| {
+                let days: u64 = days
+                    .parse()
+                    .expect("`max_job_age` environment variable must be a number of days");
+                Duration::from_secs(days.saturating_mul(24 * 60 * 60))
+            }),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.max_jobs.is_none() && self.max_age.is_none()
+    }
+
+    /// Removes the jobs that are not to be kept, returning how many were removed.
+    pub async fn prune(&self, jobs: &Jobs) -> usize {
+        let mut jobs = jobs.list.write().await;
+        let protected = protected(&jobs);
+        let now = SystemTime::now();
+        let finished = jobs
+            .iter()
+            .filter(|job| !protected.contains(&job.id))
+            .count();
+        let mut excess = self
+            .max_jobs
+            .map_or(0, |max_jobs| finished.saturating_sub(max_jobs));
+        let before = jobs.len();
+        // Jobs are in the order they were received, so the oldest go first.
+        jobs.retain(|job| {
+            if protected.contains(&job.id) {
+                return true;
+            }
+            let age = now.duration_since(job.received_at).unwrap_or_default();
+            if excess > 0 || self.max_age.is_some_and(|max_age| age > max_age) {
+                excess = excess.saturating_sub(1);
+                return false;
+            }
+            true
+        });
+        before - jobs.len()
+    }
+
+    /// Prunes jobs every so often, forever.
+    pub async fn run(self, jobs: Jobs) {
+        if self.is_unlimited() {
+            return;
+        }
+        let mut interval = tokio::time::interval(INTERVAL);
+        loop {
+            interval.tick().await;
+            let pruned = self.prune(&jobs).await;
+            if pruned > 0 {
+                eprintln!("Forgot {pruned} old jobs");
+            }
+        }
+    }
+}
+
+/// The jobs that are never pruned: those still running, and the latest successful job of
+/// each app and environment, which says what is deployed.
+fn protected(jobs: &[Arc<Job>]) -> HashSet<Uuid> {
+    let mut protected = HashSet::new();
+    let mut deployed = HashSet::new();
+    for job in jobs.iter().rev() {
+        let status = job.result.borrow().status;
+        match status {
+            None => {
+                protected.insert(job.id);
+            }
+            Some(0) if !job.superseded() => {
+                if deployed.insert((&job.app, &job.environment)) {
+                    protected.insert(job.id);
+                }
+            }
+            Some(..) => {}
+        }
+    }
+    protected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// A job of `web` in no environment, received `age` ago.
+    fn job(status: Option<i32>, age: Duration) -> serde_json::Value {
+        let received_at = (SystemTime::now() - age)
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        json!({
+            "id": Uuid::new_v4(),
+            "app": "web",
+            "source": "deploy2",
+            "request_id": "test",
+            "flags": [],
+            "received_at": received_at.as_millis() as u64,
+            "received_at_iso": "",
+            "status": status,
+            "lines": 0,
+            "timeline": [],
+            "annotations": [],
+            "invocations": [],
+            "state": "succeeded",
+            "output": [],
+        })
+    }
+
+    /// Lists the jobs, oldest first, returning their IDs.
+    async fn list(jobs: &[serde_json::Value]) -> (Jobs, Vec<Uuid>) {
+        let list = Jobs::default();
+        let mut ids = vec![];
+        for job in jobs {
+            let job = Job::from_listing(serde_json::from_value(job.clone()).unwrap());
+            ids.push(job.id);
+            list.list.write().await.push(Arc::new(job));
+        }
+        (list, ids)
+    }
+
+    async fn kept(jobs: &Jobs) -> Vec<Uuid> {
+        jobs.list.read().await.iter().map(|job| job.id).collect()
+    }
+
+    fn fixture() -> Vec<serde_json::Value> {
+        let mut staging = job(Some(0), 10 * DAY);
+        staging["environment"] = json!("staging");
+        let mut api = job(Some(0), 10 * DAY);
+        api["app"] = json!("api");
+        let mut superseded = job(Some(0), DAY);
+        superseded["superseded_by"] = json!(Uuid::new_v4());
+        vec![
+            job(Some(0), 10 * DAY),
+            job(Some(1), 10 * DAY),
+            api,
+            job(None, 10 * DAY),
+            job(Some(0), 2 * DAY),
+            staging,
+            job(Some(1), DAY),
+            job(Some(1), DAY),
+            job(Some(1), DAY),
+            superseded,
+        ]
+    }
+
+    #[tokio::test]
+    async fn old_jobs_are_forgotten() {
+        let (jobs, ids) = list(&fixture()).await;
+        let retention = Retention {
+            max_jobs: None,
+            max_age: Some(7 * DAY),
+        };
+        assert_eq!(retention.prune(&jobs).await, 2);
+        assert_eq!(kept(&jobs).await, ids[2..]);
+    }
+
+    #[tokio::test]
+    async fn the_oldest_jobs_over_the_limit_are_forgotten() {
+        let (jobs, ids) = list(&fixture()).await;
+        let retention = Retention {
+            max_jobs: Some(4),
+            max_age: None,
+        };
+        // Of the six finished jobs that are not protected, the oldest two go.
+        assert_eq!(retention.prune(&jobs).await, 2);
+        assert_eq!(kept(&jobs).await, ids[2..]);
+        let retention = Retention {
+            max_jobs: Some(1),
+            max_age: None,
+        };
+        assert_eq!(retention.prune(&jobs).await, 3);
+        assert_eq!(kept(&jobs).await, [ids[2], ids[3], ids[4], ids[5], ids[9]]);
+    }
+
+    #[tokio::test]
+    async fn running_and_deployed_jobs_are_kept() {
+        let (jobs, ids) = list(&fixture()).await;
+        let retention = Retention {
+            max_jobs: Some(0),
+            max_age: Some(Duration::ZERO),
+        };
+        assert_eq!(retention.prune(&jobs).await, 6);
+        // The running job, the latest success of `api`, of `web`, which was not superseded,
+        // and of `web` in staging.
+        assert_eq!(kept(&jobs).await, [ids[2], ids[3], ids[4], ids[5]]);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::namespaces::Scope;
+use crate::store::JobStore;
+use crate::Jobs;
+use deploy_server_types::{Excerpt, SearchResult};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// Jobs matching more lines than this only report the first few.
+const MAX_EXCERPTS: usize = 20;
+
+/// How many saved jobs that are no longer kept in memory are searched at most.
+const MAX_SAVED_RESULTS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+/// The lines, given by their stream and text, that contain `needle`, ignoring case.
+fn excerpts<'a>(lines: impl Iterator<Item = (&'a str, &'a str)>, needle: &str) -> Vec<Excerpt> {
+    lines
+        .enumerate()
+        .filter(|(_, (_, text))| text.to_lowercase().contains(needle))
+        .take(MAX_EXCERPTS)
+        .map(|(index, (stream, text))| Excerpt {
+            line: index + 1,
+            stream: stream.to_owned(),
+            text: text.to_owned(),
+        })
+        .collect()
+}
+
+/// Case-insensitive substring search over the output of every job in the scope, newest
+/// first, followed by the jobs in the job store that are no longer kept in memory, if there
+/// is one.
+pub async fn search(
+    jobs: &Jobs,
+    store: Option<&dyn JobStore>,
+    scope: &Scope,
+    query: &str,
+) -> Vec<SearchResult> {
+    let needle = query.to_lowercase();
+    let mut results = vec![];
+    if needle.is_empty() {
+        return results;
+    }
+
+    let mut searched = HashSet::new();
+    for job in jobs.list.read().await.iter().rev() {
+        searched.insert(job.id);
+        if !scope.includes(job.namespace.as_deref()) {
+            continue;
+        }
+        let result = job.result.borrow();
+        let lines = result
+            .output
+            .iter()
+            .map(|line| (line.stream(), line.text()));
+        let matches = excerpts(lines, &needle);
+        if !matches.is_empty() {
+            results.push(SearchResult {
+                id: job.id,
+                app: job.app.clone(),
+                environment: job.environment.clone(),
+                status: result.status,
+                matches,
+            });
+        }
+    }
+
+    let saved = match store {
+        Some(store) => match store.search(&needle, MAX_SAVED_RESULTS).await {
+            Ok(saved) => saved,
+            Err(error) => {
+                eprintln!("Failed to search the job store: {error}");
+                vec![]
+            }
+        },
+        None => vec![],
+    };
+    for listing in saved {
+        if searched.contains(&listing.job.id) || !scope.includes(listing.job.namespace.as_deref()) {
+            continue;
+        }
+        let lines = listing
+            .output
+            .iter()
+            .map(|line| (line.stream.as_str(), line.text.as_str()));
+        let matches = excerpts(lines, &needle);
+        if !matches.is_empty() {
+            results.push(SearchResult {
+                id: listing.job.id,
+                app: listing.job.app,
+                environment: listing.job.environment,
+                status: listing.job.status,
+                matches,
+            });
+        }
+    }
+    results
+}
@@ -0,0 +1,590 @@
+use crate::auth::{self, WebhookProvider};
+use crate::capture;
+use crate::payload::Payload;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use warp::http::StatusCode;
+
+/// The settings of apps in the configuration file, by `{app}` or `{app}.{environment}`.
+static CONFIGURED: OnceLock<BTreeMap<String, toml::Value>> = OnceLock::new();
+
+/// Makes the settings of apps in the configuration file available to `AppSettings::load`.
+/// Only the first call has any effect.
+pub fn configure(apps: BTreeMap<String, toml::Value>) {
+    let _ = CONFIGURED.set(apps);
+}
+
+/// The settings of apps in the configuration file, which are none until it is applied.
+pub fn configured_apps() -> &'static BTreeMap<String, toml::Value> {
+    static NONE: BTreeMap<String, toml::Value> = BTreeMap::new();
+    CONFIGURED.get().unwrap_or(&NONE)
+}
+
+/// Limits of deploys whose apps' settings do not set their own, read from the environment
+/// once, so that invalid ones stop the server starting rather than failing deploys.
+pub struct DeployDefaults {
+    /// From `job_timeout`, in seconds.
+    timeout: Option<u64>,
+    /// From `max_output`, in bytes.
+    max_output: Option<usize>,
+    /// From `stall_after`, in seconds.
+    stall_after: Option<u64>,
+    /// From `max_queue_wait`, in seconds.
+    max_queue_wait: Option<u64>,
+    /// How output is captured, which applies to every app.
+    pub capture: capture::Limits,
+}
+
+impl DeployDefaults {
+    pub fn from_env() -> Self {
+        Self {
+            timeout: number("job_timeout", "seconds"),
+            max_output: number("max_output", "bytes"),
+            stall_after: number("stall_after", "seconds"),
+            max_queue_wait: number("max_queue_wait", "seconds"),
+            capture: capture::Limits::from_env(),
+        }
+    }
+}
+
+/// A number from an environment variable, if it is set.
+fn number<T: FromStr>(name: &str, unit: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    Some(value.parse().unwrap_or_else(|_| {
+        panic!(
+            "`{}` environment variable must be a number of {}",
+            name, unit
+        )
+    }))
+}
+
+/// Optional per-app settings, read from `{app}.json` alongside the app's deploy script, or
+/// from `{app}.{environment}.json` for a specific environment. The configuration file
+/// may set them instead.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppSettings {
+    /// The person or team responsible for the app.
+    pub owner: Option<String>,
+    /// How to reach the owner, such as an email address or chat channel.
+    pub contact: Option<String>,
+    /// The namespace the app is in, whose API tokens and console users may deploy it and
+    /// see its jobs.
+    pub namespace: Option<String>,
+    /// Repositories (`owner/name`) whose webhook deliveries may deploy this app. Any
+    /// repository may when this is empty.
+    pub allowed_repositories: Vec<String>,
+    /// The secret this app's webhook deliveries are signed with, when it is not
+    /// `github_webhook_secret`, or a list of secrets to accept any of while rotating it.
+    #[serde(deserialize_with = "deserialize_secrets")]
+    pub webhook_secret: Vec<String>,
+    /// The provider whose deliveries deploy this app, named as in `webhook_provider`, when
+    /// it is not the server's.
+    #[serde(deserialize_with = "deserialize_provider")]
+    pub webhook_provider: Option<Arc<dyn WebhookProvider>>,
+    /// The webhook events that deploy this app, as the provider names them, such as `push`
+    /// and `release` for GitHub. Only pushes do when this is empty.
+    pub events: Vec<String>,
+    /// The status to answer triggers that are ignored with: 200 (the default), 202, or 422
+    /// for senders that should see them as refused.
+    #[serde(deserialize_with = "deserialize_ignored_status")]
+    pub ignored_status: u16,
+    /// Whether webhook deliveries from forks may deploy this app.
+    pub allow_forks: bool,
+    /// What to do with deliveries from a fork or a repository that is not allowed.
+    pub unexpected_repository: UnexpectedRepository,
+    /// Skip deploying a commit that the most recent successful deploy already deployed,
+    /// recording a no-op job instead.
+    pub skip_deployed_sha: bool,
+    /// Patterns of the branches whose pushes deploy this app, such as `main`, `release/*`
+    /// or the full `refs/heads/main`. Patterns starting with `!` exclude branches that would
+    /// otherwise match.
+    pub branches: Vec<String>,
+    /// Patterns of the tags whose pushes deploy this app, such as `v*.*.*` and `!*-rc*`.
+    /// When either this or `branches` is set, pushes to refs matching neither are ignored.
+    pub tags: Vec<String>,
+    /// A Rhai script, relative to the scripts directory, that decides what each webhook
+    /// delivery that passes the checks above deploys, as the `filter` module describes.
+    pub filter: Option<PathBuf>,
+    /// The branch /deploy2 requests are expected to deploy. Requests naming another `ref`
+    /// are refused, unless their token is one of the `any_ref_tokens`, whose deploys of
+    /// other refs are flagged instead.
+    pub default_branch: Option<String>,
+    /// Names of the API tokens allowed to deploy this app through /deploy2. Any token may
+    /// when this is empty.
+    pub allowed_senders: Vec<String>,
+    /// The most, in seconds, to wait before deploying, picking a random delay for each
+    /// deploy so that hosts deploying the same app do not all restart it at once.
+    pub start_jitter: u64,
+    /// When a deploy is triggered while earlier ones are still queued, skip the queued
+    /// ones, so that only the latest runs.
+    pub coalesce: bool,
+    /// How long, in seconds, a deploy may wait for its turn once it is queued, after any
+    /// start jitter or GitHub approval. One that waits longer expires, failing without
+    /// deploying what is by then likely a stale commit. Defaults to the `max_queue_wait`
+    /// environment variable, and deploys may wait forever if neither is set.
+    pub max_queue_wait: Option<u64>,
+    /// How long, in seconds, a deploy may run before whatever it is running is killed and
+    /// it is recorded as timed out. Defaults to the `job_timeout` environment variable,
+    /// and deploys may run forever if neither is set.
+    pub timeout: Option<u64>,
+    /// How long, in seconds, a deploy may go without output before it is warned about as
+    /// stalled, which it is again after each further silence that long. Defaults to the
+    /// `stall_after` environment variable, and deploys are never warned about if neither is
+    /// set.
+    pub stall_after: Option<u64>,
+    /// The most output, in bytes, to keep of each deploy. The start and end of longer
+    /// output are kept, with a note of how much was left out between them. Defaults to
+    /// the `max_output` environment variable, and all output is kept if neither is set.
+    pub max_output: Option<usize>,
+    /// The deploy script, relative to the scripts directory, when it is not named after
+    /// the app.
+    pub script: Option<PathBuf>,
+    /// Labels for every job of the app, which triggers and scripts may add to or override.
+    pub labels: BTreeMap<String, String>,
+    /// Environment variables to set for the deploy script and pre-flight check.
+    pub env: BTreeMap<String, String>,
+    /// Names of the `env` variables whose values jobs may show. The others often hold
+    /// credentials, so their values are redacted.
+    pub shown_env: Vec<String>,
+    /// Leave the job's `DEPLOY_WORKSPACE` directory in place when a deploy fails, rather
+    /// than removing it once the job finishes.
+    pub keep_failed_workspace: bool,
+    /// systemd units to restart, for apps deployed without a script.
+    pub systemd_units: Vec<String>,
+    /// How long, in seconds, to wait for each systemd unit to come back up.
+    pub systemd_timeout: u64,
+    /// A Nomad job specification (JSON) to submit, for apps deployed without a script.
+    pub nomad_job: Option<PathBuf>,
+    /// How long, in seconds, to wait for the Nomad deployment to become healthy.
+    pub nomad_timeout: u64,
+    /// Before deploying, run the deploy script with `--can-deploy`, and only deploy if it
+    /// exits successfully.
+    pub preflight: bool,
+    /// A command to run as the pre-flight check instead of the deploy script, for apps
+    /// deployed without one.
+    pub preflight_command: Option<PathBuf>,
+    /// Open a GitHub issue when deploys keep failing.
+    pub failure_issue: Option<FailureIssue>,
+    /// The GitHub environment deploys are reported to, whose required reviewers and wait
+    /// timer they wait for. Approval is asked for by the workflow run that /deploy2 names
+    /// with `run_id`, which needs a job in the environment.
+    pub github_environment: Option<String>,
+    /// Categories of failure, tagging each failed deploy with the first whose pattern
+    /// matches a line of its output.
+    pub failure_categories: Vec<FailureCategory>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailureCategory {
+    /// Such as `build`, `migration` or `flake`.
+    pub category: String,
+    /// A regular expression, matched against each line of output.
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+}
+
+impl FailureCategory {
+    /// The category of the first of `categories` whose pattern matches one of `lines`.
+    pub fn classify<'a>(
+        categories: &[FailureCategory],
+        lines: impl Iterator<Item = &'a str> + Clone,
+    ) -> Option<String> {
+        categories
+            .iter()
+            .find(|category| lines.clone().any(|line| category.pattern.is_match(line)))
+            .map(|category| category.category.clone())
+    }
+}
+
+/// Where and when to open an issue about repeated failures. The issue is commented on by
+/// each further failure, and closed by the next successful deploy.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailureIssue {
+    /// The repository (`owner/name`) to open the issue in.
+    pub repository: String,
+    /// How many consecutive deploys must fail before the issue is opened.
+    #[serde(default = "FailureIssue::default_after_failures")]
+    pub after_failures: usize,
+    /// Only failures in these `failure_categories` open or comment on the issue, when
+    /// any are listed.
+    #[serde(default)]
+    pub categories: Vec<String>,
+}
+
+impl FailureIssue {
+    fn default_after_failures() -> usize {
+        3
+    }
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        Self {
+            owner: None,
+            contact: None,
+            namespace: None,
+            allowed_repositories: vec![],
+            webhook_secret: vec![],
+            webhook_provider: None,
+            events: vec![],
+            ignored_status: 200,
+            allow_forks: false,
+            unexpected_repository: UnexpectedRepository::default(),
+            skip_deployed_sha: false,
+            branches: vec![],
+            tags: vec![],
+            filter: None,
+            default_branch: None,
+            allowed_senders: vec![],
+            start_jitter: 0,
+            coalesce: false,
+            max_queue_wait: None,
+            timeout: None,
+            stall_after: None,
+            max_output: None,
+            script: None,
+            labels: BTreeMap::new(),
+            env: BTreeMap::new(),
+            shown_env: vec![],
+            keep_failed_workspace: false,
+            systemd_units: vec![],
+            systemd_timeout: 90,
+            nomad_job: None,
+            nomad_timeout: 600,
+            preflight: false,
+            preflight_command: None,
+            failure_issue: None,
+            github_environment: None,
+            failure_categories: vec![],
+        }
+    }
+}
+
+fn deserialize_provider<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Arc<dyn WebhookProvider>>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    auth::provider(&name)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// A secret, or a list of secrets that are all accepted.
+pub fn deserialize_secrets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Secrets {
+        One(String),
+        Many(Vec<String>),
+    }
+    let secrets = match Secrets::deserialize(deserializer)? {
+        Secrets::One(secret) => vec![secret],
+        Secrets::Many(secrets) => secrets,
+    };
+    if secrets.iter().any(String::is_empty) {
+        return Err(serde::de::Error::custom("secrets must not be empty"));
+    }
+    Ok(secrets)
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_ignored_status<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    match u16::deserialize(deserializer)? {
+        status @ (200 | 202 | 422) => Ok(status),
+        status => Err(serde::de::Error::custom(format!(
+            "ignored_status must be 200, 202 or 422, not {status}"
+        ))),
+    }
+}
+
+/// Whether `name` matches one of the patterns and none of the `!` patterns. Patterns may
+/// name the full ref, starting with `namespace`.
+fn matches_patterns(patterns: &[String], namespace: &str, name: &str) -> bool {
+    let (excluded, included): (Vec<_>, Vec<_>) = patterns
+        .iter()
+        .map(|pattern| match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        })
+        .map(|(excluded, pattern)| (excluded, pattern.strip_prefix(namespace).unwrap_or(pattern)))
+        .partition(|(excluded, _)| *excluded);
+    included.iter().any(|(_, pattern)| glob(pattern, name))
+        && !excluded.iter().any(|(_, pattern)| glob(pattern, name))
+}
+
+/// Matches `text` against a pattern in which `*` stands for any run of characters and `?`
+/// for any one character.
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and where in the text it is currently matched up to.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnexpectedRepository {
+    /// Refuse to deploy.
+    #[default]
+    Refuse,
+    /// Deploy anyway, but flag the job.
+    Flag,
+}
+
+impl AppSettings {
+    /// Loads the settings for an app in an environment from `{app}.{environment}.json`,
+    /// falling back to the app's `{app}.json`. A table of the same name in the
+    /// configuration file is used in place of either file.
+    pub fn load(directory: &Path, app: &str, environment: Option<&str>) -> Result<Self, String> {
+        if let Some(environment) = environment {
+            let name = format!("{app}.{environment}");
+            if let Some(settings) = Self::configured(&name)? {
+                return Ok(settings);
+            }
+            let path = directory.join(format!("{name}.json"));
+            if path.is_file() {
+                return Self::read(&path).map(Option::unwrap_or_default);
+            }
+        }
+        if let Some(settings) = Self::configured(app)? {
+            return Ok(settings);
+        }
+        Self::read(&directory.join(format!("{app}.json"))).map(Option::unwrap_or_default)
+    }
+
+    /// How many bytes of output to keep of a deploy, if it is limited. A limit of zero
+    /// does not limit it.
+    pub fn max_output(&self, defaults: &DeployDefaults) -> Option<usize> {
+        let max = self.max_output.or(defaults.max_output)?;
+        Some(max).filter(|max| *max > 0)
+    }
+
+    fn configured(name: &str) -> Result<Option<Self>, String> {
+        match CONFIGURED.get().and_then(|apps| apps.get(name)) {
+            Some(settings) => settings
+                .clone()
+                .try_into()
+                .map(Some)
+                .map_err(|error| format!("Invalid settings for `{name}`: {error}")),
+            None => Ok(None),
+        }
+    }
+
+    /// How long a deploy may run, if it is limited. A timeout of zero does not limit it.
+    pub fn timeout(&self, defaults: &DeployDefaults) -> Option<Duration> {
+        let seconds = self.timeout.or(defaults.timeout)?;
+        Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero())
+    }
+
+    /// How long a deploy may wait for its turn, if it is limited. Zero does not limit it.
+    pub fn max_queue_wait(&self, defaults: &DeployDefaults) -> Option<Duration> {
+        let seconds = self.max_queue_wait.or(defaults.max_queue_wait)?;
+        Some(Duration::from_secs(seconds)).filter(|wait| !wait.is_zero())
+    }
+
+    /// How long a deploy may go without output before it is warned about, if it is. Zero
+    /// never warns.
+    pub fn stall_after(&self, defaults: &DeployDefaults) -> Option<Duration> {
+        let seconds = self.stall_after.or(defaults.stall_after)?;
+        Some(Duration::from_secs(seconds)).filter(|after| !after.is_zero())
+    }
+
+    fn read(path: &Path) -> Result<Option<Self>, String> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|error| format!("Invalid settings in {}: {error}", path.display())),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(format!("Failed to read {}: {error}", path.display())),
+        }
+    }
+
+    /// Whether a push to `reference` should deploy the app, going by its branch and tag
+    /// patterns.
+    pub fn allows_ref(&self, reference: Option<&str>) -> bool {
+        if self.branches.is_empty() && self.tags.is_empty() {
+            return true;
+        }
+        let reference = match reference {
+            Some(reference) => reference,
+            None => return false,
+        };
+        if let Some(branch) = reference.strip_prefix("refs/heads/") {
+            matches_patterns(&self.branches, "refs/heads/", branch)
+        } else if let Some(tag) = reference.strip_prefix("refs/tags/") {
+            matches_patterns(&self.tags, "refs/tags/", tag)
+        } else {
+            false
+        }
+    }
+
+    pub fn ignored_status(&self) -> StatusCode {
+        StatusCode::from_u16(self.ignored_status).unwrap_or(StatusCode::OK)
+    }
+
+    /// Whether a webhook delivery about `event` should deploy the app.
+    pub fn deploys_event(&self, provider: &dyn WebhookProvider, event: &str) -> bool {
+        if self.events.is_empty() {
+            provider.is_push(event)
+        } else {
+            self.events.iter().any(|deployed| deployed == event)
+        }
+    }
+
+    /// Describes anything unexpected about where a webhook delivery came from.
+    pub fn check_origin(&self, payload: &Payload) -> Vec<String> {
+        let mut problems = vec![];
+        if payload.is_fork() && !self.allow_forks {
+            problems.push("the delivery came from a fork".to_owned());
+        }
+        if !self.allowed_repositories.is_empty() {
+            match payload.repository() {
+                Some(repository)
+                    if self
+                        .allowed_repositories
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(repository)) => {}
+                Some(repository) => {
+                    problems.push(format!("the repository {repository} is not allowed"))
+                }
+                None => problems.push("the delivery does not name a repository".to_owned()),
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    fn refs(branches: &[&str], tags: &[&str]) -> AppSettings {
+        AppSettings {
+            branches: patterns(branches),
+            tags: patterns(tags),
+            ..AppSettings::default()
+        }
+    }
+
+    #[test]
+    fn stars_match_any_run_of_characters() {
+        assert!(glob("release/*", "release/1.0"));
+        assert!(glob("release/*", "release/"));
+        assert!(glob("*", ""));
+        assert!(glob("*-hotfix", "release/1.0-hotfix"));
+        assert!(glob("v*.*.*", "v1.20.3"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("release/*", "releases/1.0"));
+        assert!(!glob("v*.*.*", "v1.20"));
+        assert!(!glob("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn question_marks_match_one_character() {
+        assert!(glob("v?", "v1"));
+        assert!(glob("v?.?", "v1.2"));
+        assert!(!glob("v?", "v"));
+        assert!(!glob("v?", "v10"));
+        assert!(glob("v?*", "v10"));
+    }
+
+    #[test]
+    fn patterns_without_wildcards_match_exactly() {
+        assert!(glob("main", "main"));
+        assert!(!glob("main", "main2"));
+        assert!(!glob("main", "mai"));
+    }
+
+    #[test]
+    fn patterns_may_name_the_full_ref() {
+        let branches = patterns(&["refs/heads/main", "refs/heads/release/*"]);
+        assert!(matches_patterns(&branches, "refs/heads/", "main"));
+        assert!(matches_patterns(&branches, "refs/heads/", "release/2"));
+        assert!(!matches_patterns(&branches, "refs/heads/", "develop"));
+    }
+
+    #[test]
+    fn exclusions_override_inclusions() {
+        let tags = patterns(&["v*", "!*-rc*", "!refs/tags/v0.*"]);
+        assert!(matches_patterns(&tags, "refs/tags/", "v1.0.0"));
+        assert!(!matches_patterns(&tags, "refs/tags/", "v1.0.0-rc1"));
+        assert!(!matches_patterns(&tags, "refs/tags/", "v0.9.0"));
+        assert!(!matches_patterns(&tags, "refs/tags/", "nightly"));
+        // Exclusions alone match nothing, as there is nothing for them to exclude from.
+        assert!(!matches_patterns(
+            &patterns(&["!main"]),
+            "refs/heads/",
+            "develop"
+        ));
+    }
+
+    #[test]
+    fn branches_and_tags_are_matched_by_their_own_patterns() {
+        let settings = refs(&["main"], &["v*"]);
+        assert!(settings.allows_ref(Some("refs/heads/main")));
+        assert!(settings.allows_ref(Some("refs/tags/v1.0.0")));
+        assert!(!settings.allows_ref(Some("refs/tags/main")));
+        assert!(!settings.allows_ref(Some("refs/heads/v1.0.0")));
+        assert!(!settings.allows_ref(Some("refs/pull/1/head")));
+        assert!(!settings.allows_ref(None));
+
+        // Setting only branches ignores every tag, and the other way around.
+        assert!(!refs(&["*"], &[]).allows_ref(Some("refs/tags/v1.0.0")));
+        assert!(!refs(&[], &["*"]).allows_ref(Some("refs/heads/main")));
+    }
+
+    #[test]
+    fn every_ref_deploys_without_patterns() {
+        let settings = refs(&[], &[]);
+        assert!(settings.allows_ref(Some("refs/heads/anything")));
+        assert!(settings.allows_ref(Some("refs/tags/v1.0.0")));
+        assert!(settings.allows_ref(None));
+    }
+
+    #[test]
+    fn patterns_naming_a_ref_of_another_kind_match_nothing() {
+        let branches = patterns(&["refs/tags/v1"]);
+        assert!(!matches_patterns(&branches, "refs/heads/", "v1"));
+    }
+}
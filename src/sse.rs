@@ -0,0 +1,24 @@
+//! Streams a job's output as Server-Sent Events while it runs.
+
+use crate::progress;
+use crate::Job;
+use deploy_server_types::JobProgress;
+use futures::stream::{Stream, StreamExt};
+use std::convert::Infallible;
+use std::sync::Arc;
+use warp::sse::Event;
+
+/// Every line of the job's output as a `line` event, the ones already written first,
+/// followed by a `finished` event with its exit status once it has finished.
+pub fn job_events(job: Arc<Job>) -> impl Stream<Item = Result<Event, Infallible>> {
+    progress::job_events(job).map(|event| {
+        Ok(match event {
+            JobProgress::Line { line } => Event::default()
+                .event("line")
+                .data(serde_json::to_string(&line).unwrap()),
+            JobProgress::Finished { job, .. } => Event::default()
+                .event("finished")
+                .data(job.status.unwrap_or_default().to_string()),
+        })
+    })
+}
@@ -0,0 +1,214 @@
+use crate::hooks::Hooks;
+use crate::{invocation, lock_key, settings, Integrations, Jobs};
+use deploy_server_types::Job;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// A snapshot of the server's internal state, for debugging stuck or misbehaving deploys.
+#[derive(Serialize)]
+pub struct StateDump {
+    running: usize,
+    jobs: Vec<Job>,
+    /// How many more deploys may start before `max_concurrent_deploys` is reached, when it
+    /// is set.
+    permits_available: Option<usize>,
+    /// Each app and environment this instance has deployed, and whether a deploy of it
+    /// holds its lock.
+    locks: Vec<LockState>,
+    config: ConfigDigest,
+}
+
+#[derive(Serialize)]
+struct ConfigDigest {
+    /// A SHA-256 hash of every setting and app in the configuration file, to tell
+    /// whether the configuration changed between two dumps, even if only in a secret.
+    sha256: String,
+    port: u16,
+    hooks: Vec<&'static str>,
+    /// The server's settings, with secrets and credentials in URLs redacted.
+    settings: BTreeMap<String, String>,
+    /// The apps and environments the configuration file has settings for.
+    apps: Vec<String>,
+}
+
+impl ConfigDigest {
+    /// The server's settings are environment variables with lower case names, unlike the
+    /// variables it inherits.
+    fn collect(hooks: &Hooks, port: u16) -> Self {
+        let variables: BTreeMap<_, _> = std::env::vars()
+            .filter(|(name, _)| !name.chars().any(|c| c.is_ascii_uppercase()))
+            .collect();
+        let apps = settings::configured_apps();
+        let mut hash = Sha256::new();
+        for (name, value) in &variables {
+            hash.update(format!("{name}={value}\n"));
+        }
+        for (app, settings) in apps {
+            hash.update(format!("[{app}]\n{settings}\n"));
+        }
+        Self {
+            sha256: hex::encode(hash.finalize()),
+            port,
+            hooks: hooks.configured(),
+            settings: variables
+                .iter()
+                .map(|(name, value)| (name.clone(), invocation::redact(name, value)))
+                .collect(),
+            apps: apps.keys().cloned().collect(),
+        }
+    }
+}
+
+impl StateDump {
+    pub async fn collect(
+        jobs: &Jobs,
+        integrations: &Integrations,
+        hooks: &Hooks,
+        port: u16,
+    ) -> Self {
+        let states: Vec<_> = jobs
+            .list
+            .read()
+            .await
+            .iter()
+            .map(|job| job.summary())
+            .collect();
+        Self {
+            running: states.iter().filter(|job| job.status.is_none()).count(),
+            jobs: states,
+            permits_available: permits_available(integrations),
+            locks: locks(integrations),
+            config: ConfigDigest::collect(hooks, port),
+        }
+    }
+}
+
+fn permits_available(integrations: &Integrations) -> Option<usize> {
+    integrations
+        .permits
+        .as_ref()
+        .map(|permits| permits.available())
+}
+
+/// The lock of each app and environment, in order.
+fn locks(integrations: &Integrations) -> Vec<LockState> {
+    let mut locks: Vec<_> = integrations
+        .deploying
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, lock)| LockState {
+            key: key.clone(),
+            held: lock.try_lock().is_err(),
+        })
+        .collect();
+    locks.sort_by(|a, b| a.key.cmp(&b.key));
+    locks
+}
+
+/// What the deploy queue is waiting on, for working out why a queued job is not starting.
+#[derive(Serialize)]
+pub struct QueueDump {
+    /// How many more deploys may start before `max_concurrent_deploys` is reached, when it
+    /// is set.
+    permits_available: Option<usize>,
+    running: usize,
+    waiting: usize,
+    /// Each app and environment this instance has deployed, and whether a deploy of it
+    /// holds its lock.
+    locks: Vec<LockState>,
+    jobs: Vec<QueuedJob>,
+}
+
+#[derive(Serialize)]
+struct LockState {
+    key: String,
+    held: bool,
+}
+
+/// An unfinished job.
+#[derive(Serialize)]
+struct QueuedJob {
+    id: Uuid,
+    app: String,
+    environment: Option<String>,
+    running: bool,
+    /// What a job that has not started is waiting for: its app's `lock`, or a `permit`
+    /// once it has the lock.
+    waiting_for: Option<&'static str>,
+    superseded_by: Option<Uuid>,
+    /// How long ago the job was triggered.
+    age_seconds: u64,
+}
+
+impl QueueDump {
+    pub async fn collect(jobs: &Jobs, integrations: &Integrations) -> Self {
+        let locks = locks(integrations);
+        let permits_available = permits_available(integrations);
+        let held = |key: &str| locks.iter().any(|lock| lock.key == key && lock.held);
+        let jobs: Vec<_> = jobs
+            .list
+            .read()
+            .await
+            .iter()
+            .filter(|job| job.result.borrow().status.is_none())
+            .map(|job| {
+                let running = job.result.borrow().started();
+                let waiting_for = if running {
+                    None
+                } else if held(&lock_key(job)) {
+                    Some("lock")
+                } else if permits_available == Some(0) {
+                    Some("permit")
+                } else {
+                    None
+                };
+                QueuedJob {
+                    id: job.id,
+                    app: job.app.clone(),
+                    environment: job.environment.clone(),
+                    running,
+                    waiting_for,
+                    superseded_by: job.cancellation.superseded_by(),
+                    age_seconds: SystemTime::now()
+                        .duration_since(job.received_at)
+                        .unwrap_or_default()
+                        .as_secs(),
+                }
+            })
+            .collect();
+        Self {
+            permits_available,
+            running: jobs.iter().filter(|job| job.running).count(),
+            waiting: jobs.iter().filter(|job| !job.running).count(),
+            locks,
+            jobs,
+        }
+    }
+}
+
+/// Logs a state dump every time the process receives `SIGUSR1`.
+#[cfg(unix)]
+pub async fn dump_on_signal(
+    jobs: Jobs,
+    integrations: std::sync::Arc<Integrations>,
+    hooks: std::sync::Arc<Hooks>,
+    port: u16,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(error) => {
+            eprintln!("Failed to listen for SIGUSR1: {error}");
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let dump = StateDump::collect(&jobs, &integrations, &hooks, port).await;
+        eprintln!("{}", serde_json::to_string_pretty(&dump).unwrap());
+    }
+}
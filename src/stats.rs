@@ -0,0 +1,54 @@
+//! Counts of how deploys are triggered, to tell automated deploys from manual ones.
+
+use crate::namespaces::Scope;
+use crate::Jobs;
+use deploy_server_types::TriggerCounts;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    /// How many days back to count, including today.
+    #[serde(default = "StatsQuery::default_days")]
+    pub days: u64,
+}
+
+impl StatsQuery {
+    fn default_days() -> u64 {
+        30
+    }
+}
+
+/// The number of jobs of each app in the scope triggered by each source, per day, oldest
+/// first. Only the jobs the server still has are counted.
+pub async fn trigger_counts(jobs: &Jobs, scope: &Scope, days: u64) -> Vec<TriggerCounts> {
+    let today = day_of(SystemTime::now());
+    let since = today.saturating_sub(days.saturating_sub(1));
+    let mut counts = BTreeMap::<_, TriggerCounts>::new();
+    for job in jobs.list.read().await.iter() {
+        let day = day_of(job.received_at);
+        if day < since || !scope.includes(job.namespace.as_deref()) {
+            continue;
+        }
+        let key = (day, job.app.clone(), job.environment.clone());
+        let entry = counts.entry(key).or_insert_with(|| TriggerCounts {
+            app: job.app.clone(),
+            environment: job.environment.clone(),
+            day: day * DAY.as_millis() as u64,
+            sources: BTreeMap::new(),
+        });
+        *entry.sources.entry(job.source).or_default() += 1;
+    }
+    counts.into_values().collect()
+}
+
+/// The number of whole days between the Unix epoch and `time`.
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / DAY.as_secs()
+}
@@ -0,0 +1,110 @@
+//! Persistence of jobs, so that deploy history survives a restart, and jobs that were
+//! still queued are deployed once the server starts again.
+//!
+//! Jobs are stored as the JSON of their listing, with all of their output, in a `jobs`
+//! table keyed by their ID. Each backend is behind a cargo feature of the same name.
+
+use deploy_server_types::JobListing;
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// Finishes if a lock taken in the store is lost before it is released, such as when the
+/// connection holding it drops.
+pub type LockLost = BoxFuture<'static, ()>;
+
+pub trait JobStore: Send + Sync {
+    /// Saves a job, replacing any copy of it that was saved before.
+    fn save<'a>(&'a self, job: &'a JobListing) -> BoxFuture<'a, Result<(), String>>;
+
+    /// The `limit` most recently received saved jobs, oldest first.
+    fn load(&self, limit: usize) -> BoxFuture<'_, Result<Vec<JobListing>, String>>;
+
+    /// A saved job.
+    fn get(&self, id: Uuid) -> BoxFuture<'_, Result<Option<JobListing>, String>>;
+
+    /// The `limit` most recently received saved jobs that mention `text`, which is in
+    /// lower case, anywhere in their listing, newest first. Callers check which lines
+    /// matched themselves.
+    fn search<'a>(
+        &'a self,
+        text: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<JobListing>, String>>;
+
+    /// The IDs of jobs as they are saved by the other instances sharing the store, for
+    /// stores that can be shared.
+    fn watch(&self) -> BoxFuture<'_, Result<Option<mpsc::UnboundedReceiver<Uuid>>, String>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Waits until no other instance sharing the store holds the lock named `key`, and
+    /// takes it. Stores that cannot be shared have nobody to wait for, and never lose it.
+    fn lock<'a>(&'a self, _key: &'a str) -> BoxFuture<'a, Result<LockLost, String>> {
+        Box::pin(async { Ok(Box::pin(futures::future::pending()) as LockLost) })
+    }
+
+    fn unlock<'a>(&'a self, _key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Takes over the unfinished jobs saved by instances sharing the store that have since
+    /// stopped, so that each is handed to exactly one of the instances still running.
+    /// Stores that cannot be shared have no other instances to take over from.
+    fn orphans(&self) -> BoxFuture<'_, Result<Vec<JobListing>, String>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+}
+
+/// A `LIKE` pattern, escaped with `\`, for the lower cased listings that mention `text`
+/// as it is written in their JSON.
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+fn like_pattern(text: &str) -> String {
+    let json = serde_json::to_string(text).unwrap();
+    let mut pattern = String::from("%");
+    for c in json[1..json.len() - 1].chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// How many of the most recent jobs to load from the store at startup, from
+/// `job_history`. Older jobs stay in the store, but are not shown.
+pub fn history_from_env() -> usize {
+    std::env::var("job_history")
+        .map(|history| {
+            history
+                .parse()
+                .expect("`job_history` environment variable must be a number")
+        })
+        .unwrap_or(1000)
+}
+
+/// The store named by `job_store`, either `sqlite:{path}` or a `postgres://` connection
+/// URL, if one is set.
+pub fn from_env() -> Option<Box<dyn JobStore>> {
+    let store = std::env::var("job_store").ok()?;
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = store.strip_prefix("sqlite:") {
+        let store = sqlite::Sqlite::open(std::path::Path::new(path))
+            .unwrap_or_else(|error| panic!("Failed to open the job store at {}: {}", path, error));
+        return Some(Box::new(store));
+    }
+    #[cfg(feature = "postgres")]
+    if store.starts_with("postgres://") || store.starts_with("postgresql://") {
+        return Some(Box::new(postgres::Postgres::new(store)));
+    }
+    panic!(
+        "`job_store` environment variable must name a store this build supports: `{}`",
+        store
+    )
+}
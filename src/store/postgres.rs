@@ -0,0 +1,392 @@
+use super::{JobStore, LockLost};
+use deploy_server_types::JobListing;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+use uuid::Uuid;
+
+/// Instances sharing the store are told of each job saved through this channel, by the
+/// ID of the instance that saved it and the job's ID, separated by a space.
+const CHANNEL: &str = "deploy_server_jobs";
+
+/// How often to try again for a lock that another instance holds.
+const LOCK_RETRY: Duration = Duration::from_secs(1);
+
+/// How long to wait before listening again, once the connection listening drops.
+const RELISTEN: Duration = Duration::from_secs(5);
+
+/// Jobs in a Postgres database, for installs that already run one. Several instances can
+/// share the database: each is told of the jobs the others save, and locks are advisory
+/// locks held by this instance's session, so they are released if it dies.
+///
+/// Each job is saved with the instance that saved it last, which holds a lease on its
+/// jobs for as long as its session lasts. The unfinished jobs of an instance whose lease
+/// has lapsed are taken over by whichever instance notices first.
+pub struct Postgres {
+    url: String,
+    /// Who this instance's jobs are saved as being run by, for this run of the server.
+    instance: Uuid,
+    /// Connected when the store is first used, since the configuration is read before
+    /// the runtime is available to connect with, and again whenever the connection has
+    /// dropped.
+    session: Mutex<Option<Session>>,
+}
+
+/// A connection, with the advisory locks it holds.
+struct Session {
+    client: Arc<Client>,
+    /// Never sent to, so it only changes, with an error, once the connection has closed
+    /// and its locks have gone with it.
+    closed: watch::Receiver<()>,
+}
+
+/// A connection that listens for jobs being saved.
+struct Listening {
+    /// The connection closes once its client is dropped, so it is kept as long as the
+    /// connection is wanted.
+    _client: Client,
+    /// Forwards the notifications, until the connection drops.
+    forwarding: JoinHandle<()>,
+}
+
+fn describe(error: tokio_postgres::Error) -> String {
+    error.to_string()
+}
+
+impl Postgres {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            instance: Uuid::new_v4(),
+            session: Mutex::new(None),
+        }
+    }
+
+    /// The current session, connecting a new one if there is none, or it has dropped.
+    async fn session(&self) -> Result<(Arc<Client>, watch::Receiver<()>), String> {
+        let mut session = self.session.lock().await;
+        if let Some(session) = &*session {
+            if !session.client.is_closed() {
+                return Ok((session.client.clone(), session.closed.clone()));
+            }
+        }
+        let (client, connection) = tokio_postgres::connect(&self.url, NoTls)
+            .await
+            .map_err(describe)?;
+        let (closing, closed) = watch::channel(());
+        tokio::spawn(async move {
+            if let Err(error) = connection.await {
+                eprintln!("The job store connection failed: {error}");
+            }
+            drop(closing);
+        });
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS jobs (
+                    id TEXT PRIMARY KEY,
+                    received_at BIGINT NOT NULL,
+                    listing TEXT NOT NULL
+                );
+                ALTER TABLE jobs ADD COLUMN IF NOT EXISTS instance TEXT",
+            )
+            .await
+            .map_err(describe)?;
+        // Nobody else ever takes this instance's lease, but another instance checking
+        // whether it has lapsed may hold it for a moment.
+        client
+            .execute(
+                "SELECT pg_advisory_lock(hashtext($1))",
+                &[&lease_key(&self.instance.to_string())],
+            )
+            .await
+            .map_err(describe)?;
+        let client = Arc::new(client);
+        *session = Some(Session {
+            client: client.clone(),
+            closed: closed.clone(),
+        });
+        Ok((client, closed))
+    }
+
+    async fn client(&self) -> Result<Arc<Client>, String> {
+        Ok(self.session().await?.0)
+    }
+}
+
+/// The advisory lock an instance holds for as long as it is running.
+fn lease_key(instance: &str) -> String {
+    format!("instance:{instance}")
+}
+
+/// Whether nothing holds the instance's lease any more, as it has stopped.
+async fn lease_lapsed(client: &Client, instance: &str) -> Result<bool, String> {
+    let key = lease_key(instance);
+    let lapsed: bool = client
+        .query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&key])
+        .await
+        .map_err(describe)?
+        .get(0);
+    if lapsed {
+        client
+            .execute("SELECT pg_advisory_unlock(hashtext($1))", &[&key])
+            .await
+            .map_err(describe)?;
+    }
+    Ok(lapsed)
+}
+
+/// Listens for jobs being saved by instances other than `instance`, on a connection of its
+/// own, as notifications arrive on the connection that listens for them, and it is only
+/// ever used for that.
+async fn listen(
+    url: &str,
+    instance: Uuid,
+    sender: mpsc::UnboundedSender<Uuid>,
+) -> Result<Listening, String> {
+    let (client, mut connection) = tokio_postgres::connect(url, NoTls)
+        .await
+        .map_err(describe)?;
+    let forwarding = tokio::spawn(async move {
+        let mut messages =
+            futures::stream::poll_fn(move |context| connection.poll_message(context));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    // Instances from before notifications named who saved the job send only
+                    // its ID.
+                    let (saved_by, id) = match notification.payload().split_once(' ') {
+                        Some((saved_by, id)) => (saved_by.parse().ok(), id),
+                        None => (None, notification.payload()),
+                    };
+                    // This instance has its own jobs already, and they are still live.
+                    if saved_by == Some(instance) {
+                        continue;
+                    }
+                    let id = match id.parse() {
+                        Ok(id) => id,
+                        Err(..) => continue,
+                    };
+                    if sender.send(id).is_err() {
+                        break;
+                    }
+                }
+                Ok(..) => {}
+                Err(error) => {
+                    eprintln!("The job store stopped notifying of saved jobs: {error}");
+                    break;
+                }
+            }
+        }
+    });
+    client
+        .batch_execute(&format!("LISTEN {CHANNEL}"))
+        .await
+        .map_err(describe)?;
+    Ok(Listening {
+        _client: client,
+        forwarding,
+    })
+}
+
+impl JobStore for Postgres {
+    fn save<'a>(&'a self, job: &'a JobListing) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let id = job.job.id.to_string();
+            let listing = serde_json::to_string(job).unwrap();
+            let instance = self.instance.to_string();
+            let client = self.client().await?;
+            client
+                .execute(
+                    "INSERT INTO jobs (id, received_at, listing, instance) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (id) DO UPDATE
+                    SET listing = EXCLUDED.listing, instance = EXCLUDED.instance",
+                    &[&id, &(job.job.received_at as i64), &listing, &instance],
+                )
+                .await
+                .map_err(describe)?;
+            client
+                .execute(
+                    "SELECT pg_notify($1, $2)",
+                    &[&CHANNEL, &format!("{instance} {id}")],
+                )
+                .await
+                .map_err(describe)?;
+            Ok(())
+        })
+    }
+
+    fn load(&self, limit: usize) -> BoxFuture<'_, Result<Vec<JobListing>, String>> {
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        Box::pin(async move {
+            let rows = self
+                .client()
+                .await?
+                .query(
+                    "SELECT listing FROM (
+                        SELECT listing, received_at FROM jobs
+                        ORDER BY received_at DESC LIMIT $1
+                    ) AS recent ORDER BY received_at",
+                    &[&limit],
+                )
+                .await
+                .map_err(describe)?;
+            rows.iter()
+                .map(|row| {
+                    serde_json::from_str(row.get::<_, &str>(0)).map_err(|error| error.to_string())
+                })
+                .collect()
+        })
+    }
+
+    fn get(&self, id: Uuid) -> BoxFuture<'_, Result<Option<JobListing>, String>> {
+        Box::pin(async move {
+            let row = self
+                .client()
+                .await?
+                .query_opt("SELECT listing FROM jobs WHERE id = $1", &[&id.to_string()])
+                .await
+                .map_err(describe)?;
+            row.map(|row| {
+                serde_json::from_str(row.get::<_, &str>(0)).map_err(|error| error.to_string())
+            })
+            .transpose()
+        })
+    }
+
+    fn search<'a>(
+        &'a self,
+        text: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<JobListing>, String>> {
+        let pattern = super::like_pattern(text);
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        Box::pin(async move {
+            let rows = self
+                .client()
+                .await?
+                .query(
+                    "SELECT listing FROM jobs WHERE lower(listing) LIKE $1 ESCAPE '\\'
+                    ORDER BY received_at DESC LIMIT $2",
+                    &[&pattern, &limit],
+                )
+                .await
+                .map_err(describe)?;
+            rows.iter()
+                .map(|row| {
+                    serde_json::from_str(row.get::<_, &str>(0)).map_err(|error| error.to_string())
+                })
+                .collect()
+        })
+    }
+
+    fn watch(&self) -> BoxFuture<'_, Result<Option<mpsc::UnboundedReceiver<Uuid>>, String>> {
+        Box::pin(async move {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let mut listening = listen(&self.url, self.instance, sender.clone()).await?;
+            let url = self.url.clone();
+            let instance = self.instance;
+            // Listens again whenever the connection drops, until nobody is listening any
+            // more. Jobs saved in between are missed.
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = sender.closed() => return,
+                        _ = &mut listening.forwarding => {}
+                    }
+                    listening = loop {
+                        tokio::time::sleep(RELISTEN).await;
+                        if sender.is_closed() {
+                            return;
+                        }
+                        match listen(&url, instance, sender.clone()).await {
+                            Ok(listening) => break listening,
+                            Err(error) => {
+                                eprintln!("Failed to listen to the job store again: {error}")
+                            }
+                        }
+                    };
+                }
+            });
+            Ok(Some(receiver))
+        })
+    }
+
+    fn lock<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<LockLost, String>> {
+        Box::pin(async move {
+            // Waiting in `pg_advisory_lock` would hold up every other query on the shared
+            // connection, so the lock is tried until it is free instead.
+            let (client, mut closed) = self.session().await?;
+            loop {
+                let locked: bool = client
+                    .query_one("SELECT pg_try_advisory_lock(hashtext($1))", &[&key])
+                    .await
+                    .map_err(describe)?
+                    .get(0);
+                if locked {
+                    return Ok(
+                        Box::pin(async move { while closed.changed().await.is_ok() {} })
+                            as LockLost,
+                    );
+                }
+                tokio::time::sleep(LOCK_RETRY).await;
+            }
+        })
+    }
+
+    fn unlock<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.client()
+                .await?
+                .execute("SELECT pg_advisory_unlock(hashtext($1))", &[&key])
+                .await
+                .map_err(describe)?;
+            Ok(())
+        })
+    }
+    fn orphans(&self) -> BoxFuture<'_, Result<Vec<JobListing>, String>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let instance = self.instance.to_string();
+            let owners = client
+                .query(
+                    "SELECT DISTINCT instance FROM jobs
+                    WHERE instance IS DISTINCT FROM $1 AND listing::jsonb ->> 'status' IS NULL",
+                    &[&instance],
+                )
+                .await
+                .map_err(describe)?;
+            let mut orphans = vec![];
+            for owner in owners {
+                let owner: Option<String> = owner.get(0);
+                // Jobs saved before instances held leases have nobody to ask.
+                if let Some(owner) = &owner {
+                    if !lease_lapsed(&client, owner).await? {
+                        continue;
+                    }
+                }
+                // Taken over in one statement, so that only one instance gets each job.
+                let rows = client
+                    .query(
+                        "UPDATE jobs SET instance = $1
+                        WHERE instance IS NOT DISTINCT FROM $2
+                        AND listing::jsonb ->> 'status' IS NULL
+                        RETURNING listing",
+                        &[&instance, &owner],
+                    )
+                    .await
+                    .map_err(describe)?;
+                for row in rows {
+                    let listing = serde_json::from_str(row.get::<_, &str>(0))
+                        .map_err(|error| error.to_string())?;
+                    orphans.push(listing);
+                }
+            }
+            Ok(orphans)
+        })
+    }
+}
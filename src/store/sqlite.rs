@@ -0,0 +1,166 @@
+use super::JobStore;
+use deploy_server_types::JobListing;
+use futures::future::BoxFuture;
+use rusqlite::OptionalExtension;
+use rusqlite::{params, Connection};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Jobs in a SQLite database file, for installs that have nowhere else to keep them.
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id TEXT PRIMARY KEY,
+                received_at INTEGER NOT NULL,
+                listing TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs a query on the blocking thread pool, so the database never blocks the server.
+    async fn run<T, F>(&self, query: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || query(&connection.lock().unwrap()))
+            .await
+            .map_err(|error| error.to_string())?
+            .map_err(|error| error.to_string())
+    }
+}
+
+impl JobStore for Sqlite {
+    fn save<'a>(&'a self, job: &'a JobListing) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let id = job.job.id.to_string();
+            let received_at = job.job.received_at as i64;
+            let listing = serde_json::to_string(job).unwrap();
+            self.run(move |connection| {
+                connection.execute(
+                    "INSERT OR REPLACE INTO jobs (id, received_at, listing) VALUES (?1, ?2, ?3)",
+                    params![id, received_at, listing],
+                )
+            })
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn load(&self, limit: usize) -> BoxFuture<'_, Result<Vec<JobListing>, String>> {
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        Box::pin(async move {
+            let listings = self
+                .run(move |connection| {
+                    connection
+                        .prepare(
+                            "SELECT listing FROM (
+                                SELECT listing, received_at FROM jobs
+                                ORDER BY received_at DESC LIMIT ?1
+                            ) ORDER BY received_at",
+                        )?
+                        .query_map(params![limit], |row| row.get::<_, String>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                })
+                .await?;
+            listings
+                .iter()
+                .map(|listing| serde_json::from_str(listing).map_err(|error| error.to_string()))
+                .collect()
+        })
+    }
+
+    fn get(&self, id: Uuid) -> BoxFuture<'_, Result<Option<JobListing>, String>> {
+        Box::pin(async move {
+            let listing = self
+                .run(move |connection| {
+                    connection
+                        .query_row(
+                            "SELECT listing FROM jobs WHERE id = ?1",
+                            params![id.to_string()],
+                            |row| row.get::<_, String>(0),
+                        )
+                        .optional()
+                })
+                .await?;
+            listing
+                .map(|listing| serde_json::from_str(&listing).map_err(|error| error.to_string()))
+                .transpose()
+        })
+    }
+
+    fn search<'a>(
+        &'a self,
+        text: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<JobListing>, String>> {
+        let pattern = super::like_pattern(text);
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        Box::pin(async move {
+            let listings = self
+                .run(move |connection| {
+                    connection
+                        .prepare(
+                            "SELECT listing FROM jobs WHERE lower(listing) LIKE ?1 ESCAPE '\\'
+                            ORDER BY received_at DESC LIMIT ?2",
+                        )?
+                        .query_map(params![pattern, limit], |row| row.get::<_, String>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                })
+                .await?;
+            listings
+                .iter()
+                .map(|listing| serde_json::from_str(listing).map_err(|error| error.to_string()))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn searches_listings_as_they_read() {
+        let store = Sqlite::open(Path::new(":memory:")).unwrap();
+        let listing: JobListing = serde_json::from_value(json!({
+            "id": Uuid::new_v4(),
+            "app": "web",
+            "source": "deploy2",
+            "request_id": "test",
+            "flags": [],
+            "received_at": 0,
+            "received_at_iso": "",
+            "status": 0,
+            "lines": 1,
+            "timeline": [],
+            "annotations": [],
+            "labels": {},
+            "invocations": [],
+            "state": "succeeded",
+            "output": [{ "stream": "stdout", "text": "Wrote \"Cache\" to 100%", "elapsed_ms": 0 }],
+        }))
+        .unwrap();
+        store.save(&listing).await.unwrap();
+        for text in ["\"cache\" to 100%", "wrote"] {
+            assert_eq!(store.search(text, 10).await.unwrap().len(), 1, "{}", text);
+        }
+        for text in ["\"cache\" to 1000", "cache_", "wrote\\"] {
+            assert!(store.search(text, 10).await.unwrap().is_empty(), "{}", text);
+        }
+    }
+}
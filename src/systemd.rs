@@ -0,0 +1,138 @@
+//! A runner that restarts systemd units over D-Bus instead of running a deploy script.
+
+use crate::{JobWriter, OutputLine};
+use futures::StreamExt;
+use std::future::{pending, ready};
+use std::process::Stdio;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio_stream::wrappers::LinesStream;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{Connection, Proxy};
+
+const SYSTEMD: &str = "org.freedesktop.systemd1";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Journal lines written just after a unit comes up are still worth capturing.
+const JOURNAL_GRACE: Duration = Duration::from_secs(1);
+
+/// An entry of `ListJobs`: id, unit, type, state, job path and unit path.
+type SystemdJob = (
+    u32,
+    String,
+    String,
+    String,
+    OwnedObjectPath,
+    OwnedObjectPath,
+);
+
+async fn restart(connection: &Connection, unit: &str, timeout: Duration) -> Result<(), String> {
+    let describe = |error: zbus::Error| format!("Failed to restart {unit}: {error}");
+    let manager = Proxy::new(
+        connection,
+        SYSTEMD,
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+    )
+    .await
+    .map_err(describe)?;
+
+    let job: OwnedObjectPath = manager
+        .call("RestartUnit", &(unit, "replace"))
+        .await
+        .map_err(describe)?;
+    let started = Instant::now();
+    loop {
+        let jobs: Vec<SystemdJob> = manager.call("ListJobs", &()).await.map_err(describe)?;
+        if !jobs.iter().any(|(.., path, _)| *path == job) {
+            break;
+        }
+        if started.elapsed() > timeout {
+            return Err(format!("Timed out waiting for {unit} to restart"));
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let path: OwnedObjectPath = manager.call("GetUnit", &(unit,)).await.map_err(describe)?;
+    let state: String = Proxy::new(
+        connection,
+        SYSTEMD,
+        path.into_inner(),
+        "org.freedesktop.systemd1.Unit",
+    )
+    .await
+    .map_err(describe)?
+    .get_property("ActiveState")
+    .await
+    .map_err(describe)?;
+    if state == "active" {
+        Ok(())
+    } else {
+        Err(format!("{unit} is {state} after restarting"))
+    }
+}
+
+/// Restarts each unit in turn, waiting for it to become active and capturing its journal
+/// while it restarts. Returns the job's exit status.
+pub async fn restart_units(writer: &JobWriter, units: &[String], timeout: Duration) -> i32 {
+    let connection = match Connection::system().await {
+        Ok(connection) => connection,
+        Err(error) => {
+            writer.push(OutputLine::Stderr(format!(
+                "Failed to connect to systemd: {error}"
+            )));
+            return 255;
+        }
+    };
+
+    for unit in units {
+        writer.push(OutputLine::Stdout(format!("Restarting {unit}")));
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut journal = Command::new("journalctl")
+            .args(["--follow", "--no-pager", "--output", "short-iso", "--unit"])
+            .arg(unit)
+            .arg(format!("--since=@{since}"))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .ok();
+        let lines = journal
+            .as_mut()
+            .and_then(|journal| journal.stdout.take())
+            .map(|stdout| LinesStream::new(BufReader::new(stdout).lines()));
+        let consume = async {
+            match lines {
+                Some(lines) => {
+                    lines
+                        .filter_map(|line| ready(line.ok()))
+                        .for_each(|line| {
+                            writer.push(OutputLine::stdout(line));
+                            ready(())
+                        })
+                        .await
+                }
+                None => pending().await,
+            }
+        };
+        let restart = restart(&connection, unit, timeout);
+        tokio::pin!(consume, restart);
+
+        let result = tokio::select! {
+            result = &mut restart => result,
+            () = &mut consume => restart.await,
+        };
+        let _ = tokio::time::timeout(JOURNAL_GRACE, &mut consume).await;
+        drop(journal);
+
+        if let Err(error) = result {
+            writer.push(OutputLine::Stderr(error));
+            return 1;
+        }
+        writer.push(OutputLine::Stdout(format!("{unit} is active")));
+    }
+    0
+}
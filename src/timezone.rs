@@ -0,0 +1,34 @@
+//! The timezone times are shown to people in, which is often not the server's own.
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy)]
+pub struct DisplayTimezone(Tz);
+
+impl DisplayTimezone {
+    /// The IANA timezone named by `display_timezone`, such as `Asia/Tokyo`, or UTC.
+    pub fn from_env() -> Self {
+        let timezone = std::env::var("display_timezone")
+            .map(|name| {
+                name.parse()
+                    .expect("`display_timezone` environment variable must name an IANA timezone")
+            })
+            .unwrap_or(Tz::UTC);
+        Self(timezone)
+    }
+
+    /// The timezone's IANA name, such as `Asia/Tokyo`.
+    pub fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    /// Formats a time for people to read, with the timezone's abbreviation.
+    pub fn format(&self, time: SystemTime) -> String {
+        DateTime::<Utc>::from(time)
+            .with_timezone(&self.0)
+            .format("%Y-%m-%d %H:%M:%S %Z")
+            .to_string()
+    }
+}
@@ -0,0 +1,167 @@
+//! The resources a deploy script used, as reported by the kernel once it has exited.
+
+use std::io;
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::process::Child;
+
+#[derive(Clone, Copy)]
+pub struct ResourceUsage {
+    /// The largest resident set of the script, or of any of the processes it waited for.
+    pub peak_rss: u64,
+    /// CPU time spent by the script and the processes it waited for.
+    pub user_time: Duration,
+    pub system_time: Duration,
+}
+
+/// Waits for the child to exit, collecting its resource usage where the platform can.
+///
+/// Only tokio reaps the child. It is first waited for without being reaped, so that its
+/// usage can be read while it is a zombie.
+#[cfg(unix)]
+pub async fn wait(child: &mut Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    let pid = match child.id() {
+        Some(pid) => pid as libc::id_t,
+        None => return child.wait().await.map(|status| (status, None)),
+    };
+    let exited = tokio::task::spawn_blocking(move || wait_exited(pid))
+        .await
+        .map_err(io::Error::other)?;
+    if let Err(error) = exited {
+        eprintln!("Failed to wait for process {pid}, so its usage is unknown: {error}");
+        return child.wait().await.map(|status| (status, None));
+    }
+    reap(child).await
+}
+
+/// Waits for the process to exit, leaving it to be reaped.
+#[cfg(unix)]
+fn wait_exited(pid: libc::id_t) -> io::Result<()> {
+    loop {
+        // SAFETY: `siginfo_t` is plain data that waitid fills in when it succeeds.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let options = libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid, &mut info, options) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Reaps the child, which has exited, along with what it used. Linux reports the usage of
+/// a zombie to the `waitid` system call, though not to libc's wrapper of it.
+#[cfg(target_os = "linux")]
+async fn reap(child: &mut Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    let pid = child.id().map(|pid| pid as libc::id_t);
+    // SAFETY: `rusage` is plain data that waitid fills in when it succeeds.
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    let read = pid.is_some_and(|pid| {
+        // SAFETY: as `rusage`, and waitid only writes through the pointers to them.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let options = libc::WEXITED | libc::WNOWAIT | libc::WNOHANG;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PID,
+                pid,
+                &mut info as *mut libc::siginfo_t,
+                options,
+                &mut rusage as *mut libc::rusage,
+            )
+        };
+        result == 0
+    });
+    let status = child.wait().await?;
+    Ok((status, read.then(|| usage(&rusage, None))))
+}
+
+/// Reaps the child, which has exited, along with what it used, which is the difference
+/// reaping it made to the usage of every child reaped. Other platforms only report the
+/// largest resident set of any of them, so that is the peak reported.
+#[cfg(all(unix, not(target_os = "linux")))]
+async fn reap(child: &mut Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    use std::sync::Mutex;
+
+    /// Held while a child is reaped, so that no other deploy's children are counted.
+    static REAPING: Mutex<()> = Mutex::new(());
+
+    let reaped = {
+        let _reaping = REAPING.lock().unwrap();
+        let before = children();
+        child
+            .try_wait()?
+            .map(|status| (status, usage(&children(), Some(&before))))
+    };
+    match reaped {
+        Some((status, usage)) => Ok((status, Some(usage))),
+        None => child.wait().await.map(|status| (status, None)),
+    }
+}
+
+/// The usage of every child this process has reaped.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn children() -> libc::rusage {
+    // SAFETY: `rusage` is plain data that getrusage fills in, and it cannot fail for
+    // `RUSAGE_CHILDREN`.
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut rusage) };
+    rusage
+}
+
+/// The usage in `rusage`, less the times in `before`.
+#[cfg(unix)]
+fn usage(rusage: &libc::rusage, before: Option<&libc::rusage>) -> ResourceUsage {
+    let since = |time: libc::timeval, before: Option<libc::timeval>| {
+        duration(time).saturating_sub(before.map_or(Duration::ZERO, duration))
+    };
+    ResourceUsage {
+        peak_rss: max_rss_bytes(rusage.ru_maxrss),
+        user_time: since(rusage.ru_utime, before.map(|before| before.ru_utime)),
+        system_time: since(rusage.ru_stime, before.map(|before| before.ru_stime)),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn wait(child: &mut Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    child.wait().await.map(|status| (status, None))
+}
+
+#[cfg(unix)]
+fn duration(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+/// macOS reports the peak resident set in bytes, where other platforms use kilobytes.
+#[cfg(unix)]
+fn max_rss_bytes(max_rss: libc::c_long) -> u64 {
+    if cfg!(target_os = "macos") {
+        max_rss as u64
+    } else {
+        max_rss as u64 * 1024
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn usage_is_read_before_tokio_reaps_the_child() {
+        let mut child = tokio::process::Command::new("sh")
+            .args([
+                "-c",
+                "i=0; while [ $i -lt 100000 ]; do i=$((i + 1)); done; exit 3",
+            ])
+            .spawn()
+            .unwrap();
+        let (status, usage) = wait(&mut child).await.unwrap();
+        assert_eq!(status.code(), Some(3));
+        let usage = usage.unwrap();
+        assert!(usage.peak_rss > 0);
+        assert!(usage.user_time + usage.system_time > Duration::ZERO);
+        assert!(child.try_wait().unwrap().is_some());
+    }
+}
@@ -0,0 +1,327 @@
+use deploy_server::{build_routes, Config, State};
+use deploy_server_types::{AppStatus, JobListing, JobState, TriggerCounts, TriggerSource};
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::http::StatusCode;
+
+const SECRET: &str = "integration-secret";
+
+fn config() -> Config {
+    Config::new(vec![SECRET.to_owned()], 0)
+}
+
+/// A job of `app` that succeeded just now, as a job store would have saved it.
+fn finished_job(app: &str, labels: serde_json::Value) -> JobListing {
+    let received_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    serde_json::from_value(json!({
+        "id": uuid::Uuid::new_v4(),
+        "app": app,
+        "source": "deploy2",
+        "request_id": "test",
+        "flags": [],
+        "received_at": received_at,
+        "received_at_iso": "",
+        "status": 0,
+        "lines": 0,
+        "timeline": [],
+        "annotations": [],
+        "labels": labels,
+        "invocations": [],
+        "state": "succeeded",
+        "output": [],
+    }))
+    .unwrap()
+}
+
+/// The apps of the jobs in a response from /api/jobs.
+fn apps(body: &[u8]) -> Vec<String> {
+    serde_json::from_slice::<Vec<JobListing>>(body)
+        .unwrap()
+        .into_iter()
+        .map(|listing| listing.job.app)
+        .collect()
+}
+
+#[tokio::test]
+async fn serves_the_console() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request().path("/").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn serves_json_status_to_clients_that_ask_for_it() {
+    let mut failed = finished_job("web", json!({}));
+    failed.job.received_at -= 60 * 1000;
+    failed.job.status = Some(1);
+    failed.state = JobState::Failed;
+    let state = State::default();
+    state
+        .restore(vec![failed, finished_job("web", json!({}))])
+        .await;
+    let routes = build_routes(&config(), &state);
+
+    let response = warp::test::request()
+        .path("/")
+        .header("accept", "application/json")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let statuses: Vec<AppStatus> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].app, "web");
+    assert_eq!(statuses[0].state, JobState::Succeeded);
+}
+
+#[tokio::test]
+async fn lists_no_jobs_before_any_are_triggered() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request().path("/api/jobs").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body(), "[]");
+}
+
+#[tokio::test]
+async fn filters_jobs_by_label() {
+    let state = State::default();
+    state
+        .restore(vec![
+            finished_job("production", json!({ "env": "prod" })),
+            finished_job("staging", json!({ "env": "staging" })),
+            finished_job("unlabelled", json!({})),
+        ])
+        .await;
+    let routes = build_routes(&config(), &state);
+
+    let response = warp::test::request()
+        .path("/api/jobs?label=env=prod")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(apps(response.body()), ["production"]);
+
+    let response = warp::test::request()
+        .path("/api/jobs?label=env")
+        .reply(&routes)
+        .await;
+    assert_eq!(apps(response.body()), ["production", "staging"]);
+
+    let response = warp::test::request()
+        .path("/api/jobs?label=env=dev")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.body(), "[]");
+}
+
+#[tokio::test]
+async fn restored_jobs_stay_expired() {
+    let mut expired = finished_job("stale", json!({}));
+    expired.job.status = Some(255);
+    expired.job.expired_after_ms = Some(60_000);
+    let state = State::default();
+    state.restore(vec![expired]).await;
+    let routes = build_routes(&config(), &state);
+
+    let response = warp::test::request().path("/api/jobs").reply(&routes).await;
+    let listings: Vec<JobListing> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(listings[0].state, JobState::Expired);
+    assert_eq!(listings[0].job.expired_after_ms, Some(60_000));
+}
+
+#[tokio::test]
+async fn counts_triggers_by_app_and_source() {
+    let mut webhook = finished_job("web", json!({}));
+    webhook.job.source = TriggerSource::Webhook;
+    let mut last_month = finished_job("web", json!({}));
+    last_month.job.received_at -= 30 * 24 * 60 * 60 * 1000;
+    let state = State::default();
+    state
+        .restore(vec![
+            finished_job("web", json!({})),
+            finished_job("web", json!({})),
+            webhook,
+            finished_job("worker", json!({})),
+            last_month,
+        ])
+        .await;
+    let routes = build_routes(&config(), &state);
+
+    let response = warp::test::request()
+        .path("/api/stats/triggers?days=7")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let counts: Vec<TriggerCounts> = serde_json::from_slice(response.body()).unwrap();
+    let counts: Vec<_> = counts
+        .into_iter()
+        .map(|counts| (counts.app, counts.sources.into_iter().collect::<Vec<_>>()))
+        .collect();
+    assert_eq!(
+        counts,
+        [
+            (
+                "web".to_owned(),
+                vec![(TriggerSource::Webhook, 1), (TriggerSource::Deploy2, 2)]
+            ),
+            ("worker".to_owned(), vec![(TriggerSource::Deploy2, 1)]),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unknown_jobs_are_not_found() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request()
+        .path(&format!("/api/jobs/{}", uuid::Uuid::new_v4()))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deploys_need_a_token() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request()
+        .method("POST")
+        .path("/deploy2/app")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn debugging_the_queue_needs_the_actions_secret() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request()
+        .path("/admin/debug")
+        .header("X-Deploy-Secret", "wrong")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = warp::test::request()
+        .path("/admin/debug")
+        .header("X-Deploy-Secret", SECRET)
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.body(),
+        r#"{"permits_available":null,"running":0,"waiting":0,"locks":[],"jobs":[]}"#
+    );
+}
+
+#[tokio::test]
+async fn cancelling_needs_a_token() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/jobs/{}/cancel", uuid::Uuid::new_v4()))
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body("secret=wrong")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn only_failed_jobs_can_be_acknowledged() {
+    let mut failed = finished_job("web", json!({}));
+    failed.job.status = Some(1);
+    failed.state = JobState::Failed;
+    let failed_id = failed.job.id;
+    let succeeded = finished_job("web", json!({}));
+    let succeeded_id = succeeded.job.id;
+    let state = State::default();
+    state.restore(vec![failed, succeeded]).await;
+    let routes = build_routes(&config(), &state);
+
+    let acknowledge = |id: uuid::Uuid| {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/jobs/{id}/acknowledge"))
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(format!("secret={SECRET}"))
+    };
+    let response = acknowledge(succeeded_id).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = acknowledge(failed_id).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    let response = warp::test::request()
+        .path(&format!("/api/jobs/{failed_id}"))
+        .reply(&routes)
+        .await;
+    let listing: JobListing = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(listing.job.acknowledgement.unwrap().by, "default");
+}
+
+#[tokio::test]
+async fn refused_requests_are_counted() {
+    let routes = build_routes(&config(), &State::default());
+    warp::test::request()
+        .method("POST")
+        .path("/deploy2/app")
+        .reply(&routes)
+        .await;
+    let response = warp::test::request().path("/metrics").reply(&routes).await;
+    let metrics = String::from_utf8_lossy(response.body());
+    assert!(metrics.contains(
+        "deploy_rejections_total{reason=\"invalid_signature\",endpoint=\"/deploy2\",source=\"unknown\"} 1"
+    ));
+}
+
+#[tokio::test]
+async fn unknown_apps_are_not_found() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request()
+        .method("POST")
+        .path("/deploy2/no-such-app")
+        .header("X-Deploy-Secret", SECRET)
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn responses_carry_a_request_id() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request()
+        .path("/api/version")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("X-Request-Id"));
+}
+
+#[tokio::test]
+async fn unsigned_deliveries_are_kept_to_be_redriven() {
+    let routes = build_routes(&config(), &State::default());
+    let response = warp::test::request()
+        .method("POST")
+        .path("/deploy/app")
+        .body("{}")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = warp::test::request()
+        .path("/admin/deliveries")
+        .header("X-Deploy-Secret", SECRET)
+        .reply(&routes)
+        .await;
+    let deliveries: Vec<serde_json::Value> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0]["reason"], "invalid signature");
+    assert_eq!(deliveries[0]["size"], 2);
+
+    let response = warp::test::request().path("/").reply(&routes).await;
+    let console = String::from_utf8_lossy(response.body());
+    assert!(console.contains(&format!(
+        "/api/deliveries/{}/redrive",
+        deliveries[0]["id"].as_str().unwrap()
+    )));
+}
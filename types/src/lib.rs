@@ -0,0 +1,360 @@
+//! The shapes of the JSON documents deploy-server produces: the job records served by its
+//! API and state dumps, the events passed to hooks, and the responses of its trigger
+//! endpoints. Consumers can deserialize with these rather than guessing.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// A job, without its output.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub app: String,
+    pub environment: Option<String>,
+    /// The commit being deployed, when the trigger said.
+    pub sha: Option<String>,
+    /// The ref that was pushed, such as `refs/heads/main`, when the trigger said.
+    pub reference: Option<String>,
+    /// The `owner/name` of the repository, when the trigger said.
+    pub repository: Option<String>,
+    /// Who pushed the commit, when the trigger said.
+    pub pusher: Option<String>,
+    /// The name of the API token that triggered the job, if it was triggered by one.
+    pub sender: Option<String>,
+    pub source: TriggerSource,
+    pub request_id: String,
+    /// Anything suspicious about the trigger that did not prevent the deploy.
+    pub flags: Vec<String>,
+    /// The person or team responsible for the app, from its settings.
+    pub owner: Option<String>,
+    /// How to reach the owner.
+    pub contact: Option<String>,
+    /// The namespace of the app, from its settings.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// When the trigger was received, in milliseconds since the Unix epoch.
+    pub received_at: u64,
+    /// `received_at` in ISO 8601 format, in UTC.
+    pub received_at_iso: String,
+    /// The exit status, once the job has finished.
+    pub status: Option<i32>,
+    /// The number of lines of output captured so far.
+    pub lines: usize,
+    pub timeline: Vec<TimelineEvent>,
+    /// Messages the script asked to be surfaced, in the order it printed them.
+    pub annotations: Vec<Annotation>,
+    /// What the deploy script used, once it has exited. Only scripts report this.
+    pub usage: Option<ResourceUsage>,
+    /// Set once an operator has acknowledged that the job failed.
+    pub acknowledgement: Option<Acknowledgement>,
+    /// Set once the job has been cancelled.
+    pub cancellation: Option<Cancellation>,
+    /// Set if the job was stopped for running for longer than this many milliseconds.
+    pub timed_out_after_ms: Option<u64>,
+    /// Set if the job expired after waiting this many milliseconds for its turn.
+    pub expired_after_ms: Option<u64>,
+    /// Set if the job was skipped while queued, in favour of this later job.
+    pub superseded_by: Option<Uuid>,
+    /// What kind of failure it was, if it failed with output matching one of its app's
+    /// `failure_categories`.
+    pub category: Option<String>,
+    /// Labels from the app's settings, the trigger and the script, in that order of
+    /// precedence, for API consumers to filter jobs by.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// The processes the job ran, in order.
+    pub invocations: Vec<Invocation>,
+    /// The workflow run artifact the job deploys, when the trigger named one.
+    #[serde(default)]
+    pub artifact: Option<Artifact>,
+}
+
+/// A workflow run artifact, as a /deploy2 request names it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Artifact {
+    pub repository: String,
+    pub run_id: u64,
+    pub artifact: String,
+}
+
+/// A process that a job ran, and what it was run with.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invocation {
+    /// The program, with any symlinks resolved.
+    pub program: String,
+    pub args: Vec<String>,
+    /// The `DEPLOY_*` environment variables it saw, with secrets redacted.
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Acknowledgement {
+    /// The name of the API token the operator acknowledged with.
+    pub by: String,
+    /// When, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// `at` in ISO 8601 format, in UTC.
+    pub at_iso: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cancellation {
+    /// The name of the API token the job was cancelled with.
+    pub by: String,
+    /// When, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// `at` in ISO 8601 format, in UTC.
+    pub at_iso: String,
+}
+
+/// Resources used by a deploy script and the processes it waited for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub peak_rss_bytes: u64,
+    pub user_cpu_ms: u64,
+    pub system_cpu_ms: u64,
+}
+
+/// A job as /api/jobs lists it, with the end of its output, or as /api/jobs/{id} serves
+/// it, with all of its output.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobListing {
+    #[serde(flatten)]
+    pub job: Job,
+    pub state: JobState,
+    /// The last lines of the job's output.
+    pub output: Vec<OutputLine>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// Waiting for an earlier deploy of the same app to finish.
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    /// Skipped while queued, in favour of a later job.
+    Superseded,
+    #[serde(rename = "timed_out")]
+    TimedOut,
+    /// Waited for its turn for longer than its app allows, so it never started.
+    Expired,
+}
+
+impl JobState {
+    /// The state of a job with the given exit status, which is `None` while it runs.
+    pub fn from_status(status: Option<i32>) -> Self {
+        match status {
+            None => JobState::Running,
+            Some(0) => JobState::Succeeded,
+            Some(..) => JobState::Failed,
+        }
+    }
+
+    /// The state of a job, which is queued until it has started, superseded if it was
+    /// skipped for a later job, expired if it waited too long to start, and cancelled or
+    /// timed out if it was stopped before it succeeded.
+    pub fn of(job: &Job) -> Self {
+        match Self::from_status(job.status) {
+            JobState::Succeeded if job.superseded_by.is_some() => JobState::Superseded,
+            JobState::Running if !job.timeline.iter().any(|event| event.event == "started") => {
+                JobState::Queued
+            }
+            JobState::Failed if job.cancellation.is_some() => JobState::Cancelled,
+            JobState::Failed if job.timed_out_after_ms.is_some() => JobState::TimedOut,
+            JobState::Failed if job.expired_after_ms.is_some() => JobState::Expired,
+            state => state,
+        }
+    }
+}
+
+/// The latest job of an app in an environment, as `GET /` serves it to clients that ask
+/// for JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppStatus {
+    pub app: String,
+    pub environment: Option<String>,
+    pub state: JobState,
+    pub job: Job,
+}
+
+/// A line of a job's output.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputLine {
+    /// `stdout` or `stderr`.
+    pub stream: String,
+    pub text: String,
+    /// Milliseconds since the trigger was received, when the line was read.
+    pub elapsed_ms: u64,
+}
+
+/// An update sent to the console over its WebSocket.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ConsoleEvent {
+    /// A job started, or changed state.
+    Job { job: Box<Job>, state: JobState },
+    /// A job wrote a line of output.
+    Line { job_id: Uuid, line: OutputLine },
+}
+
+/// Progress of a single job, as `State::watch_job` streams it to embedders.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JobProgress {
+    /// The job wrote a line of output.
+    Line { line: OutputLine },
+    /// The job finished. Nothing follows this.
+    Finished { job: Box<Job>, state: JobState },
+}
+
+/// A point in a job's lifecycle.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub event: String,
+    /// Milliseconds since the trigger was received, measured with a monotonic clock.
+    pub elapsed_ms: u64,
+}
+
+/// A message a deploy script surfaced by printing an annotation command such as
+/// `::error file=app.js,line=10::Missing semicolon`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Annotation {
+    /// `notice`, `warning` or `error`.
+    pub level: String,
+    pub message: String,
+    pub title: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// The tamper-evident record of a finished job. The signature covers the record
+/// serialized as compact JSON with its fields in this order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: Uuid,
+    pub app: String,
+    pub environment: Option<String>,
+    pub sha: Option<String>,
+    pub sender: Option<String>,
+    pub request_id: String,
+    pub flags: Vec<String>,
+    pub owner: Option<String>,
+    pub contact: Option<String>,
+    pub received_at: u64,
+    pub status: i32,
+    /// The SHA-256 of the job's output, hex encoded. Each line is hashed as its stream
+    /// (`stdout` or `stderr`), a tab, its text and a newline.
+    pub output_sha256: String,
+}
+
+/// A job record with the server's signature, as served by /api/jobs/{id}/record and
+/// checked by /api/records/verify.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedJobRecord {
+    pub record: JobRecord,
+    /// The Ed25519 signature of the record, hex encoded, which checks against the public
+    /// key served by /api/records/key.
+    pub signature: String,
+}
+
+/// The public key that job records are signed with, as served by /api/records/key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordKey {
+    /// Always `ed25519`.
+    pub algorithm: String,
+    /// The key, hex encoded.
+    pub public_key: String,
+}
+
+/// The JSON passed on stdin to hook executables.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobEvent {
+    /// The hook point: `on_trigger`, `on_start`, `on_finish`, `on_failure` or `on_stall`.
+    pub hook: String,
+    #[serde(flatten)]
+    pub job: Job,
+}
+
+/// The JSON passed on stdin to the `on_batch` hook: the jobs for one destination that
+/// finished within one batch window, in the order they finished.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchEvent {
+    /// Always `on_batch`.
+    pub hook: String,
+    /// Who is to be told about the jobs: the contact of their apps, or failing that the
+    /// owner. `None` for the jobs of apps with neither.
+    #[serde(default)]
+    pub destination: Option<String>,
+    pub jobs: Vec<Job>,
+}
+
+/// Which build of deploy-server is running, as served by /api/version.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Version {
+    pub version: String,
+    /// The commit it was built from, if it was built from a Git checkout.
+    pub git_sha: Option<String>,
+    pub rustc: Option<String>,
+    pub features: Vec<String>,
+}
+
+/// The response of a trigger endpoint.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TriggerResponse {
+    pub job_id: Uuid,
+    /// `started`, `queued` behind earlier deploys of the app, or `skipped` if the commit
+    /// was already deployed.
+    pub status: String,
+    /// Where to poll for the job's status and output: its /api/jobs/{id} URL.
+    pub status_url: String,
+    /// How many deploys of the app are running or queued ahead of this one.
+    pub queue_position: usize,
+}
+
+/// How a job was triggered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TriggerSource {
+    /// A webhook delivery to /deploy.
+    Webhook,
+    /// A webhook delivery that was refused, redriven by an operator.
+    Redrive,
+    /// A request to /deploy2 with an API token.
+    Deploy2,
+    /// A `post-receive` hook of a repository on the server's host.
+    PostReceive,
+}
+
+/// How many jobs of an app were triggered each way on one day.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TriggerCounts {
+    pub app: String,
+    pub environment: Option<String>,
+    /// The start of the day (UTC), in milliseconds since the Unix epoch.
+    pub day: u64,
+    pub sources: BTreeMap<TriggerSource, usize>,
+}
+
+/// A job whose output matched a search.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub id: Uuid,
+    pub app: String,
+    pub environment: Option<String>,
+    pub status: Option<i32>,
+    pub matches: Vec<Excerpt>,
+}
+
+/// A matching line of output.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Excerpt {
+    /// The line number, counting from 1.
+    pub line: usize,
+    /// `stdout` or `stderr`.
+    pub stream: String,
+    pub text: String,
+}